@@ -0,0 +1,148 @@
+//! Cost of each stage between an exchange message and the gRPC summary.
+//!
+//! Run with `cargo bench`; criterion compares each run with the previous
+//! one and reports regressions.
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use rust_decimal::Decimal;
+
+use orderbook_aggregator::{
+    aggregator::Aggregator,
+    binance, bitstamp, mock_exchange,
+    order_book::{Exchange, Orderbook, OrderbookLevel},
+    proto::Summary,
+    scale::Scale,
+};
+
+/// Levels per side sent by the Binance `depth10` stream.
+const BINANCE_LEVELS: usize = 10;
+/// Levels per side sent by the Bitstamp `order_book` channel.
+const BITSTAMP_LEVELS: usize = 100;
+
+/// Price and size of a level as the exchanges send them.
+type Level = (String, String);
+
+/// `count` price levels per side around a mid of 0.0625.
+fn levels(count: usize) -> (Vec<Level>, Vec<Level>) {
+    let level = |price: i64, i: usize| {
+        let price = Decimal::new(price, 6).to_string();
+        let size = Decimal::new(1_000 + 37 * i as i64, 3).to_string();
+        (price, size)
+    };
+    let bids = (0..count).map(|i| level(62_400 - i as i64, i)).collect();
+    let asks = (0..count).map(|i| level(62_600 + i as i64, i)).collect();
+    (bids, asks)
+}
+
+fn as_strs(levels: &[Level]) -> Vec<(&str, &str)> {
+    levels
+        .iter()
+        .map(|(price, size)| (price.as_str(), size.as_str()))
+        .collect()
+}
+
+fn binance_message(count: usize) -> String {
+    let (bids, asks) = levels(count);
+    mock_exchange::binance::depth(1, &as_strs(&bids), &as_strs(&asks))
+}
+
+fn bitstamp_message(count: usize) -> String {
+    let (bids, asks) = levels(count);
+    mock_exchange::bitstamp::order_book("ethbtc", 1, &as_strs(&bids), &as_strs(&asks))
+}
+
+/// Scale of the benchmarked pair.
+const SCALE: Scale = Scale::new(8, 8);
+
+/// Orderbook of `exchange` with `count` levels per side, its prices
+/// shifted by `offset` ticks of 0.000001 so venues interleave.
+fn orderbook(exchange: Exchange, count: usize, offset: i64) -> Orderbook {
+    const TICK: i64 = 100;
+    const SIZE: i64 = 150_000_000;
+    let bids = (0..count as i64)
+        .map(|i| OrderbookLevel::bid((62_400 - 2 * i - offset) * TICK, SIZE, exchange))
+        .collect();
+    let asks = (0..count as i64)
+        .map(|i| OrderbookLevel::ask((62_600 + 2 * i + offset) * TICK, SIZE, exchange))
+        .collect();
+    Orderbook::from_bids_asks(SCALE, bids, asks)
+}
+
+fn parse(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parse");
+    for &count in &[BINANCE_LEVELS, BITSTAMP_LEVELS] {
+        let msg = binance_message(count);
+        group.throughput(Throughput::Bytes(msg.len() as u64));
+        group.bench_with_input(BenchmarkId::new("binance", count), &msg, |b, msg| {
+            let mut parser = binance::Parser::new(SCALE);
+            b.iter(|| parser.parse(black_box(msg)).unwrap())
+        });
+
+        let msg = bitstamp_message(count);
+        group.throughput(Throughput::Bytes(msg.len() as u64));
+        group.bench_with_input(BenchmarkId::new("bitstamp", count), &msg, |b, msg| {
+            let mut parser = bitstamp::Parser::new(SCALE);
+            b.iter(|| parser.parse(black_box(msg)).unwrap())
+        });
+    }
+    group.finish();
+}
+
+fn aggregate(c: &mut Criterion) {
+    // `Unknown` stands in for a third venue until there is one.
+    let venues = [Exchange::Binance, Exchange::Bitstamp, Exchange::Unknown];
+    let mut group = c.benchmark_group("aggregate");
+    for count in 1..=venues.len() {
+        for &levels in &[BINANCE_LEVELS, BITSTAMP_LEVELS, 1_000] {
+            let mut aggregator = Aggregator::new();
+            for (offset, &exchange) in venues[..count].iter().enumerate() {
+                aggregator.update(exchange, orderbook(exchange, levels, offset as i64));
+            }
+            let id = BenchmarkId::new(format!("{}_venues", count), levels);
+            group.throughput(Throughput::Elements((count * levels * 2) as u64));
+            group.bench_function(id, |b| b.iter(|| aggregator.aggregate()));
+        }
+    }
+    group.finish();
+}
+
+fn summary(c: &mut Criterion) {
+    let mut aggregator = Aggregator::new();
+    aggregator.update(
+        Exchange::Binance,
+        orderbook(Exchange::Binance, BINANCE_LEVELS, 0),
+    );
+    aggregator.update(
+        Exchange::Bitstamp,
+        orderbook(Exchange::Bitstamp, BITSTAMP_LEVELS, 1),
+    );
+    let aggregated = aggregator.aggregate();
+    c.bench_function("summary_from", |b| {
+        b.iter_batched(
+            || aggregated.clone(),
+            Summary::from,
+            criterion::BatchSize::SmallInput,
+        )
+    });
+}
+
+/// A Bitstamp message turned into the summary sent to subscribers, with
+/// Binance already in the aggregate.
+fn end_to_end(c: &mut Criterion) {
+    let msg = bitstamp_message(BITSTAMP_LEVELS);
+    let mut parser = bitstamp::Parser::new(SCALE);
+    let mut aggregator = Aggregator::new();
+    aggregator.update(
+        Exchange::Binance,
+        orderbook(Exchange::Binance, BINANCE_LEVELS, 1),
+    );
+    c.bench_function("message_to_summary", |b| {
+        b.iter(|| {
+            let orderbook = parser.parse(black_box(&msg)).unwrap().unwrap();
+            aggregator.update(Exchange::Bitstamp, orderbook);
+            Summary::from(aggregator.aggregate())
+        })
+    });
+}
+
+criterion_group!(benches, parse, aggregate, summary, end_to_end);
+criterion_main!(benches);
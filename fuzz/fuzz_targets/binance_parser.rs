@@ -0,0 +1,17 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use orderbook_aggregator::binance::Parser;
+use orderbook_aggregator::scale::Scale;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(msg) = std::str::from_utf8(data) {
+        // Parsing the same message twice must not be a sequence gap.
+        let mut parser = Parser::new(Scale::default());
+        if let Ok(Some(orderbook)) = parser.parse(msg) {
+            assert!(parser.parse(msg).is_ok());
+            assert!(orderbook.bids.windows(2).all(|pair| pair[0] >= pair[1]));
+            assert!(orderbook.asks.windows(2).all(|pair| pair[0] <= pair[1]));
+        }
+    }
+});
@@ -1,14 +1,75 @@
 use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
 
-use crate::order_book::{Exchange, Orderbook};
+use rust_decimal::Decimal;
+use strum_macros::EnumString;
+use tokio::sync::{mpsc, watch};
+use tokio::time::{self, Instant};
+use tracing::{debug, debug_span, warn};
+
+use crate::error::Error;
+use crate::metrics;
+use crate::order_book::{Exchange, Orderbook, OrderbookUpdateEvent};
+use crate::trade;
 
 /// Number of asks and bids returned by the aggregator.
-const LIMIT: usize = 10;
+pub const LIMIT: usize = 10;
+
+/// How a locked or crossed aggregate, a bid of one venue at or above an
+/// ask of another, is resolved.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, EnumString)]
+#[strum(serialize_all = "kebab-case")]
+pub enum CrossPolicy {
+    /// Keep every level; the summary reports the book as locked or crossed.
+    #[default]
+    Flag,
+    /// Drop the conflicting levels of the venue updated least recently.
+    DropStaler,
+    /// Drop the conflicting levels of both venues.
+    DropBoth,
+}
+
+impl CrossPolicy {
+    /// Read the policy from the `CROSS_POLICY` environment variable,
+    /// `flag` if unset.
+    pub fn from_env() -> crate::Result<Self> {
+        match std::env::var("CROSS_POLICY") {
+            Ok(policy) => Self::from_str(&policy).map_err(|err| Error::config("CROSS_POLICY", err)),
+            Err(_) => Ok(CrossPolicy::default()),
+        }
+    }
+}
+
+/// Aggregated orderbook published to the gRPC subscribers.
+#[derive(Debug, Clone, Default)]
+pub struct Snapshot {
+    /// Id of the `OrderbookUpdateEvent` which produced this snapshot.
+    pub update_id: u64,
+    /// When the snapshot was published, in microseconds since the epoch.
+    pub published_at: u64,
+    /// Best `LIMIT` levels of each side.
+    pub orderbook: Orderbook,
+    /// Every level of every venue, shared by all the subscribers.
+    pub depth: Arc<Orderbook>,
+    /// `depth` before locked and crossed venues were resolved by the
+    /// `CrossPolicy`, the same book under `CrossPolicy::Flag`.
+    pub raw_depth: Arc<Orderbook>,
+    /// Rate each venue's prices were converted into the reference quote
+    /// currency at, for the venues quoting another currency.
+    pub quote_rates: HashMap<Exchange, Decimal>,
+}
 
 /// Orderbook aggregator state.
 #[derive(Debug)]
 pub struct Aggregator {
     pub orderbooks: HashMap<Exchange, Orderbook>,
+    /// When each exchange's orderbook was last updated.
+    updated_at: HashMap<Exchange, Instant>,
+    /// Rate each exchange's orderbook was converted at, see `quote`.
+    pub quote_rates: HashMap<Exchange, Decimal>,
+    cross_policy: CrossPolicy,
 }
 
 impl Aggregator {
@@ -16,29 +77,136 @@ impl Aggregator {
     pub fn new() -> Self {
         Self {
             orderbooks: HashMap::new(),
+            updated_at: HashMap::new(),
+            quote_rates: HashMap::new(),
+            cross_policy: CrossPolicy::default(),
         }
     }
 
+    /// Resolve locked and crossed aggregates according to `cross_policy`.
+    pub fn with_cross_policy(mut self, cross_policy: CrossPolicy) -> Self {
+        self.cross_policy = cross_policy;
+        self
+    }
+
     /// Update orderbook snapshot for given exchange.
+    ///
+    /// An empty orderbook removes the exchange from the aggregate.
     pub fn update(&mut self, exchange: Exchange, orderbook: Orderbook) {
-        self.orderbooks.insert(exchange, orderbook);
+        self.update_at(exchange, orderbook, Instant::now());
+    }
+
+    /// Update orderbook snapshot for given exchange, received from it at
+    /// `updated_at`.
+    pub fn update_at(&mut self, exchange: Exchange, orderbook: Orderbook, updated_at: Instant) {
+        if orderbook.is_empty() {
+            self.remove(exchange);
+        } else {
+            self.orderbooks.insert(exchange, orderbook);
+            self.updated_at.insert(exchange, updated_at);
+        }
+    }
+
+    /// Remove orderbook of given exchange.
+    pub fn remove(&mut self, exchange: Exchange) -> Option<Orderbook> {
+        self.updated_at.remove(&exchange);
+        self.quote_rates.remove(&exchange);
+        self.orderbooks.remove(&exchange)
+    }
+
+    /// Remove orderbooks which weren't updated within `max_age` and
+    /// return their exchanges.
+    pub fn remove_stale(&mut self, max_age: Duration) -> Vec<Exchange> {
+        let stale: Vec<Exchange> = self
+            .updated_at
+            .iter()
+            .filter(|(_, updated_at)| updated_at.elapsed() > max_age)
+            .map(|(exchange, _)| *exchange)
+            .collect();
+        for exchange in &stale {
+            self.remove(*exchange);
+        }
+        stale
     }
 
     /// Create aggregated orderbook
     pub fn aggregate(&mut self) -> Orderbook {
+        self.aggregate_depth().limit(LIMIT)
+    }
+
+    /// Create aggregated orderbook with every level of every venue.
+    ///
+    /// Venues locking or crossing each other are resolved by the
+    /// `CrossPolicy`.
+    pub fn aggregate_depth(&self) -> Orderbook {
+        self.uncross(self.aggregate_raw())
+    }
+
+    /// Create aggregated orderbook with every level of every venue, locked
+    /// and crossed venues included.
+    ///
+    /// All venues quote the same instrument, so their books share its
+    /// scale and their levels are merged as they are.
+    pub fn aggregate_raw(&self) -> Orderbook {
+        let scale = self
+            .orderbooks
+            .values()
+            .map(|orderbook| orderbook.scale)
+            .next()
+            .unwrap_or_default();
+        debug_assert!(self.orderbooks.values().all(|ob| ob.scale == scale));
         let all_bids = self
             .orderbooks
             .values()
-            .map(|orderbook| orderbook.bids.to_vec())
-            .flatten()
+            .flat_map(|orderbook| orderbook.bids.to_vec())
             .collect();
         let all_asks = self
             .orderbooks
             .values()
-            .map(|orderbook| orderbook.asks.to_vec())
-            .flatten()
+            .flat_map(|orderbook| orderbook.asks.to_vec())
             .collect();
-        Orderbook::from_bids_asks(all_bids, all_asks).limit(LIMIT)
+        Orderbook::from_bids_asks(scale, all_bids, all_asks)
+    }
+
+    /// Drop the levels of `orderbook` locking or crossing another venue's
+    /// as the `CrossPolicy` says, until its top levels don't conflict.
+    fn uncross(&self, mut orderbook: Orderbook) -> Orderbook {
+        loop {
+            let (bid, ask) = match (orderbook.bids.first(), orderbook.asks.first()) {
+                (Some(bid), Some(ask)) if bid.price >= ask.price => (*bid, *ask),
+                _ => return orderbook,
+            };
+            // A venue crossing itself has no other venue to blame.
+            if bid.exchange == ask.exchange {
+                return orderbook;
+            }
+            let (drop_bids, drop_asks) = match self.cross_policy {
+                CrossPolicy::Flag => return orderbook,
+                CrossPolicy::DropStaler => {
+                    let bids_staler =
+                        self.updated_at.get(&bid.exchange) <= self.updated_at.get(&ask.exchange);
+                    (bids_staler, !bids_staler)
+                }
+                CrossPolicy::DropBoth => (true, true),
+            };
+            let bids = orderbook
+                .bids
+                .iter()
+                .filter(|level| {
+                    !(drop_bids && level.exchange == bid.exchange && level.price >= ask.price)
+                })
+                .cloned()
+                .collect();
+            let asks = orderbook
+                .asks
+                .iter()
+                .filter(|level| {
+                    !(drop_asks && level.exchange == ask.exchange && level.price <= bid.price)
+                })
+                .cloned()
+                .collect();
+            orderbook = Orderbook::from_bids_asks(orderbook.scale, bids, asks);
+        }
     }
 }
 
@@ -48,48 +216,455 @@ impl Default for Aggregator {
     }
 }
 
+/// Run the aggregation loop.
+///
+/// Applies orderbook updates received on `rx` and publishes the
+/// aggregated orderbook of `pair` on `tx`. Publishing starts once two
+/// exchanges have reported; after that every change is published, including
+/// venues being dropped because they disconnected or haven't sent an update
+/// within `max_age`. Updates received from their venue longer than
+/// `max_age` ago, e.g. re-sent by `quote` at a new rate, are ignored, so
+/// they don't bring back a venue dropped as stale. Updates containing
+/// levels of another exchange than their own are rejected, venues locking
+/// or crossing each other are resolved by `cross_policy`. Returns when all
+/// senders are gone.
+pub async fn run(
+    pair: String,
+    mut rx: mpsc::Receiver<OrderbookUpdateEvent>,
+    tx: watch::Sender<Snapshot>,
+    max_age: Duration,
+    cross_policy: CrossPolicy,
+) -> crate::Result<()> {
+    let mut aggregator = Aggregator::new().with_cross_policy(cross_policy);
+    let mut publishing = false;
+    let mut update_id = 0;
+    let mut staleness = time::interval(max_age);
+
+    loop {
+        let received_at = tokio::select! {
+            msg = rx.recv() => {
+                let msg = match msg {
+                    Some(msg) => msg,
+                    None => return Ok(()),
+                };
+                metrics::CHANNEL_BACKLOG.set(rx.len() as i64);
+                let _enter = debug_span!("update", update_id = msg.id, venue = %msg.exchange).entered();
+                if let Err(err) = msg.validate() {
+                    warn!(%err, "rejected orderbook update");
+                    let venue: &'static str = msg.exchange.into();
+                    metrics::REJECTED_UPDATES_TOTAL.with_label_values(&[venue]).inc();
+                    continue;
+                }
+                if !msg.orderbook.is_empty() && msg.received_at.elapsed() > max_age {
+                    debug!("ignored stale orderbook update");
+                    continue;
+                }
+                match msg.quote_rate {
+                    Some(rate) => aggregator.quote_rates.insert(msg.exchange, rate),
+                    None => aggregator.quote_rates.remove(&msg.exchange),
+                };
+                aggregator.update_at(msg.exchange, msg.orderbook, msg.received_at);
+                update_id = msg.id;
+                Some(msg.received_at)
+            }
+            _ = staleness.tick() => {
+                let stale = aggregator.remove_stale(max_age);
+                if stale.is_empty() {
+                    continue;
+                }
+                warn!(?stale, "dropped stale orderbooks");
+                None
+            }
+        };
+
+        publishing = publishing || aggregator.orderbooks.len() > 1;
+        if !publishing {
+            continue;
+        }
+
+        let timer = metrics::AGGREGATE_DURATION_SECONDS.start_timer();
+        let raw_depth = Arc::new(aggregator.aggregate_raw());
+        let depth = match cross_policy {
+            CrossPolicy::Flag => raw_depth.clone(),
+            _ => Arc::new(aggregator.uncross((*raw_depth).clone())),
+        };
+        let orderbook = depth.limit(LIMIT);
+        timer.observe_duration();
+        metrics::observe_orderbook(&pair, &orderbook);
+        tx.send(Snapshot {
+            update_id,
+            published_at: trade::now_micros(),
+            orderbook,
+            depth,
+            raw_depth,
+            quote_rates: aggregator.quote_rates.clone(),
+        })?;
+        if let Some(received_at) = received_at {
+            metrics::PUBLISH_LATENCY_SECONDS.observe(received_at.elapsed().as_secs_f64());
+        }
+        debug!(update_id, "published aggregated orderbook");
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use proptest::prelude::*;
+    use rust_decimal::Decimal;
     use rust_decimal_macros::*;
 
     use super::*;
-    use crate::order_book::{Orderbook, OrderbookLevel};
+    use crate::order_book::{strategy, BookState, LevelSide, Orderbook, OrderbookLevel};
+    use crate::scale::Scale;
+
+    const SCALE: Scale = Scale::new(8, 8);
+
+    fn bid(price: Decimal, exchange: Exchange) -> OrderbookLevel {
+        OrderbookLevel::bid(
+            SCALE.ticks(price).unwrap(),
+            SCALE.lots(dec!(1)).unwrap(),
+            exchange,
+        )
+    }
+
+    fn ask(price: Decimal, exchange: Exchange) -> OrderbookLevel {
+        OrderbookLevel::ask(
+            SCALE.ticks(price).unwrap(),
+            SCALE.lots(dec!(1)).unwrap(),
+            exchange,
+        )
+    }
 
     #[test]
     fn test_orderbook_aggregation() {
         let asks = vec![
-            OrderbookLevel::ask(dec!(1.3), dec!(1.0), Exchange::Bitstamp),
-            OrderbookLevel::ask(dec!(1.1), dec!(1.0), Exchange::Bitstamp),
-            OrderbookLevel::ask(dec!(0.9), dec!(1.0), Exchange::Bitstamp),
+            ask(dec!(1.3), Exchange::Bitstamp),
+            ask(dec!(1.1), Exchange::Bitstamp),
+            ask(dec!(0.9), Exchange::Bitstamp),
         ];
         let bids = vec![
-            OrderbookLevel::bid(dec!(0.83), dec!(1.0), Exchange::Bitstamp),
-            OrderbookLevel::bid(dec!(0.75), dec!(1.0), Exchange::Bitstamp),
-            OrderbookLevel::bid(dec!(0.7), dec!(1.0), Exchange::Bitstamp),
+            bid(dec!(0.83), Exchange::Bitstamp),
+            bid(dec!(0.75), Exchange::Bitstamp),
+            bid(dec!(0.7), Exchange::Bitstamp),
         ];
-        let bitstamp_ob = Orderbook::from_bids_asks(bids, asks);
+        let bitstamp_ob = Orderbook::from_bids_asks(SCALE, bids, asks);
         let asks = vec![
-            OrderbookLevel::ask(dec!(1.2), dec!(1.0), Exchange::Binance),
-            OrderbookLevel::ask(dec!(1.1), dec!(1.0), Exchange::Binance),
-            OrderbookLevel::ask(dec!(0.85), dec!(1.0), Exchange::Binance),
+            ask(dec!(1.2), Exchange::Binance),
+            ask(dec!(1.1), Exchange::Binance),
+            ask(dec!(0.85), Exchange::Binance),
         ];
         let bids = vec![
-            OrderbookLevel::bid(dec!(0.8), dec!(1.0), Exchange::Binance),
-            OrderbookLevel::bid(dec!(0.75), dec!(1.0), Exchange::Binance),
-            OrderbookLevel::bid(dec!(0.7), dec!(1.0), Exchange::Binance),
+            bid(dec!(0.8), Exchange::Binance),
+            bid(dec!(0.75), Exchange::Binance),
+            bid(dec!(0.7), Exchange::Binance),
         ];
-        let binance_ob = Orderbook::from_bids_asks(bids, asks);
+        let binance_ob = Orderbook::from_bids_asks(SCALE, bids, asks);
         let mut aggregator = Aggregator::new();
         aggregator.update(Exchange::Binance, binance_ob);
         aggregator.update(Exchange::Bitstamp, bitstamp_ob);
         let aggregated = aggregator.aggregate();
 
         let top_bid = aggregated.bids.first().unwrap();
-        assert_eq!(top_bid.price, dec!(0.83));
+        assert_eq!(aggregated.top_bid(), Some(dec!(0.83)));
         assert_eq!(top_bid.exchange, Exchange::Bitstamp);
 
         let top_ask = aggregated.asks.first().unwrap();
         assert_eq!(top_ask.exchange, Exchange::Binance);
-        assert_eq!(top_ask.price, dec!(0.85));
+        assert_eq!(aggregated.top_ask(), Some(dec!(0.85)));
+    }
+
+    fn orderbook(exchange: Exchange, bid_price: Decimal, ask_price: Decimal) -> Orderbook {
+        Orderbook::from_bids_asks(
+            SCALE,
+            vec![bid(bid_price, exchange)],
+            vec![ask(ask_price, exchange)],
+        )
+    }
+
+    #[test]
+    fn empty_update_removes_exchange() {
+        let mut aggregator = Aggregator::new();
+        aggregator.update(
+            Exchange::Binance,
+            orderbook(Exchange::Binance, dec!(1), dec!(2)),
+        );
+        aggregator.update(Exchange::Binance, Orderbook::new());
+        assert!(aggregator.orderbooks.is_empty());
+        assert!(aggregator.aggregate().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stale_orderbooks_are_removed() {
+        let mut aggregator = Aggregator::new();
+        aggregator.update(
+            Exchange::Binance,
+            orderbook(Exchange::Binance, dec!(1), dec!(2)),
+        );
+        time::advance(Duration::from_secs(2)).await;
+        aggregator.update(
+            Exchange::Bitstamp,
+            orderbook(Exchange::Bitstamp, dec!(1), dec!(2)),
+        );
+
+        let stale = aggregator.remove_stale(Duration::from_secs(1));
+        assert_eq!(stale, vec![Exchange::Binance]);
+        assert_eq!(aggregator.orderbooks.len(), 1);
+        assert!(aggregator.orderbooks.contains_key(&Exchange::Bitstamp));
+    }
+
+    /// Aggregator with Binance crossing Bitstamp, which was updated later.
+    async fn crossed(cross_policy: CrossPolicy) -> Aggregator {
+        let mut aggregator = Aggregator::new().with_cross_policy(cross_policy);
+        let binance = Orderbook::from_bids_asks(
+            SCALE,
+            vec![
+                bid(dec!(1.4), Exchange::Binance),
+                bid(dec!(1.0), Exchange::Binance),
+            ],
+            vec![ask(dec!(1.5), Exchange::Binance)],
+        );
+        aggregator.update(Exchange::Binance, binance);
+        time::advance(Duration::from_secs(1)).await;
+        aggregator.update(
+            Exchange::Bitstamp,
+            orderbook(Exchange::Bitstamp, dec!(1.1), dec!(1.3)),
+        );
+        aggregator
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn cross_policies() {
+        let aggregated = crossed(CrossPolicy::Flag).await.aggregate();
+        assert_eq!(aggregated.state(), BookState::Crossed);
+        assert_eq!(aggregated.top_bid(), Some(dec!(1.4)));
+
+        // Binance's bid is older than Bitstamp's ask.
+        let aggregated = crossed(CrossPolicy::DropStaler).await.aggregate();
+        assert_eq!(aggregated.state(), BookState::Normal);
+        assert_eq!(aggregated.top_bid(), Some(dec!(1.1)));
+        assert_eq!(aggregated.top_ask(), Some(dec!(1.3)));
+        assert_eq!(aggregated.bids.len(), 2);
+
+        let aggregated = crossed(CrossPolicy::DropBoth).await.aggregate();
+        assert_eq!(aggregated.state(), BookState::Normal);
+        assert_eq!(aggregated.top_bid(), Some(dec!(1.1)));
+        assert_eq!(aggregated.top_ask(), Some(dec!(1.5)));
+    }
+
+    #[test]
+    fn locked_venues_are_dropped() {
+        let mut aggregator = Aggregator::new().with_cross_policy(CrossPolicy::DropBoth);
+        aggregator.update(
+            Exchange::Binance,
+            orderbook(Exchange::Binance, dec!(1.0), dec!(1.2)),
+        );
+        aggregator.update(
+            Exchange::Bitstamp,
+            orderbook(Exchange::Bitstamp, dec!(1.2), dec!(1.4)),
+        );
+        let aggregated = aggregator.aggregate();
+        assert_eq!(aggregated.top_bid(), Some(dec!(1.0)));
+        assert_eq!(aggregated.top_ask(), Some(dec!(1.4)));
+
+        let aggregator = aggregator.with_cross_policy(CrossPolicy::Flag);
+        assert_eq!(aggregator.aggregate_depth().state(), BookState::Locked);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn run_rejects_levels_of_other_exchanges() {
+        let (tx, rx) = mpsc::channel(8);
+        let (snapshot_tx, mut snapshot_rx) = watch::channel(Snapshot::default());
+        tokio::spawn(run(
+            "ethbtc".to_owned(),
+            rx,
+            snapshot_tx,
+            Duration::from_secs(5),
+            CrossPolicy::Flag,
+        ));
+
+        let updates = vec![
+            (
+                Exchange::Binance,
+                orderbook(Exchange::Binance, dec!(1.0), dec!(1.2)),
+            ),
+            (
+                Exchange::Bitstamp,
+                orderbook(Exchange::Binance, dec!(1.5), dec!(1.6)),
+            ),
+            (
+                Exchange::Bitstamp,
+                orderbook(Exchange::Bitstamp, dec!(1.1), dec!(1.3)),
+            ),
+        ];
+        for (exchange, orderbook) in updates {
+            tx.send(OrderbookUpdateEvent::new(exchange, orderbook))
+                .await
+                .unwrap();
+        }
+        snapshot_rx.changed().await.unwrap();
+        assert!(snapshot_rx.borrow().published_at > 0);
+        let orderbook = snapshot_rx.borrow().orderbook.clone();
+        assert_eq!(orderbook.top_bid(), Some(dec!(1.1)));
+        assert_eq!(orderbook.bids[0].exchange, Exchange::Bitstamp);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn run_publishes_quote_rates() {
+        let (tx, rx) = mpsc::channel(8);
+        let (snapshot_tx, mut snapshot_rx) = watch::channel(Snapshot::default());
+        tokio::spawn(run(
+            "btcusd".to_owned(),
+            rx,
+            snapshot_tx,
+            Duration::from_secs(5),
+            CrossPolicy::Flag,
+        ));
+
+        let binance = OrderbookUpdateEvent {
+            quote_rate: Some(dec!(0.9998)),
+            ..OrderbookUpdateEvent::new(
+                Exchange::Binance,
+                orderbook(Exchange::Binance, dec!(1.0), dec!(1.2)),
+            )
+        };
+        let bitstamp = orderbook(Exchange::Bitstamp, dec!(1.1), dec!(1.3));
+        tx.send(binance).await.unwrap();
+        tx.send(OrderbookUpdateEvent::new(Exchange::Bitstamp, bitstamp))
+            .await
+            .unwrap();
+        snapshot_rx.changed().await.unwrap();
+        let quote_rates = snapshot_rx.borrow_and_update().quote_rates.clone();
+        assert_eq!(quote_rates.len(), 1);
+        assert_eq!(quote_rates[&Exchange::Binance], dec!(0.9998));
+
+        tx.send(OrderbookUpdateEvent::new(
+            Exchange::Binance,
+            Orderbook::new(),
+        ))
+        .await
+        .unwrap();
+        snapshot_rx.changed().await.unwrap();
+        assert!(snapshot_rx.borrow().quote_rates.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn run_publishes_after_venue_drops() {
+        let (tx, rx) = mpsc::channel(8);
+        let (snapshot_tx, mut snapshot_rx) = watch::channel(Snapshot::default());
+        let max_age = Duration::from_secs(5);
+        tokio::spawn(run(
+            "ethbtc".to_owned(),
+            rx,
+            snapshot_tx,
+            max_age,
+            CrossPolicy::Flag,
+        ));
+
+        let binance = orderbook(Exchange::Binance, dec!(1.0), dec!(1.2));
+        let bitstamp = orderbook(Exchange::Bitstamp, dec!(1.1), dec!(1.3));
+        tx.send(OrderbookUpdateEvent::new(Exchange::Binance, binance))
+            .await
+            .unwrap();
+        tx.send(OrderbookUpdateEvent::new(Exchange::Bitstamp, bitstamp))
+            .await
+            .unwrap();
+        snapshot_rx.changed().await.unwrap();
+        let orderbook = snapshot_rx.borrow_and_update().orderbook.clone();
+        assert_eq!(orderbook.top_bid(), Some(dec!(1.1)));
+
+        tx.send(OrderbookUpdateEvent::new(
+            Exchange::Bitstamp,
+            Orderbook::new(),
+        ))
+        .await
+        .unwrap();
+        snapshot_rx.changed().await.unwrap();
+        let orderbook = snapshot_rx.borrow_and_update().orderbook.clone();
+        assert_eq!(orderbook.top_bid(), Some(dec!(1.0)));
+
+        snapshot_rx.changed().await.unwrap();
+        assert!(snapshot_rx.borrow().orderbook.is_empty());
+    }
+
+    /// Orderbook of `exchange` around a mid close enough to the other
+    /// venue's for their bids and asks to overlap.
+    fn overlapping(exchange: Exchange) -> impl Strategy<Value = Orderbook> {
+        (-50i64..=50).prop_flat_map(move |offset| {
+            strategy::orderbook_around(exchange, strategy::MID_TICKS + offset, 100, 2 * LIMIT)
+        })
+    }
+
+    /// Whether `level` locks or crosses a level of another venue in
+    /// `orderbook`.
+    fn conflicts(level: &OrderbookLevel, orderbook: &Orderbook) -> bool {
+        match level.side {
+            LevelSide::Bid => orderbook
+                .asks
+                .iter()
+                .any(|ask| ask.exchange != level.exchange && ask.price <= level.price),
+            LevelSide::Ask => orderbook
+                .bids
+                .iter()
+                .any(|bid| bid.exchange != level.exchange && bid.price >= level.price),
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn aggregate_keeps_book_invariants(
+            binance in overlapping(Exchange::Binance),
+            bitstamp in overlapping(Exchange::Bitstamp),
+            cross_policy in prop_oneof![
+                Just(CrossPolicy::Flag),
+                Just(CrossPolicy::DropStaler),
+                Just(CrossPolicy::DropBoth),
+            ],
+        ) {
+            let all_bids = [binance.bids.to_vec(), bitstamp.bids.to_vec()].concat();
+            let all_asks = [binance.asks.to_vec(), bitstamp.asks.to_vec()].concat();
+            let input = Orderbook::from_bids_asks(strategy::SCALE, all_bids, all_asks);
+            let mut aggregator = Aggregator::new().with_cross_policy(cross_policy);
+            aggregator.update(Exchange::Binance, binance);
+            aggregator.update(Exchange::Bitstamp, bitstamp);
+            let depth = aggregator.aggregate_depth();
+            let aggregated = aggregator.aggregate();
+
+            prop_assert_eq!(aggregated.bids.len(), depth.bids.len().min(LIMIT));
+            prop_assert_eq!(aggregated.asks.len(), depth.asks.len().min(LIMIT));
+            // Both venues may quote the same price, but never the same level.
+            for pair in depth.bids.windows(2) {
+                prop_assert!(pair[0] > pair[1]);
+                prop_assert!(pair[0].price >= pair[1].price);
+            }
+            for pair in depth.asks.windows(2) {
+                prop_assert!(pair[0] < pair[1]);
+                prop_assert!(pair[0].price <= pair[1].price);
+            }
+
+            let kept = |level: &OrderbookLevel| match level.side {
+                LevelSide::Bid => depth.bids.contains(level),
+                LevelSide::Ask => depth.asks.contains(level),
+            };
+            let levels = || input.bids.iter().chain(input.asks.iter());
+            prop_assert_eq!(
+                depth.bids.len() + depth.asks.len(),
+                levels().filter(|level| kept(level)).count()
+            );
+            match cross_policy {
+                CrossPolicy::Flag => {
+                    prop_assert!(levels().all(kept));
+                    prop_assert_eq!(depth.state(), input.state());
+                }
+                CrossPolicy::DropStaler | CrossPolicy::DropBoth => {
+                    // Only conflicting levels are dropped, until none are left.
+                    for level in levels().filter(|level| !kept(level)) {
+                        prop_assert!(conflicts(level, &input));
+                    }
+                    prop_assert_eq!(depth.state(), BookState::Normal);
+                    if let (Some(bid), Some(ask)) = (depth.top_bid(), depth.top_ask()) {
+                        prop_assert!(bid < ask);
+                    }
+                }
+            }
+        }
     }
 }
@@ -0,0 +1,275 @@
+//! # arbitrage
+//!
+//! Detects crossed venues in the aggregated orderbook: a bid on one venue
+//! above an ask on another. Buying the ask and selling into the bid is
+//! profitable as long as the difference exceeds both venues' taker fees.
+use std::cmp::Reverse;
+
+use rust_decimal::prelude::*;
+
+use crate::error::Error;
+use crate::fees::TakerFees;
+use crate::order_book::{Exchange, Orderbook, OrderbookLevel};
+
+/// Profitable buy on one venue and sell on another.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Opportunity {
+    /// Venue whose asks are bought.
+    pub buy: Exchange,
+    /// Venue whose bids are sold into.
+    pub sell: Exchange,
+    /// Base quantity which can be bought and sold at a profit.
+    pub quantity: Decimal,
+    /// Worst ask price bought.
+    pub buy_price: Decimal,
+    /// Worst bid price sold at.
+    pub sell_price: Decimal,
+    /// Profit after taker fees on both venues, in the quote currency.
+    pub profit: Decimal,
+    /// `profit` relative to the fee-inclusive cost of buying, in basis
+    /// points.
+    pub profit_bps: Decimal,
+}
+
+/// Smallest profit an `Opportunity` must make to be reported.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct Threshold {
+    /// In the quote currency.
+    pub min_profit: Decimal,
+    /// Relative to the cost of buying, in basis points.
+    pub min_profit_bps: Decimal,
+}
+
+impl Threshold {
+    /// Read the minimums from the `ARB_MIN_PROFIT` and `ARB_MIN_PROFIT_BPS`
+    /// environment variables, zero if unset.
+    pub fn from_env() -> crate::Result<Self> {
+        let var = |name| match std::env::var(name) {
+            Ok(value) => Decimal::from_str(value.trim()).map_err(|err| Error::config(name, err)),
+            Err(_) => Ok(Decimal::zero()),
+        };
+        Ok(Self {
+            min_profit: var("ARB_MIN_PROFIT")?,
+            min_profit_bps: var("ARB_MIN_PROFIT_BPS")?,
+        })
+    }
+
+    /// Whether `opportunity` makes at least the minimum profit.
+    pub fn accepts(&self, opportunity: &Opportunity) -> bool {
+        opportunity.profit >= self.min_profit && opportunity.profit_bps >= self.min_profit_bps
+    }
+}
+
+/// Find every pair of venues crossed in `orderbook`, a full-depth
+/// aggregate, by more than their taker fees.
+///
+/// The `Synthetic` venue is left out, as its levels can only be traded
+/// through its legs, each charging its own fee.
+///
+/// For each pair the asks of the buy venue are matched with the bids of
+/// the sell venue, best first, for as long as the fee-adjusted bid stays
+/// above the fee-adjusted ask. The most profitable opportunity comes
+/// first.
+pub fn detect(orderbook: &Orderbook, fees: &TakerFees) -> Vec<Opportunity> {
+    let mut venues: Vec<Exchange> = orderbook
+        .bids
+        .iter()
+        .chain(orderbook.asks.iter())
+        .map(|level| level.exchange)
+        .filter(|&exchange| exchange != Exchange::Synthetic)
+        .collect();
+    venues.sort();
+    venues.dedup();
+
+    let mut opportunities = Vec::new();
+    for &buy in &venues {
+        for &sell in &venues {
+            if buy == sell {
+                continue;
+            }
+            let asks = orderbook.asks.iter().filter(|ask| ask.exchange == buy);
+            let bids = orderbook.bids.iter().filter(|bid| bid.exchange == sell);
+            if let Some(opportunity) = cross(orderbook, fees, asks, bids) {
+                opportunities.push(opportunity);
+            }
+        }
+    }
+    opportunities.sort_by_key(|opportunity| Reverse(opportunity.profit));
+    opportunities
+}
+
+/// Match `asks` of one venue with `bids` of another while profitable.
+///
+/// Levels without a price or size are skipped.
+fn cross<'a>(
+    orderbook: &Orderbook,
+    fees: &TakerFees,
+    asks: impl Iterator<Item = &'a OrderbookLevel>,
+    bids: impl Iterator<Item = &'a OrderbookLevel>,
+) -> Option<Opportunity> {
+    let scale = orderbook.scale;
+    let tradable = |level: &&OrderbookLevel| level.price != 0 && level.size != 0;
+    let mut asks = asks.filter(tradable).peekable();
+    let mut bids = bids.filter(tradable).peekable();
+    let (mut ask_left, mut bid_left) = (None, None);
+    let mut matched: Option<Opportunity> = None;
+    let mut cost = Decimal::zero();
+
+    while let (Some(ask), Some(bid)) = (asks.peek(), bids.peek()) {
+        let ask_price = fees.effective_price(ask, scale);
+        let bid_price = fees.effective_price(bid, scale);
+        if bid_price <= ask_price {
+            break;
+        }
+        let ask_size = *ask_left.get_or_insert_with(|| scale.size(ask.size));
+        let bid_size = *bid_left.get_or_insert_with(|| scale.size(bid.size));
+        let quantity = ask_size.min(bid_size);
+
+        let opportunity = matched.get_or_insert_with(|| Opportunity {
+            buy: ask.exchange,
+            sell: bid.exchange,
+            quantity: Decimal::zero(),
+            buy_price: Decimal::zero(),
+            sell_price: Decimal::zero(),
+            profit: Decimal::zero(),
+            profit_bps: Decimal::zero(),
+        });
+        opportunity.quantity += quantity;
+        opportunity.buy_price = scale.price(ask.price);
+        opportunity.sell_price = scale.price(bid.price);
+        opportunity.profit += quantity * (bid_price - ask_price);
+        cost += quantity * ask_price;
+
+        ask_left = Some(ask_size - quantity);
+        bid_left = Some(bid_size - quantity);
+        if ask_left == Some(Decimal::zero()) {
+            asks.next();
+            ask_left = None;
+        }
+        if bid_left == Some(Decimal::zero()) {
+            bids.next();
+            bid_left = None;
+        }
+    }
+
+    if cost.is_zero() {
+        return None;
+    }
+    matched.map(|opportunity| Opportunity {
+        profit_bps: opportunity.profit / cost * Decimal::from(10_000),
+        ..opportunity
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+    use crate::scale::Scale;
+
+    const SCALE: Scale = Scale::new(2, 2);
+
+    fn level(side: &str, price: Decimal, size: Decimal, exchange: Exchange) -> OrderbookLevel {
+        let (price, size) = (SCALE.ticks(price).unwrap(), SCALE.lots(size).unwrap());
+        match side {
+            "bid" => OrderbookLevel::bid(price, size, exchange),
+            _ => OrderbookLevel::ask(price, size, exchange),
+        }
+    }
+
+    /// Bitstamp bids above Binance's asks.
+    fn crossed() -> Orderbook {
+        Orderbook::from_bids_asks(
+            SCALE,
+            vec![
+                level("bid", dec!(102), dec!(1), Exchange::Bitstamp),
+                level("bid", dec!(101), dec!(2), Exchange::Bitstamp),
+                level("bid", dec!(99), dec!(1), Exchange::Binance),
+            ],
+            vec![
+                level("ask", dec!(100), dec!(1.5), Exchange::Binance),
+                level("ask", dec!(100.5), dec!(1), Exchange::Binance),
+                level("ask", dec!(103), dec!(1), Exchange::Bitstamp),
+            ],
+        )
+    }
+
+    #[test]
+    fn crossed_venues_are_detected() {
+        let opportunities = detect(&crossed(), &TakerFees::default());
+        assert_eq!(opportunities.len(), 1);
+        let opportunity = &opportunities[0];
+        assert_eq!(
+            (opportunity.buy, opportunity.sell),
+            (Exchange::Binance, Exchange::Bitstamp)
+        );
+        // 1 @ 100 -> 102, 0.5 @ 100 -> 101, 1 @ 100.5 -> 101.
+        assert_eq!(opportunity.quantity, dec!(2.5));
+        assert_eq!(opportunity.buy_price, dec!(100.5));
+        assert_eq!(opportunity.sell_price, dec!(101));
+        assert_eq!(opportunity.profit, dec!(3));
+        assert_eq!(opportunity.profit_bps.round_dp(2), dec!(119.76));
+    }
+
+    #[test]
+    fn fees_eat_into_profit() {
+        let fees = TakerFees(
+            [
+                (Exchange::Binance, dec!(50)),
+                (Exchange::Bitstamp, dec!(50)),
+            ]
+            .iter()
+            .cloned()
+            .collect(),
+        );
+        let opportunities = detect(&crossed(), &fees);
+        // Only 102 * 0.995 = 101.49 beats 100 * 1.005 = 100.5.
+        assert_eq!(opportunities[0].quantity, dec!(1));
+        assert_eq!(opportunities[0].profit, dec!(0.99));
+
+        let threshold = Threshold {
+            min_profit: dec!(1),
+            min_profit_bps: dec!(0),
+        };
+        assert!(!threshold.accepts(&opportunities[0]));
+    }
+
+    #[test]
+    fn synthetic_venue_is_left_out() {
+        let mut bids = crossed().bids.to_vec();
+        bids.push(level("bid", dec!(110), dec!(1), Exchange::Synthetic));
+        let mut asks = crossed().asks.to_vec();
+        asks.push(level("ask", dec!(90), dec!(1), Exchange::Synthetic));
+        let orderbook = Orderbook::from_bids_asks(SCALE, bids, asks);
+        let opportunities = detect(&orderbook, &TakerFees::default());
+        assert_eq!(opportunities, detect(&crossed(), &TakerFees::default()));
+    }
+
+    #[test]
+    fn empty_levels_are_skipped() {
+        let orderbook = Orderbook::from_bids_asks(
+            SCALE,
+            vec![level("bid", dec!(102), dec!(0), Exchange::Bitstamp)],
+            vec![level("ask", dec!(100), dec!(1), Exchange::Binance)],
+        );
+        assert!(detect(&orderbook, &TakerFees::default()).is_empty());
+
+        let mut asks = crossed().asks.to_vec();
+        asks.push(level("ask", dec!(0), dec!(1), Exchange::Binance));
+        asks.push(level("ask", dec!(99), dec!(0), Exchange::Binance));
+        let orderbook = Orderbook::from_bids_asks(SCALE, crossed().bids.to_vec(), asks);
+        let opportunities = detect(&orderbook, &TakerFees::default());
+        assert_eq!(opportunities, detect(&crossed(), &TakerFees::default()));
+    }
+
+    #[test]
+    fn uncrossed_book_has_no_opportunities() {
+        let orderbook = Orderbook::from_bids_asks(
+            SCALE,
+            vec![level("bid", dec!(99), dec!(1), Exchange::Bitstamp)],
+            vec![level("ask", dec!(100), dec!(1), Exchange::Binance)],
+        );
+        assert!(detect(&orderbook, &TakerFees::default()).is_empty());
+    }
+}
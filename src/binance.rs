@@ -1,92 +1,306 @@
-use futures::sink::SinkExt;
-use futures::stream::StreamExt;
-use rust_decimal::Decimal;
+use std::fmt;
+use std::str::FromStr;
+
+use serde::de::{self, Deserializer, IgnoredAny, MapAccess, Visitor};
 use serde::Deserialize;
+use strum_macros::{EnumString, IntoStaticStr};
 use tokio::sync::mpsc::Sender;
-use websocket_lite::{Message, Opcode};
+use tracing::{debug, instrument};
 
-use crate::order_book::{
-    AsksVec, BidsVec, Exchange, Orderbook, OrderbookLevel, OrderbookUpdateEvent,
-};
+use crate::error::Error;
+use crate::json::{Exact, Level, LevelsSeed};
+use crate::order_book::{Exchange, Orderbook, OrderbookUpdateEvent};
+use crate::recorder::Recorder;
+use crate::scale::Scale;
+use crate::shutdown::Shutdown;
+use crate::trade::{self, Aggressor, Trade};
+use crate::websocket;
 
-const URL: &str = "wss://stream.binance.com:9443/ws/";
+/// Base URL of the Binance websocket streams.
+pub const URL: &str = "wss://stream.binance.com:9443/ws/";
 
-/// Partial orderbook representation coming from Binance websocket.
-#[derive(Deserialize, Debug)]
-#[serde(rename_all = "camelCase")]
-struct PartialBookEvent {
-    last_update_id: i64,
-    bids: Vec<(Decimal, Decimal)>,
-    asks: Vec<(Decimal, Decimal)>,
+/// Parses the messages of one Binance connection, live or replayed.
+///
+/// Levels are parsed into buffers of ticks and lots of `scale` kept
+/// between messages, so parsing only allocates the returned `Orderbook`.
+#[derive(Debug, Default)]
+pub struct Parser {
+    scale: Scale,
+    last_update_id: u64,
+    bids: Vec<Level>,
+    asks: Vec<Level>,
 }
 
-impl From<PartialBookEvent> for Orderbook {
-    /// Create new `Orderbook` from `PartialBookEvent`
-    fn from(partial_book_event: PartialBookEvent) -> Self {
-        let orderbook_entry_from = move |e| OrderbookLevel::from_exchange(Exchange::Bitstamp, e);
-        let asks: Vec<OrderbookLevel> = partial_book_event
-            .asks
-            .into_iter()
-            .map(orderbook_entry_from)
-            .collect();
-        let bids: Vec<OrderbookLevel> = partial_book_event
-            .bids
-            .into_iter()
-            .map(orderbook_entry_from)
-            .collect::<Vec<OrderbookLevel>>();
-
-        Orderbook {
-            asks: AsksVec::from(asks),
-            bids: BidsVec::from(bids),
+impl Parser {
+    pub fn new(scale: Scale) -> Self {
+        Self {
+            scale,
+            ..Self::default()
+        }
+    }
+
+    /// Parse a text message into the orderbook it carries.
+    ///
+    /// Every Binance depth message carries one, so `None` is never
+    /// returned. Fails with `Error::SequenceGap` if the update is older
+    /// than the previous one.
+    pub fn parse(&mut self, msg: &str) -> crate::Result<Option<Orderbook>> {
+        let exchange = Exchange::Binance;
+        let event = PartialBookEvent {
+            scale: self.scale,
+            bids: &mut self.bids,
+            asks: &mut self.asks,
+        };
+        let mut deserializer = serde_json::Deserializer::from_str(msg);
+        let last_update_id = deserializer
+            .deserialize_map(event)
+            .and_then(|last_update_id| deserializer.end().map(|()| last_update_id))
+            .map_err(|err| Error::protocol(exchange, msg, err))?;
+        if last_update_id < self.last_update_id {
+            return Err(Error::SequenceGap {
+                exchange,
+                last: self.last_update_id,
+                received: last_update_id,
+            });
         }
+        self.last_update_id = last_update_id;
+        Ok(Some(Orderbook::from_levels(
+            self.scale, exchange, &self.bids, &self.asks,
+        )))
     }
 }
 
-/// Run the Binance websocket client.
-pub async fn run(pair: &str, tx: Sender<OrderbookUpdateEvent>) -> crate::Result<()> {
-    let url = format!("{}{}@depth10@100ms", URL, pair);
-    let builder = websocket_lite::ClientBuilder::new(&url)?;
-    let mut ws_stream = builder.async_connect().await?;
+/// Fields of `PartialBookEvent`.
+#[derive(Deserialize)]
+#[serde(field_identifier, rename_all = "camelCase")]
+enum Field {
+    LastUpdateId,
+    Bids,
+    Asks,
+    #[serde(other)]
+    Other,
+}
 
-    loop {
-        let msg: Option<crate::Result<Message>> = ws_stream.next().await;
+/// Partial book depth message coming from Binance websocket,
+/// `{"lastUpdateId":..,"bids":[..],"asks":[..]}`.
+///
+/// Deserializes the levels into the parser's buffers and returns the
+/// `lastUpdateId`.
+struct PartialBookEvent<'p> {
+    scale: Scale,
+    bids: &'p mut Vec<Level>,
+    asks: &'p mut Vec<Level>,
+}
 
-        let msg = match msg {
-            Some(Ok(msg)) => msg,
-            Some(Err(err)) => {
-                let _ = ws_stream.send(websocket_lite::Message::close(None)).await;
-                bail!("received error message; closing ws; {:?}", err)
-            }
-            None => {
-                break Err(String::from("Stream terminated").into());
+impl<'de, 'p> Visitor<'de> for PartialBookEvent<'p> {
+    type Value = u64;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a partial book depth message")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<u64, A::Error> {
+        let mut last_update_id = None;
+        let (mut bids, mut asks) = (false, false);
+        while let Some(field) = map.next_key()? {
+            match field {
+                Field::LastUpdateId => last_update_id = Some(map.next_value()?),
+                Field::Bids => {
+                    map.next_value_seed(LevelsSeed {
+                        scale: self.scale,
+                        levels: &mut *self.bids,
+                    })?;
+                    bids = true;
+                }
+                Field::Asks => {
+                    map.next_value_seed(LevelsSeed {
+                        scale: self.scale,
+                        levels: &mut *self.asks,
+                    })?;
+                    asks = true;
+                }
+                Field::Other => {
+                    map.next_value::<IgnoredAny>()?;
+                }
             }
+        }
+        if !bids {
+            return Err(de::Error::missing_field("bids"));
+        }
+        if !asks {
+            return Err(de::Error::missing_field("asks"));
+        }
+        last_update_id.ok_or_else(|| de::Error::missing_field("lastUpdateId"))
+    }
+}
+
+/// Run the Binance websocket client connected to `url`, normally `URL`.
+///
+/// Levels are kept in ticks and lots of `scale`. Every text message is
+/// handed to `recorder`, if any, before parsing. The websocket is closed
+/// and `Ok` returned once `shutdown` fires. Fails with
+/// `Error::SequenceGap` if an update older than the previous one arrives.
+#[instrument(
+    name = "connection",
+    skip(tx, recorder, shutdown),
+    fields(venue = "Binance")
+)]
+pub async fn run(
+    url: &str,
+    pair: &str,
+    scale: Scale,
+    tx: Sender<OrderbookUpdateEvent>,
+    recorder: Option<Recorder>,
+    shutdown: Shutdown,
+) -> crate::Result<()> {
+    let exchange = Exchange::Binance;
+    let url = format!("{}{}@depth10@100ms", url, pair);
+    let mut parser = Parser::new(scale);
+    websocket::read(exchange, &url, None, &tx, shutdown, |msg| {
+        if let Some(recorder) = &recorder {
+            recorder.record(exchange, msg);
+        }
+        let orderbook = match parser.parse(msg)? {
+            Some(orderbook) => orderbook,
+            None => return Ok(None),
         };
+        let update_event = OrderbookUpdateEvent::new(exchange, orderbook);
+        debug!(
+            update_id = update_event.id,
+            bids = update_event.orderbook.bids.len(),
+            asks = update_event.orderbook.asks.len(),
+            "received orderbook update"
+        );
+        Ok(Some(update_event))
+    })
+    .await
+}
 
-        match msg.opcode() {
-            Opcode::Text => {
-                let response = msg.as_text().unwrap();
-                let update: PartialBookEvent = serde_json::from_str(response)?;
-                let orderbook = Orderbook::from(update);
-                let update_event = OrderbookUpdateEvent::new(Exchange::Binance, orderbook);
-                tx.send(update_event).await?;
-            }
-            Opcode::Ping => ws_stream.send(Message::pong(msg.into_data())).await?,
-            Opcode::Close => {
-                let _ = ws_stream.send(Message::close(None)).await;
-                break Ok(());
-            }
-            Opcode::Binary => {}
-            Opcode::Pong => {}
+/// Binance stream a trade connection subscribes to.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, EnumString, IntoStaticStr)]
+pub enum TradeStream {
+    /// `<symbol>@trade`, every trade.
+    #[default]
+    #[strum(serialize = "trade")]
+    Trade,
+    /// `<symbol>@aggTrade`, the trades of one taker order at one price
+    /// combined.
+    #[strum(serialize = "aggTrade")]
+    AggTrade,
+}
+
+impl TradeStream {
+    /// Read the stream from the `BINANCE_TRADE_STREAM` environment
+    /// variable, `trade` if unset.
+    pub fn from_env() -> crate::Result<Self> {
+        match std::env::var("BINANCE_TRADE_STREAM") {
+            Ok(stream) => Self::from_str(stream.trim())
+                .map_err(|err| Error::config("BINANCE_TRADE_STREAM", err)),
+            Err(_) => Ok(Self::default()),
         }
     }
 }
 
+/// `trade` or `aggTrade` event; the id is `t` for the former and `a` for
+/// the latter, whose trade events use `a` for the seller's order id.
+#[derive(Deserialize)]
+struct TradeEvent<'a> {
+    #[serde(rename = "e", borrow)]
+    event: std::borrow::Cow<'a, str>,
+    #[serde(rename = "t")]
+    trade_id: Option<u64>,
+    #[serde(rename = "a")]
+    aggregate_id: Option<u64>,
+    #[serde(rename = "p")]
+    price: Exact,
+    #[serde(rename = "q")]
+    quantity: Exact,
+    /// Milliseconds since the epoch.
+    #[serde(rename = "T")]
+    trade_time: u64,
+    #[serde(rename = "m")]
+    buyer_is_maker: bool,
+}
+
+/// Parse a message of a trade stream into the trade it carries.
+///
+/// Returns `None` for events other than trades.
+pub fn parse_trade(msg: &str) -> crate::Result<Option<Trade>> {
+    let exchange = Exchange::Binance;
+    let event: TradeEvent =
+        serde_json::from_str(msg).map_err(|err| Error::protocol(exchange, msg, err))?;
+    let (id, field) = match &*event.event {
+        "trade" => (event.trade_id, "t"),
+        "aggTrade" => (event.aggregate_id, "a"),
+        _ => return Ok(None),
+    };
+    let id = id.ok_or_else(|| {
+        let err: serde_json::Error = de::Error::missing_field(field);
+        Error::protocol(exchange, msg, err)
+    })?;
+    Ok(Some(Trade {
+        exchange,
+        id,
+        price: event.price.0,
+        size: event.quantity.0,
+        // The buyer is the maker when a sell order hit its bid.
+        aggressor: if event.buyer_is_maker {
+            Aggressor::Sell
+        } else {
+            Aggressor::Buy
+        },
+        traded_at: event.trade_time * 1_000,
+        received_at: trade::now_micros(),
+    }))
+}
+
+/// Run the Binance trade stream client loop connected to `url`, normally
+/// `URL`, sending every trade of `pair` on `stream`.
+///
+/// Trades aren't recorded. The websocket is closed and `Ok` returned once
+/// `shutdown` fires.
+#[instrument(name = "trades", skip(tx, shutdown), fields(venue = "Binance"))]
+pub async fn run_trades(
+    url: &str,
+    pair: &str,
+    stream: TradeStream,
+    tx: Sender<Trade>,
+    shutdown: Shutdown,
+) -> crate::Result<()> {
+    let stream: &'static str = stream.into();
+    let url = format!("{}{}@{}", url, pair, stream);
+    websocket::read(Exchange::Binance, &url, None, &tx, shutdown, parse_trade).await
+}
+
 #[cfg(test)]
 mod tests {
+    use std::time::Duration;
+
     use ntest::timeout;
-    use tokio::sync::mpsc;
+    use rust_decimal_macros::dec;
+    use tokio::sync::{mpsc, watch};
 
     use super::*;
+    use crate::mock_exchange::binance::{agg_trade, depth, trade};
+    use crate::mock_exchange::{MockExchange, Script};
+    use crate::order_book::LevelSide;
+
+    /// Run the connector against `exchange` until it stops.
+    async fn run_against(
+        exchange: &MockExchange,
+        tx: Sender<OrderbookUpdateEvent>,
+    ) -> crate::Result<()> {
+        let (_notify_shutdown, shutdown) = watch::channel(false);
+        run(
+            &exchange.url(),
+            "ethbtc",
+            Scale::default(),
+            tx,
+            None,
+            Shutdown::new(shutdown),
+        )
+        .await
+    }
 
     /// This test case asserts that the `run` function given the
     /// valid pair name will send a message through the channel
@@ -94,20 +308,161 @@ mod tests {
     #[tokio::test]
     #[timeout(5000)]
     async fn run_sends_updates_within_5s() {
+        let script = Script::new()
+            .ping()
+            .text(depth(1, &[("0.061", "2.5")], &[("0.062", "1.0")]));
+        let exchange = MockExchange::start(vec![script]).await.unwrap();
         let (tx, mut rx) = mpsc::channel(2);
 
+        let url = exchange.url();
         tokio::spawn(async move {
-            run("ethbtc", tx).await.unwrap();
+            let (_notify_shutdown, shutdown) = watch::channel(false);
+            run(
+                &url,
+                "ethbtc",
+                Scale::default(),
+                tx,
+                None,
+                Shutdown::new(shutdown),
+            )
+            .await
+            .unwrap();
         });
 
-        let recv = tokio::spawn(async move {
-            loop {
-                if let Some(_) = rx.recv().await {
-                    break;
-                }
-            }
+        let event = rx.recv().await.unwrap();
+        assert_eq!(event.exchange, Exchange::Binance);
+        assert_eq!(event.orderbook.top_bid(), Some(dec!(0.061)));
+        assert_eq!(event.orderbook.top_ask(), Some(dec!(0.062)));
+        assert_eq!(event.orderbook.bids[0].side, LevelSide::Bid);
+        assert_eq!(event.orderbook.asks[0].side, LevelSide::Ask);
+        assert_eq!(exchange.paths(), vec!["/ethbtc@depth10@100ms"]);
+    }
+
+    #[test]
+    fn levels_are_tagged_with_binance() {
+        let msg = depth(1, &[("0.061", "2.5"), ("0.06", "1")], &[("0.062", "1.0")]);
+        let orderbook = Parser::new(Scale::default()).parse(&msg).unwrap().unwrap();
+        let mut levels = orderbook.bids.iter().chain(orderbook.asks.iter());
+        assert!(levels.all(|level| level.exchange == Exchange::Binance));
+        let event = OrderbookUpdateEvent::new(Exchange::Binance, orderbook);
+        assert!(event.validate().is_ok());
+    }
+
+    #[tokio::test]
+    #[timeout(5000)]
+    async fn malformed_frame_is_protocol_error() {
+        let exchange = MockExchange::start(vec![Script::new().text("{\"lastUpdateId\":")])
+            .await
+            .unwrap();
+        let (tx, _rx) = mpsc::channel(2);
+
+        match run_against(&exchange, tx).await {
+            Err(Error::Protocol { payload, .. }) => assert_eq!(payload, "{\"lastUpdateId\":"),
+            res => panic!("unexpected result {:?}", res),
+        }
+    }
+
+    #[tokio::test]
+    #[timeout(5000)]
+    async fn invalid_utf8_frame_is_connection_error() {
+        let script = Script::new().raw_text(vec![b'{', 0xff, b'}']);
+        let exchange = MockExchange::start(vec![script]).await.unwrap();
+        let (tx, _rx) = mpsc::channel(2);
+
+        let res = run_against(&exchange, tx).await;
+        assert!(matches!(res, Err(Error::Connection { .. })), "{:?}", res);
+    }
+
+    #[tokio::test]
+    #[timeout(5000)]
+    async fn older_update_is_sequence_gap() {
+        let script = Script::new()
+            .text(depth(5, &[("1", "1")], &[("2", "1")]))
+            .text(depth(4, &[("1", "1")], &[("2", "1")]));
+        let exchange = MockExchange::start(vec![script]).await.unwrap();
+        let (tx, _rx) = mpsc::channel(2);
+
+        match run_against(&exchange, tx).await {
+            Err(Error::SequenceGap { last, received, .. }) => assert_eq!((last, received), (5, 4)),
+            res => panic!("unexpected result {:?}", res),
+        }
+    }
+
+    #[tokio::test]
+    #[timeout(5000)]
+    async fn disconnect_is_connection_error() {
+        let script = Script::new().delay(Duration::from_millis(50)).disconnect();
+        let exchange = MockExchange::start(vec![script]).await.unwrap();
+        let (tx, _rx) = mpsc::channel(2);
+
+        let res = run_against(&exchange, tx).await;
+        assert!(matches!(res, Err(Error::Connection { .. })), "{:?}", res);
+    }
+
+    #[tokio::test]
+    #[timeout(5000)]
+    async fn shutdown_closes_connection() {
+        let script = Script::new().text(depth(1, &[("1", "1")], &[("2", "1")]));
+        let exchange = MockExchange::start(vec![script]).await.unwrap();
+        let (tx, mut rx) = mpsc::channel(2);
+        let (notify_shutdown, shutdown) = watch::channel(false);
+
+        let url = exchange.url();
+        let connector = tokio::spawn(async move {
+            run(
+                &url,
+                "ethbtc",
+                Scale::default(),
+                tx,
+                None,
+                Shutdown::new(shutdown),
+            )
+            .await
+        });
+        rx.recv().await.unwrap();
+        notify_shutdown.send(true).unwrap();
+
+        connector.await.unwrap().unwrap();
+        while exchange.closed() == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[test]
+    fn trades_are_normalized() {
+        let trade = parse_trade(&trade(7, "0.0610", "2.5", 1_600_000_000_123, true))
+            .unwrap()
+            .unwrap();
+        assert_eq!(trade.exchange, Exchange::Binance);
+        assert_eq!(trade.id, 7);
+        assert_eq!((trade.price, trade.size), (dec!(0.0610), dec!(2.5)));
+        assert_eq!(trade.aggressor, Aggressor::Sell);
+        assert_eq!(trade.traded_at, 1_600_000_000_123_000);
+
+        let trade = parse_trade(&agg_trade(9, "0.0611", "1", 1, false))
+            .unwrap()
+            .unwrap();
+        assert_eq!((trade.id, trade.aggressor), (9, Aggressor::Buy));
+
+        assert!(parse_trade(&depth(1, &[], &[])).is_err());
+    }
+
+    #[tokio::test]
+    #[timeout(5000)]
+    async fn run_trades_sends_trades() {
+        let script = Script::new().text(agg_trade(3, "0.062", "0.5", 1, false));
+        let exchange = MockExchange::start(vec![script]).await.unwrap();
+        let (tx, mut rx) = mpsc::channel(2);
+
+        let url = exchange.url();
+        tokio::spawn(async move {
+            let (_notify_shutdown, shutdown) = watch::channel(false);
+            let stream = TradeStream::from_str("aggTrade").unwrap();
+            run_trades(&url, "ethbtc", stream, tx, Shutdown::new(shutdown)).await
         });
 
-        recv.await.unwrap();
+        let trade = rx.recv().await.unwrap();
+        assert_eq!((trade.id, trade.price), (3, dec!(0.062)));
+        assert_eq!(exchange.paths(), vec!["/ethbtc@aggTrade"]);
     }
 }
@@ -1,122 +1,382 @@
 //! # bitstamp
 //!
-use futures::sink::SinkExt;
-use futures::stream::StreamExt;
-use rust_decimal::Decimal;
+use std::borrow::Cow;
+use std::fmt;
+
+use serde::de::{self, DeserializeSeed, Deserializer, IgnoredAny, MapAccess, Visitor};
 use serde::Deserialize;
-use serde_aux::prelude::*;
 use tokio::sync::mpsc::Sender;
-use websocket_lite::{Message, Opcode};
-
-use crate::order_book::{
-    AsksVec, BidsVec, Exchange, Orderbook, OrderbookLevel, OrderbookUpdateEvent,
-};
-
-const URL: &str = "wss://ws.bitstamp.net";
-
-/// Orderbook representation coming from Bitstamp websocket.
-#[derive(Deserialize, Debug)]
-struct LiveOrderbookEvent {
-    #[serde(deserialize_with = "deserialize_number_from_string")]
-    timestamp: u64,
-    #[serde(deserialize_with = "deserialize_number_from_string")]
-    microtimestamp: u64,
-    asks: Vec<(Decimal, Decimal)>,
-    bids: Vec<(Decimal, Decimal)>,
+use tracing::{debug, instrument};
+
+use crate::error::Error;
+use crate::json::{Exact, Level, LevelsSeed, Number, Text};
+use crate::order_book::{Exchange, Orderbook, OrderbookUpdateEvent};
+use crate::recorder::Recorder;
+use crate::scale::Scale;
+use crate::shutdown::Shutdown;
+use crate::trade::{self, Aggressor, Trade};
+use crate::websocket;
+
+/// URL of the Bitstamp websocket API.
+pub const URL: &str = "wss://ws.bitstamp.net";
+
+/// Parses the messages of one Bitstamp connection, live or replayed.
+///
+/// Levels are parsed into buffers of ticks and lots of `scale` kept
+/// between messages, so parsing only allocates the returned `Orderbook`.
+#[derive(Debug, Default)]
+pub struct Parser {
+    scale: Scale,
+    last_microtimestamp: u64,
+    bids: Vec<Level>,
+    asks: Vec<Level>,
 }
 
-impl From<LiveOrderbookEvent> for Orderbook {
-    /// Create new `Orderbook` from `LiveOrderbookEvent`
-    fn from(orderbook_event: LiveOrderbookEvent) -> Self {
-        let orderbook_entry_from = move |e| OrderbookLevel::from_exchange(Exchange::Binance, e);
-        let asks: Vec<OrderbookLevel> = orderbook_event
-            .asks
-            .into_iter()
-            .map(orderbook_entry_from)
-            .collect();
-        let bids: Vec<OrderbookLevel> = orderbook_event
-            .bids
-            .into_iter()
-            .map(orderbook_entry_from)
-            .collect();
-        Orderbook {
-            asks: AsksVec::from(asks),
-            bids: BidsVec::from(bids),
+impl Parser {
+    pub fn new(scale: Scale) -> Self {
+        Self {
+            scale,
+            ..Self::default()
+        }
+    }
+
+    /// Parse a text message into the orderbook it carries.
+    ///
+    /// Returns `None` for events without an orderbook, e.g. the
+    /// subscription confirmation. Fails with `Error::SubscriptionRejected`
+    /// for error events and with `Error::SequenceGap` if the update is
+    /// older than the previous one.
+    pub fn parse(&mut self, msg: &str) -> crate::Result<Option<Orderbook>> {
+        let exchange = Exchange::Bitstamp;
+        let event = EventVisitor {
+            scale: self.scale,
+            bids: &mut self.bids,
+            asks: &mut self.asks,
+        };
+        let mut deserializer = serde_json::Deserializer::from_str(msg);
+        let event = deserializer
+            .deserialize_map(event)
+            .and_then(|event| deserializer.end().map(|()| event))
+            .map_err(|err| Error::protocol(exchange, msg, err))?;
+        match event {
+            Event::LiveOrderbook { microtimestamp } => {
+                if microtimestamp < self.last_microtimestamp {
+                    return Err(Error::SequenceGap {
+                        exchange,
+                        last: self.last_microtimestamp,
+                        received: microtimestamp,
+                    });
+                }
+                self.last_microtimestamp = microtimestamp;
+                Ok(Some(Orderbook::from_levels(
+                    self.scale, exchange, &self.bids, &self.asks,
+                )))
+            }
+            Event::Error { message } => Err(Error::SubscriptionRejected {
+                exchange,
+                reason: message.into_owned(),
+            }),
+            Event::Other { event, channel } => {
+                debug!(%event, %channel, "received event");
+                Ok(None)
+            }
         }
     }
 }
 
-/// Enum representing possible `data` structure in `Event`
-#[derive(Deserialize, Debug)]
-#[serde(untagged)]
-enum EventData {
-    LiveOrderbook(LiveOrderbookEvent),
-    Empty {},
+/// Event Bitstamp sends, `{"event":..,"channel":..,"data":{..}}`, told
+/// apart by the contents of `data`.
+#[derive(Debug)]
+enum Event<'a> {
+    /// Live orderbook update; its levels are in the parser's buffers.
+    LiveOrderbook { microtimestamp: u64 },
+    /// Request error, e.g. a rejected subscription.
+    Error { message: Cow<'a, str> },
+    /// Anything else, e.g. a subscription confirmation.
+    Other {
+        event: Cow<'a, str>,
+        channel: Cow<'a, str>,
+    },
 }
 
-/// General event structure that Bitstamp sends.
-#[derive(Deserialize, Debug)]
-struct Event {
-    event: String,
-    channel: String,
-    data: EventData,
+/// What the `data` of an `Event` contained.
+enum EventData<'a> {
+    LiveOrderbook { microtimestamp: u64 },
+    Error { message: Cow<'a, str> },
+    Empty,
 }
 
-/// Run the Bitstamp websocket client loop.
-pub async fn run(pair: &str, tx: Sender<OrderbookUpdateEvent>) -> crate::Result<()> {
-    let builder = websocket_lite::ClientBuilder::new(URL)?;
-    let mut ws_stream = builder.async_connect().await?;
+/// Fields of an `Event` and of its `data`.
+#[derive(Deserialize)]
+#[serde(field_identifier, rename_all = "lowercase")]
+enum Field {
+    Event,
+    Channel,
+    Data,
+    Timestamp,
+    Microtimestamp,
+    Bids,
+    Asks,
+    Message,
+    #[serde(other)]
+    Other,
+}
 
-    let subscribe_msg = format!(
-        r#"{{"event":"bts:subscribe","data":{{"channel":"order_book_{}"}}}}"#,
-        pair
-    );
-    ws_stream.send(Message::text(subscribe_msg)).await?;
+/// Deserializes an `Event`, its levels into the parser's buffers.
+struct EventVisitor<'p> {
+    scale: Scale,
+    bids: &'p mut Vec<Level>,
+    asks: &'p mut Vec<Level>,
+}
 
-    loop {
-        let msg: Option<crate::Result<Message>> = ws_stream.next().await;
+impl<'de, 'p> Visitor<'de> for EventVisitor<'p> {
+    type Value = Event<'de>;
 
-        let msg = match msg {
-            Some(Ok(msg)) => msg,
-            Some(Err(err)) => {
-                let _ = ws_stream.send(websocket_lite::Message::close(None)).await;
-                bail!("received error message; closing ws; {:?}", err)
-            }
-            None => {
-                break Err(String::from("Stream terminated").into());
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a Bitstamp event")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(mut self, mut map: A) -> Result<Event<'de>, A::Error> {
+        let (mut event, mut channel, mut data) = (None, None, None);
+        while let Some(field) = map.next_key()? {
+            match field {
+                Field::Event => event = Some(map.next_value::<Text>()?.0),
+                Field::Channel => channel = Some(map.next_value::<Text>()?.0),
+                Field::Data => data = Some(map.next_value_seed(&mut self)?),
+                _ => {
+                    map.next_value::<IgnoredAny>()?;
+                }
             }
-        };
+        }
+        let event = event.ok_or_else(|| de::Error::missing_field("event"))?;
+        let channel = channel.ok_or_else(|| de::Error::missing_field("channel"))?;
+        Ok(
+            match data.ok_or_else(|| de::Error::missing_field("data"))? {
+                EventData::LiveOrderbook { microtimestamp } => {
+                    Event::LiveOrderbook { microtimestamp }
+                }
+                EventData::Error { message } => Event::Error { message },
+                EventData::Empty => Event::Other { event, channel },
+            },
+        )
+    }
+}
 
-        match msg.opcode() {
-            Opcode::Text => {
-                let response = msg.as_text().unwrap();
-                let event: Event = serde_json::from_str(response)?;
-                match event.data {
-                    EventData::LiveOrderbook(orderbook_data) => {
-                        let orderbook = Orderbook::from(orderbook_data);
-                        let update_event = OrderbookUpdateEvent::new(Exchange::Bitstamp, orderbook);
-                        tx.send(update_event).await?;
-                    }
-                    _ => {}
+/// Deserializes the `data` of an `Event`.
+///
+/// It is an orderbook update if it has timestamps and both sides, else an
+/// error if it has a message.
+impl<'de, 'a, 'p> DeserializeSeed<'de> for &'a mut EventVisitor<'p> {
+    type Value = EventData<'de>;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de, 'a, 'p> Visitor<'de> for &'a mut EventVisitor<'p> {
+    type Value = EventData<'de>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Bitstamp event data")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<EventData<'de>, A::Error> {
+        let (mut timestamp, mut microtimestamp, mut message) = (None, None, None);
+        let (mut bids, mut asks) = (false, false);
+        while let Some(field) = map.next_key()? {
+            match field {
+                Field::Timestamp => timestamp = Some(map.next_value::<Number>()?),
+                Field::Microtimestamp => microtimestamp = Some(map.next_value::<Number>()?),
+                Field::Bids => {
+                    map.next_value_seed(LevelsSeed {
+                        scale: self.scale,
+                        levels: &mut *self.bids,
+                    })?;
+                    bids = true;
+                }
+                Field::Asks => {
+                    map.next_value_seed(LevelsSeed {
+                        scale: self.scale,
+                        levels: &mut *self.asks,
+                    })?;
+                    asks = true;
+                }
+                Field::Message => message = Some(map.next_value::<Text>()?.0),
+                _ => {
+                    map.next_value::<IgnoredAny>()?;
                 }
             }
-            Opcode::Ping => ws_stream.send(Message::pong(msg.into_data())).await?,
-            Opcode::Close => {
-                let _ = ws_stream.send(Message::close(None)).await;
-                break Ok(());
+        }
+        Ok(match (timestamp, microtimestamp, message) {
+            (Some(_), Some(Number(microtimestamp)), _) if bids && asks => {
+                EventData::LiveOrderbook { microtimestamp }
             }
-            Opcode::Binary => {}
-            Opcode::Pong => {}
+            (_, _, Some(message)) => EventData::Error { message },
+            _ => EventData::Empty,
+        })
+    }
+}
+
+/// Run the Bitstamp websocket client loop connected to `url`, normally `URL`.
+///
+/// Levels are kept in ticks and lots of `scale`. Every text message is
+/// handed to `recorder`, if any, before parsing. The websocket is closed
+/// and `Ok` returned once `shutdown` fires. Fails with
+/// `Error::SubscriptionRejected` if Bitstamp refuses the channel, e.g. for
+/// an unknown pair, and with `Error::SequenceGap` if an update older than
+/// the previous one arrives.
+#[instrument(
+    name = "connection",
+    skip(tx, recorder, shutdown),
+    fields(venue = "Bitstamp")
+)]
+pub async fn run(
+    url: &str,
+    pair: &str,
+    scale: Scale,
+    tx: Sender<OrderbookUpdateEvent>,
+    recorder: Option<Recorder>,
+    shutdown: Shutdown,
+) -> crate::Result<()> {
+    let exchange = Exchange::Bitstamp;
+    let subscribe_msg = format!(
+        r#"{{"event":"bts:subscribe","data":{{"channel":"order_book_{}"}}}}"#,
+        pair
+    );
+    let mut parser = Parser::new(scale);
+    websocket::read(exchange, url, Some(subscribe_msg), &tx, shutdown, |msg| {
+        if let Some(recorder) = &recorder {
+            recorder.record(exchange, msg);
+        }
+        let orderbook = match parser.parse(msg)? {
+            Some(orderbook) => orderbook,
+            None => return Ok(None),
+        };
+        let update_event = OrderbookUpdateEvent::new(exchange, orderbook);
+        debug!(
+            update_id = update_event.id,
+            bids = update_event.orderbook.bids.len(),
+            asks = update_event.orderbook.asks.len(),
+            "received orderbook update"
+        );
+        Ok(Some(update_event))
+    })
+    .await
+}
+
+/// Event of a `live_trades_<pair>` channel.
+#[derive(Deserialize)]
+struct TradeEvent<'a> {
+    #[serde(borrow)]
+    event: Text<'a>,
+    #[serde(borrow)]
+    data: TradeData<'a>,
+}
+
+/// `data` of a `TradeEvent`, a trade or an error.
+#[derive(Deserialize)]
+struct TradeData<'a> {
+    id: Option<u64>,
+    price_str: Option<Exact>,
+    amount_str: Option<Exact>,
+    /// 0 if the taker bought, 1 if it sold.
+    #[serde(rename = "type")]
+    side: Option<u8>,
+    microtimestamp: Option<Number>,
+    #[serde(borrow)]
+    message: Option<Text<'a>>,
+}
+
+/// Parse a message of a `live_trades_<pair>` channel into the trade it
+/// carries.
+///
+/// Returns `None` for events other than trades, e.g. the subscription
+/// confirmation. Fails with `Error::SubscriptionRejected` for error events.
+pub fn parse_trade(msg: &str) -> crate::Result<Option<Trade>> {
+    let exchange = Exchange::Bitstamp;
+    let protocol = |err| Error::protocol(exchange, msg, err);
+    let missing = |field| protocol(de::Error::missing_field(field));
+    let event: TradeEvent = serde_json::from_str(msg).map_err(protocol)?;
+    let data = event.data;
+    match &*event.event.0 {
+        "trade" => {}
+        "bts:error" => {
+            return Err(Error::SubscriptionRejected {
+                exchange,
+                reason: data
+                    .message
+                    .map_or_else(String::new, |text| text.0.into_owned()),
+            })
+        }
+        event => {
+            debug!(%event, "received event");
+            return Ok(None);
         }
     }
+    let aggressor = match data.side.ok_or_else(|| missing("type"))? {
+        0 => Aggressor::Buy,
+        1 => Aggressor::Sell,
+        side => {
+            let err = de::Error::invalid_value(de::Unexpected::Unsigned(side.into()), &"0 or 1");
+            return Err(protocol(err));
+        }
+    };
+    Ok(Some(Trade {
+        exchange,
+        id: data.id.ok_or_else(|| missing("id"))?,
+        price: data.price_str.ok_or_else(|| missing("price_str"))?.0,
+        size: data.amount_str.ok_or_else(|| missing("amount_str"))?.0,
+        aggressor,
+        traded_at: data
+            .microtimestamp
+            .ok_or_else(|| missing("microtimestamp"))?
+            .0,
+        received_at: trade::now_micros(),
+    }))
+}
+
+/// Run the Bitstamp trade client loop connected to `url`, normally `URL`,
+/// sending every trade of `pair`.
+///
+/// Trades aren't recorded. The websocket is closed and `Ok` returned once
+/// `shutdown` fires. Fails with `Error::SubscriptionRejected` if Bitstamp
+/// refuses the channel.
+#[instrument(name = "trades", skip(tx, shutdown), fields(venue = "Bitstamp"))]
+pub async fn run_trades(
+    url: &str,
+    pair: &str,
+    tx: Sender<Trade>,
+    shutdown: Shutdown,
+) -> crate::Result<()> {
+    let subscribe_msg = format!(
+        r#"{{"event":"bts:subscribe","data":{{"channel":"live_trades_{}"}}}}"#,
+        pair
+    );
+    let exchange = Exchange::Bitstamp;
+    websocket::read(
+        exchange,
+        url,
+        Some(subscribe_msg),
+        &tx,
+        shutdown,
+        parse_trade,
+    )
+    .await
 }
 
 #[cfg(test)]
 mod tests {
+    use std::time::Duration;
+
     use ntest::timeout;
-    use tokio::sync::mpsc;
+    use rust_decimal_macros::dec;
+    use tokio::sync::{mpsc, watch};
 
     use super::*;
+    use crate::mock_exchange::bitstamp::{error, live_trade, order_book, subscription_succeeded};
+    use crate::mock_exchange::{MockExchange, Script};
+    use crate::order_book::LevelSide;
 
     /// This test case asserts that the `run` function given the
     /// valid pair name will send a message through the channel
@@ -124,20 +384,176 @@ mod tests {
     #[tokio::test]
     #[timeout(5000)]
     async fn run_sends_updates_within_5s() {
+        let script = Script::new()
+            .expect_text()
+            .text(subscription_succeeded("ethbtc"))
+            .delay(Duration::from_millis(20))
+            .text(order_book(
+                "ethbtc",
+                1,
+                &[("0.061", "2.5")],
+                &[("0.062", "1.0")],
+            ));
+        let exchange = MockExchange::start(vec![script]).await.unwrap();
         let (tx, mut rx) = mpsc::channel(2);
 
+        let url = exchange.url();
         tokio::spawn(async move {
-            run("ethbtc", tx).await.unwrap();
+            let (_notify_shutdown, shutdown) = watch::channel(false);
+            run(
+                &url,
+                "ethbtc",
+                Scale::default(),
+                tx,
+                None,
+                Shutdown::new(shutdown),
+            )
+            .await
+            .unwrap();
         });
 
-        let recv = tokio::spawn(async move {
-            loop {
-                if let Some(_) = rx.recv().await {
-                    break;
-                }
+        let event = rx.recv().await.unwrap();
+        assert_eq!(event.exchange, Exchange::Bitstamp);
+        assert_eq!(event.orderbook.top_bid(), Some(dec!(0.061)));
+        assert_eq!(event.orderbook.top_ask(), Some(dec!(0.062)));
+        assert_eq!(
+            exchange.received(),
+            vec![r#"{"event":"bts:subscribe","data":{"channel":"order_book_ethbtc"}}"#]
+        );
+    }
+
+    #[tokio::test]
+    #[timeout(5000)]
+    async fn rejected_subscription_is_reported() {
+        let script = Script::new()
+            .expect_text()
+            .text(error("Bad subscription string."));
+        let exchange = MockExchange::start(vec![script]).await.unwrap();
+        let (tx, _rx) = mpsc::channel(2);
+        let (_notify_shutdown, shutdown) = watch::channel(false);
+
+        match run(
+            &exchange.url(),
+            "nopair",
+            Scale::default(),
+            tx,
+            None,
+            Shutdown::new(shutdown),
+        )
+        .await
+        {
+            Err(Error::SubscriptionRejected { reason, .. }) => {
+                assert_eq!(reason, "Bad subscription string.")
             }
+            res => panic!("unexpected result {:?}", res),
+        }
+    }
+
+    #[tokio::test]
+    #[timeout(5000)]
+    async fn close_frame_stops_connector() {
+        let script = Script::new().expect_text().close();
+        let exchange = MockExchange::start(vec![script]).await.unwrap();
+        let (tx, _rx) = mpsc::channel(2);
+        let (_notify_shutdown, shutdown) = watch::channel(false);
+
+        run(
+            &exchange.url(),
+            "ethbtc",
+            Scale::default(),
+            tx,
+            None,
+            Shutdown::new(shutdown),
+        )
+        .await
+        .unwrap();
+    }
+
+    #[test]
+    fn levels_are_tagged_with_bitstamp() {
+        let msg = order_book(
+            "ethbtc",
+            1,
+            &[("0.061", "2.5"), ("0.06", "1")],
+            &[("0.062", "1")],
+        );
+        let orderbook = Parser::new(Scale::default()).parse(&msg).unwrap().unwrap();
+        let mut levels = orderbook.bids.iter().chain(orderbook.asks.iter());
+        assert!(levels.all(|level| level.exchange == Exchange::Bitstamp));
+        let event = OrderbookUpdateEvent::new(Exchange::Bitstamp, orderbook);
+        assert!(event.validate().is_ok());
+    }
+
+    #[test]
+    fn events_are_parsed() {
+        let mut parser = Parser::new(Scale::default());
+        let msg = r#"{"event":"bts:error","channel":"","data":{"code":null,"message":"Bad subscription string."}}"#;
+        match parser.parse(msg) {
+            Err(Error::SubscriptionRejected { reason, .. }) => {
+                assert_eq!(reason, "Bad subscription string.")
+            }
+            res => panic!("unexpected result {:?}", res),
+        }
+
+        let msg = subscription_succeeded("ethbtc");
+        assert!(parser.parse(&msg).unwrap().is_none());
+
+        let msg = order_book("ethbtc", 7, &[("0.061", "2.5")], &[("0.062", "1.0")]);
+        let orderbook = parser.parse(&msg).unwrap().unwrap();
+        assert_eq!(orderbook.top_bid(), Some(dec!(0.061)));
+        assert_eq!(orderbook.top_ask(), Some(dec!(0.062)));
+        assert_eq!(orderbook.bids[0].side, LevelSide::Bid);
+        assert_eq!(orderbook.asks[0].side, LevelSide::Ask);
+
+        // Data with malformed levels is neither an update nor ignored.
+        let msg = r#"{"event":"data","channel":"order_book_ethbtc","data":{"timestamp":"1","microtimestamp":"8","bids":[["x","1"]],"asks":[]}}"#;
+        assert!(matches!(parser.parse(msg), Err(Error::Protocol { .. })));
+    }
+
+    #[test]
+    fn trades_are_normalized() {
+        let msg = live_trade("ethbtc", 11, "0.0610", "2.5", 1_600_000_000_123_456, 1);
+        let trade = parse_trade(&msg).unwrap().unwrap();
+        assert_eq!(trade.exchange, Exchange::Bitstamp);
+        assert_eq!(trade.id, 11);
+        assert_eq!((trade.price, trade.size), (dec!(0.0610), dec!(2.5)));
+        assert_eq!(trade.aggressor, Aggressor::Sell);
+        assert_eq!(trade.traded_at, 1_600_000_000_123_456);
+
+        assert!(parse_trade(&subscription_succeeded("ethbtc"))
+            .unwrap()
+            .is_none());
+        assert!(matches!(
+            parse_trade(&error("Bad subscription string.")),
+            Err(Error::SubscriptionRejected { .. })
+        ));
+        let msg = r#"{"event":"trade","channel":"live_trades_ethbtc","data":{"id":1}}"#;
+        assert!(matches!(parse_trade(msg), Err(Error::Protocol { .. })));
+    }
+
+    #[tokio::test]
+    #[timeout(5000)]
+    async fn run_trades_subscribes_to_live_trades() {
+        let script = Script::new()
+            .expect_text()
+            .text(live_trade("ethbtc", 1, "0.062", "0.5", 1, 0));
+        let exchange = MockExchange::start(vec![script]).await.unwrap();
+        let (tx, mut rx) = mpsc::channel(2);
+
+        let url = exchange.url();
+        tokio::spawn(async move {
+            let (_notify_shutdown, shutdown) = watch::channel(false);
+            run_trades(&url, "ethbtc", tx, Shutdown::new(shutdown)).await
         });
 
-        recv.await.unwrap();
+        let trade = rx.recv().await.unwrap();
+        assert_eq!(
+            (trade.price, trade.aggressor),
+            (dec!(0.062), Aggressor::Buy)
+        );
+        assert_eq!(
+            exchange.received(),
+            vec![r#"{"event":"bts:subscribe","data":{"channel":"live_trades_ethbtc"}}"#]
+        );
     }
 }
@@ -0,0 +1,496 @@
+//! # candle
+//!
+//! OHLCV candles built from the trades of each venue, from the trades of
+//! all venues together and from the mid price of the aggregated book. The
+//! candles of every series are kept in memory so new subscribers can be
+//! backfilled.
+use std::collections::{HashMap, VecDeque};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use rust_decimal::prelude::*;
+use strum_macros::{Display, EnumString, IntoStaticStr};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::watch;
+use tokio::time;
+use tracing::warn;
+
+use crate::aggregator::Snapshot;
+use crate::error::Error;
+use crate::order_book::Exchange;
+use crate::trade::{self, Trade};
+
+/// Length of a candle.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Display, EnumString, IntoStaticStr)]
+pub enum Interval {
+    #[strum(serialize = "1s")]
+    OneSecond,
+    #[strum(serialize = "1m")]
+    OneMinute,
+    #[strum(serialize = "5m")]
+    FiveMinutes,
+}
+
+impl Interval {
+    /// Length in microseconds.
+    pub fn micros(self) -> u64 {
+        match self {
+            Interval::OneSecond => 1_000_000,
+            Interval::OneMinute => 60_000_000,
+            Interval::FiveMinutes => 300_000_000,
+        }
+    }
+}
+
+/// What the prices of a candle come from.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Source {
+    /// Trades of one venue.
+    Venue(Exchange),
+    /// Trades of every venue.
+    Consolidated,
+    /// Mid price of the aggregated book; the volume is always zero.
+    Mid,
+}
+
+/// Open, high, low and close price and volume of one interval.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Candle {
+    pub source: Source,
+    pub interval: Interval,
+    /// Start of the interval, in microseconds since the epoch.
+    pub open_time: u64,
+    pub open: Decimal,
+    pub high: Decimal,
+    pub low: Decimal,
+    pub close: Decimal,
+    /// Base quantity traded.
+    pub volume: Decimal,
+    /// Trades, or mid price changes, the candle was built from.
+    pub count: u64,
+    /// Whether the interval is over; the candle won't change anymore.
+    pub closed: bool,
+}
+
+impl Candle {
+    fn new(source: Source, interval: Interval, open_time: u64, price: Decimal) -> Self {
+        Self {
+            source,
+            interval,
+            open_time,
+            open: price,
+            high: price,
+            low: price,
+            close: price,
+            volume: Decimal::zero(),
+            count: 0,
+            closed: false,
+        }
+    }
+
+    fn add(&mut self, price: Decimal, volume: Decimal) {
+        self.high = self.high.max(price);
+        self.low = self.low.min(price);
+        self.close = price;
+        self.volume += volume;
+        self.count += 1;
+    }
+}
+
+/// Intervals candles are built at and how many are kept.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Config {
+    pub intervals: Vec<Interval>,
+    /// Closed candles kept per source and interval.
+    pub history: usize,
+}
+
+impl Config {
+    /// Read the configuration from the `CANDLE_INTERVALS` (comma-separated,
+    /// default `1s,1m,5m`) and `CANDLE_HISTORY` (default 1000) environment
+    /// variables.
+    pub fn from_env() -> crate::Result<Self> {
+        let mut config = Self::default();
+        if let Ok(intervals) = std::env::var("CANDLE_INTERVALS") {
+            config.intervals = intervals
+                .split(',')
+                .filter(|interval| !interval.trim().is_empty())
+                .map(|interval| {
+                    Interval::from_str(interval.trim())
+                        .map_err(|err| Error::config("CANDLE_INTERVALS", err))
+                })
+                .collect::<crate::Result<_>>()?;
+        }
+        if let Ok(history) = std::env::var("CANDLE_HISTORY") {
+            config.history = history
+                .trim()
+                .parse()
+                .map_err(|err| Error::config("CANDLE_HISTORY", err))?;
+        }
+        Ok(config)
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            intervals: vec![
+                Interval::OneSecond,
+                Interval::OneMinute,
+                Interval::FiveMinutes,
+            ],
+            history: 1000,
+        }
+    }
+}
+
+/// Candles of one source at one interval.
+#[derive(Debug, Default)]
+struct Series {
+    /// Oldest first.
+    closed: VecDeque<Candle>,
+    current: Option<Candle>,
+    /// Start of the earliest interval still open, in microseconds since
+    /// the epoch; earlier ones were closed by `Candles::close`.
+    open_from: u64,
+}
+
+impl Series {
+    /// Close the current candle, if any, keeping the latest `history`
+    /// closed ones, and publish it on `tx`.
+    fn close(&mut self, history: usize, tx: &broadcast::Sender<Candle>) {
+        if let Some(mut candle) = self.current.take() {
+            candle.closed = true;
+            let _ = tx.send(candle.clone());
+            self.closed.push_back(candle);
+            if self.closed.len() > history {
+                self.closed.pop_front();
+            }
+        }
+    }
+}
+
+/// Candles of every series built so far.
+#[derive(Debug)]
+struct Book {
+    config: Config,
+    series: HashMap<(Source, Interval), Series>,
+}
+
+/// Shared handle to the candles, updated by `run` and read by the
+/// subscribers.
+#[derive(Debug, Clone)]
+pub struct Candles {
+    book: Arc<Mutex<Book>>,
+    tx: broadcast::Sender<Candle>,
+}
+
+impl Candles {
+    pub fn new(config: Config) -> Self {
+        let (tx, _) = broadcast::channel(1024);
+        let book = Book {
+            config,
+            series: HashMap::new(),
+        };
+        Self {
+            book: Arc::new(Mutex::new(book)),
+            tx,
+        }
+    }
+
+    /// Whether candles are built at `interval`.
+    pub fn builds(&self, interval: Interval) -> bool {
+        self.book
+            .lock()
+            .unwrap()
+            .config
+            .intervals
+            .contains(&interval)
+    }
+
+    /// Add `trade` to the candles of its venue and to the consolidated
+    /// ones.
+    pub fn add_trade(&self, trade: &Trade) {
+        let sources = [Source::Venue(trade.exchange), Source::Consolidated];
+        self.add(&sources, trade.price, trade.size, trade.traded_at);
+    }
+
+    /// Add the `mid` price of the aggregated book at `time`, in
+    /// microseconds since the epoch, to the mid candles.
+    pub fn add_mid(&self, mid: Decimal, time: u64) {
+        self.add(&[Source::Mid], mid, Decimal::zero(), time);
+    }
+
+    /// Add `price` and `volume` at `time` to the candles of `sources` at
+    /// every interval and publish the candles which changed.
+    ///
+    /// A value older than the current candle, e.g. a trade a venue
+    /// reported late, counts towards the current candle, or towards the
+    /// next one if its candle was already closed.
+    fn add(&self, sources: &[Source], price: Decimal, volume: Decimal, time: u64) {
+        let mut book = self.book.lock().unwrap();
+        let Book { config, series } = &mut *book;
+        for &source in sources {
+            for &interval in &config.intervals {
+                let series = series.entry((source, interval)).or_default();
+                let open_time = (time - time % interval.micros()).max(series.open_from);
+                if matches!(&series.current, Some(current) if current.open_time < open_time) {
+                    series.close(config.history, &self.tx);
+                }
+                let current = series
+                    .current
+                    .get_or_insert_with(|| Candle::new(source, interval, open_time, price));
+                current.add(price, volume);
+                let _ = self.tx.send(current.clone());
+            }
+        }
+    }
+
+    /// Close and publish the current candles whose interval is over at
+    /// `now`, in microseconds since the epoch.
+    ///
+    /// An interval without any value gets no candle, so a series skips
+    /// the intervals nothing traded in.
+    pub fn close(&self, now: u64) {
+        let mut book = self.book.lock().unwrap();
+        let Book { config, series } = &mut *book;
+        for (&(_, interval), series) in series.iter_mut() {
+            let open_from = now - now % interval.micros();
+            if matches!(&series.current, Some(current) if current.open_time < open_from) {
+                series.close(config.history, &self.tx);
+            }
+            series.open_from = series.open_from.max(open_from);
+        }
+    }
+
+    /// Shortest interval candles are built at, in microseconds.
+    fn shortest_interval(&self) -> Option<u64> {
+        let book = self.book.lock().unwrap();
+        book.config
+            .intervals
+            .iter()
+            .map(|interval| interval.micros())
+            .min()
+    }
+
+    /// Up to `history` of the latest closed candles of `source` at
+    /// `interval`, every kept one if `None`, oldest first and followed by
+    /// the current candle, and a receiver of every candle published from
+    /// then on.
+    pub fn subscribe(
+        &self,
+        source: Source,
+        interval: Interval,
+        history: Option<usize>,
+    ) -> (Vec<Candle>, broadcast::Receiver<Candle>) {
+        // Subscribing under the lock, so no update is missed or repeated.
+        let book = self.book.lock().unwrap();
+        let mut candles = Vec::new();
+        if let Some(series) = book.series.get(&(source, interval)) {
+            let skip = history.map_or(0, |history| series.closed.len().saturating_sub(history));
+            candles.extend(series.closed.iter().skip(skip).cloned());
+            candles.extend(series.current.iter().cloned());
+        }
+        (candles, self.tx.subscribe())
+    }
+}
+
+/// Build candles from the trades received on `trade_rx` and from the mid
+/// price of the snapshots published on `snapshot_rx`, until the
+/// aggregator is gone.
+///
+/// Candles are closed once their interval is over, whether or not a later
+/// value arrived, see `Candles::close`.
+pub async fn run(
+    candles: Candles,
+    mut trade_rx: broadcast::Receiver<Trade>,
+    mut snapshot_rx: watch::Receiver<Snapshot>,
+) {
+    // Every interval is a multiple of the shortest one, so its ends are
+    // the only times a candle closes.
+    let tick = candles.shortest_interval();
+    let mut trades_open = true;
+    loop {
+        let until_close = tick.map_or(Duration::MAX, |tick| {
+            Duration::from_micros(tick - trade::now_micros() % tick)
+        });
+        tokio::select! {
+            res = trade_rx.recv(), if trades_open => match res {
+                Ok(trade) => candles.add_trade(&trade),
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!(skipped, "candles lagging; skipped trades");
+                }
+                Err(RecvError::Closed) => trades_open = false,
+            },
+            res = snapshot_rx.changed() => {
+                if res.is_err() {
+                    break;
+                }
+                let (mid, published_at) = {
+                    let snapshot = snapshot_rx.borrow();
+                    (snapshot.orderbook.mid(), snapshot.published_at)
+                };
+                if let Some(mid) = mid {
+                    candles.add_mid(mid, published_at);
+                }
+            }
+            _ = time::sleep(until_close), if tick.is_some() => {
+                candles.close(trade::now_micros());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ntest::timeout;
+    use rust_decimal_macros::dec;
+
+    use super::*;
+    use crate::trade::Aggressor;
+
+    fn candles(history: usize) -> Candles {
+        Candles::new(Config {
+            intervals: vec![Interval::OneSecond, Interval::OneMinute],
+            history,
+        })
+    }
+
+    fn trade(exchange: Exchange, price: Decimal, size: Decimal, traded_at: u64) -> Trade {
+        Trade {
+            exchange,
+            id: 1,
+            price,
+            size,
+            aggressor: Aggressor::Buy,
+            traded_at,
+            received_at: traded_at,
+        }
+    }
+
+    #[test]
+    fn trades_build_venue_and_consolidated_candles() {
+        let candles = candles(10);
+        candles.add_trade(&trade(Exchange::Binance, dec!(10), dec!(1), 1_000_000));
+        candles.add_trade(&trade(Exchange::Bitstamp, dec!(12), dec!(2), 1_200_000));
+        candles.add_trade(&trade(Exchange::Binance, dec!(9), dec!(1), 1_900_000));
+        candles.add_trade(&trade(Exchange::Binance, dec!(11), dec!(3), 2_100_000));
+
+        let (history, _) = candles.subscribe(Source::Consolidated, Interval::OneSecond, None);
+        assert_eq!(history.len(), 2);
+        let first = &history[0];
+        assert!(first.closed);
+        assert_eq!(first.open_time, 1_000_000);
+        assert_eq!(
+            (first.open, first.high, first.low, first.close),
+            (dec!(10), dec!(12), dec!(9), dec!(9))
+        );
+        assert_eq!((first.volume, first.count), (dec!(4), 3));
+        assert!(!history[1].closed);
+        assert_eq!(history[1].open, dec!(11));
+
+        let (history, _) =
+            candles.subscribe(Source::Venue(Exchange::Bitstamp), Interval::OneMinute, None);
+        assert_eq!(history.len(), 1);
+        assert_eq!((history[0].open_time, history[0].volume), (0, dec!(2)));
+    }
+
+    #[test]
+    fn history_is_bounded() {
+        let candles = candles(2);
+        for second in 0..5 {
+            candles.add_mid(Decimal::from(second), second * 1_000_000);
+        }
+        let (history, _) = candles.subscribe(Source::Mid, Interval::OneSecond, None);
+        let opens: Vec<Decimal> = history.iter().map(|candle| candle.open).collect();
+        assert_eq!(opens, vec![dec!(2), dec!(3), dec!(4)]);
+
+        let (history, _) = candles.subscribe(Source::Mid, Interval::OneSecond, Some(1));
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].volume, dec!(0));
+    }
+
+    #[tokio::test]
+    async fn updates_are_published() {
+        let candles = candles(10);
+        let (_, mut rx) = candles.subscribe(Source::Mid, Interval::OneSecond, None);
+        candles.add_mid(dec!(1), 500_000);
+        candles.add_mid(dec!(2), 1_500_000);
+
+        let mid_1s = |candle: &Candle| candle.interval == Interval::OneSecond;
+        let mut updates = Vec::new();
+        while let Ok(candle) = rx.try_recv() {
+            if mid_1s(&candle) {
+                updates.push((candle.open_time, candle.close, candle.closed));
+            }
+        }
+        assert_eq!(
+            updates,
+            vec![
+                (0, dec!(1), false),
+                (0, dec!(1), true),
+                (1_000_000, dec!(2), false)
+            ]
+        );
+    }
+
+    #[test]
+    fn candles_close_when_their_interval_is_over() {
+        let candles = candles(10);
+        candles.add_trade(&trade(Exchange::Binance, dec!(10), dec!(1), 1_200_000));
+        candles.close(1_900_000);
+        let (history, _) = candles.subscribe(Source::Consolidated, Interval::OneSecond, None);
+        assert!(!history[0].closed);
+
+        candles.close(2_000_000);
+        let (history, _) = candles.subscribe(Source::Consolidated, Interval::OneSecond, None);
+        assert_eq!(history.len(), 1);
+        assert!(history[0].closed);
+
+        // A late trade counts towards the next candle, and nothing traded
+        // in the seconds after it.
+        candles.add_trade(&trade(Exchange::Binance, dec!(11), dec!(1), 1_500_000));
+        candles.close(5_000_000);
+        candles.add_trade(&trade(Exchange::Binance, dec!(12), dec!(1), 5_500_000));
+        let (history, _) = candles.subscribe(Source::Consolidated, Interval::OneSecond, None);
+        let opens: Vec<(u64, Decimal, bool)> = history
+            .iter()
+            .map(|candle| (candle.open_time, candle.open, candle.closed))
+            .collect();
+        assert_eq!(
+            opens,
+            vec![
+                (1_000_000, dec!(10), true),
+                (2_000_000, dec!(11), true),
+                (5_000_000, dec!(12), false)
+            ]
+        );
+
+        let (history, _) = candles.subscribe(Source::Consolidated, Interval::OneMinute, None);
+        assert_eq!(history.len(), 1);
+        assert_eq!((history[0].count, history[0].closed), (3, false));
+    }
+
+    #[tokio::test]
+    #[timeout(5000)]
+    async fn run_closes_candles_without_later_trades() {
+        let candles = candles(10);
+        let (trade_tx, trade_rx) = broadcast::channel(2);
+        let (_snapshot_tx, snapshot_rx) = watch::channel(Snapshot::default());
+        let (_, mut rx) = candles.subscribe(Source::Consolidated, Interval::OneSecond, None);
+        tokio::spawn(run(candles, trade_rx, snapshot_rx));
+
+        let now = trade::now_micros();
+        trade_tx
+            .send(trade(Exchange::Bitstamp, dec!(10), dec!(1), now))
+            .unwrap();
+        loop {
+            let candle = rx.recv().await.unwrap();
+            if candle.interval == Interval::OneSecond && candle.closed {
+                assert_eq!(candle.open_time, now - now % 1_000_000);
+                break;
+            }
+        }
+    }
+}
@@ -0,0 +1,71 @@
+//! # config
+//!
+//! Parsing of configuration shared by several modules.
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::str::FromStr;
+
+use rust_decimal::Decimal;
+
+use crate::error::Error;
+use crate::order_book::Exchange;
+
+/// Split `value` of the environment variable `name`, a comma-separated
+/// list of `key=value` pairs, into its trimmed pairs.
+pub(crate) fn key_values<'a>(name: &str, value: &'a str) -> crate::Result<Vec<(&'a str, &'a str)>> {
+    value
+        .split(',')
+        .filter(|pair| !pair.trim().is_empty())
+        .map(|pair| match pair.split_once('=') {
+            Some((key, value)) => Ok((key.trim(), value.trim())),
+            None => Err(Error::config(
+                name,
+                format!("expected `key=value`, got `{}`", pair),
+            )),
+        })
+        .collect()
+}
+
+/// Parse `value` of the environment variable `name`, a comma-separated
+/// list of `Venue=T` pairs.
+pub(crate) fn venue_values<T>(name: &str, value: &str) -> crate::Result<HashMap<Exchange, T>>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    key_values(name, value)?
+        .into_iter()
+        .map(|(venue, value)| {
+            let venue = Exchange::from_str(venue).map_err(|err| Error::config(name, err))?;
+            let value = T::from_str(value).map_err(|err| Error::config(name, err))?;
+            Ok((venue, value))
+        })
+        .collect()
+}
+
+/// Parse `value` of the environment variable `name`, a comma-separated
+/// list of `Venue=decimal` pairs.
+pub(crate) fn venue_decimals(name: &str, value: &str) -> crate::Result<HashMap<Exchange, Decimal>> {
+    venue_values(name, value)
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+
+    #[test]
+    fn venue_decimals_are_parsed() {
+        let sizes = venue_decimals("X", "Binance=0.001, Bitstamp=0.01").unwrap();
+        assert_eq!(sizes[&Exchange::Binance], dec!(0.001));
+        assert_eq!(sizes[&Exchange::Bitstamp], dec!(0.01));
+        assert!(venue_decimals("X", "Kraken=1").is_err());
+        assert!(venue_decimals("X", "Binance").is_err());
+        assert!(venue_decimals("X", "Binance=x").is_err());
+
+        let pairs: HashMap<Exchange, String> = venue_values("X", "Binance=btcusdt").unwrap();
+        assert_eq!(pairs[&Exchange::Binance], "btcusdt");
+        assert_eq!(key_values("X", " usdt = 1, ").unwrap(), vec![("usdt", "1")]);
+    }
+}
@@ -0,0 +1,182 @@
+//! # error
+//!
+//! Error type shared by the connectors, the aggregator and the server.
+use std::fmt::Display;
+
+use tonic::{Code, Status};
+
+use crate::order_book::Exchange;
+
+/// Boxed error of an underlying library, e.g. `websocket_lite`.
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Error returned by most functions.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// The websocket connection to an exchange failed or was dropped.
+    #[error("{exchange} connection failed: {source}")]
+    Connection {
+        exchange: Exchange,
+        #[source]
+        source: BoxError,
+    },
+
+    /// A message from an exchange didn't match the expected schema.
+    #[error("{exchange} sent unexpected message: {source}")]
+    Protocol {
+        exchange: Exchange,
+        /// The raw message as received.
+        payload: String,
+        #[source]
+        source: serde_json::Error,
+    },
+
+    /// The exchange refused the channel subscription, e.g. for an
+    /// unknown pair.
+    #[error("{exchange} rejected subscription: {reason}")]
+    SubscriptionRejected { exchange: Exchange, reason: String },
+
+    /// An update arrived out of sequence.
+    #[error("{exchange} sequence gap: received {received} after {last}")]
+    SequenceGap {
+        exchange: Exchange,
+        last: u64,
+        received: u64,
+    },
+
+    /// An update contained a level tagged with another exchange.
+    #[error("{exchange} update contains a level of {found}")]
+    Provenance { exchange: Exchange, found: Exchange },
+
+    /// The receiving side of a channel is gone.
+    #[error("downstream channel closed")]
+    DownstreamClosed,
+
+    /// Every exchange connector stopped, so there is nothing to aggregate.
+    #[error("all exchange connectors stopped")]
+    ConnectorsStopped,
+
+    /// Invalid configuration, e.g. a malformed environment variable.
+    #[error("invalid configuration: {0}")]
+    Config(String),
+
+    /// A recording of exchange messages couldn't be decoded.
+    #[error("invalid recording: {0}")]
+    Recording(String),
+
+    /// The history database failed.
+    #[error("history database failed: {0}")]
+    History(#[from] rusqlite::Error),
+
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    #[error(transparent)]
+    Http(#[from] hyper::Error),
+
+    #[error(transparent)]
+    Transport(#[from] tonic::transport::Error),
+
+    #[error(transparent)]
+    Task(#[from] tokio::task::JoinError),
+}
+
+impl Error {
+    /// Create `Error::Connection` for `exchange`.
+    pub fn connection(exchange: Exchange, source: impl Into<BoxError>) -> Self {
+        Error::Connection {
+            exchange,
+            source: source.into(),
+        }
+    }
+
+    /// Create `Error::Protocol` for `exchange` keeping the raw `payload`.
+    pub fn protocol(exchange: Exchange, payload: &str, source: serde_json::Error) -> Self {
+        Error::Protocol {
+            exchange,
+            payload: payload.to_owned(),
+            source,
+        }
+    }
+
+    /// Create `Error::Config` describing why `name` is invalid.
+    pub fn config(name: &str, reason: impl Display) -> Self {
+        Error::Config(format!("{}: {}", name, reason))
+    }
+}
+
+impl<T> From<tokio::sync::mpsc::error::SendError<T>> for Error {
+    fn from(_: tokio::sync::mpsc::error::SendError<T>) -> Self {
+        Error::DownstreamClosed
+    }
+}
+
+impl<T> From<tokio::sync::watch::error::SendError<T>> for Error {
+    fn from(_: tokio::sync::watch::error::SendError<T>) -> Self {
+        Error::DownstreamClosed
+    }
+}
+
+impl From<Error> for Status {
+    fn from(err: Error) -> Self {
+        let code = match err {
+            Error::Connection { .. } | Error::ConnectorsStopped => Code::Unavailable,
+            Error::SubscriptionRejected { .. } => Code::FailedPrecondition,
+            Error::SequenceGap { .. } => Code::DataLoss,
+            Error::DownstreamClosed => Code::Cancelled,
+            Error::Protocol { .. }
+            | Error::Provenance { .. }
+            | Error::Config(_)
+            | Error::Recording(_)
+            | Error::History(_)
+            | Error::Io(_)
+            | Error::Http(_)
+            | Error::Transport(_)
+            | Error::Task(_) => Code::Internal,
+        };
+        Status::new(code, err.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn protocol_error_keeps_payload() {
+        let payload = r#"{"bids": 1}"#;
+        let source = serde_json::from_str::<Vec<u8>>(payload).unwrap_err();
+        match Error::protocol(Exchange::Binance, payload, source) {
+            Error::Protocol {
+                exchange, payload, ..
+            } => {
+                assert_eq!(exchange, Exchange::Binance);
+                assert_eq!(payload, r#"{"bids": 1}"#);
+            }
+            err => panic!("unexpected error {:?}", err),
+        }
+    }
+
+    #[test]
+    fn errors_map_to_status_codes() {
+        let status = Status::from(Error::connection(Exchange::Bitstamp, "reset"));
+        assert_eq!(status.code(), Code::Unavailable);
+        assert_eq!(status.message(), "Bitstamp connection failed: reset");
+
+        let status = Status::from(Error::SubscriptionRejected {
+            exchange: Exchange::Bitstamp,
+            reason: "Bad subscription string.".to_owned(),
+        });
+        assert_eq!(status.code(), Code::FailedPrecondition);
+
+        let status = Status::from(Error::SequenceGap {
+            exchange: Exchange::Binance,
+            last: 2,
+            received: 1,
+        });
+        assert_eq!(status.code(), Code::DataLoss);
+
+        let status = Status::from(Error::DownstreamClosed);
+        assert_eq!(status.code(), Code::Cancelled);
+    }
+}
@@ -0,0 +1,180 @@
+//! # fees
+//!
+//! Taker fees of the venues and the fee-adjusted view of the aggregated
+//! orderbook. A bid is worth its price less the fee to the seller taking
+//! it, an ask costs its price plus the fee to the buyer, so the best raw
+//! price isn't necessarily the best executable one.
+use std::collections::HashMap;
+
+use rust_decimal::prelude::*;
+
+use crate::config::venue_decimals;
+use crate::order_book::{BookState, Exchange, LevelSide, Orderbook, OrderbookLevel};
+use crate::scale::Scale;
+
+/// Taker fee of each venue, in basis points.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TakerFees(pub HashMap<Exchange, Decimal>);
+
+impl TakerFees {
+    /// Read the fees from the `TAKER_FEES_BPS` environment variable, a
+    /// comma-separated list of `Venue=bps` pairs, e.g.
+    /// `Binance=10,Bitstamp=40`. Venues not listed charge no fee.
+    pub fn from_env() -> crate::Result<Self> {
+        match std::env::var("TAKER_FEES_BPS") {
+            Ok(fees) => venue_decimals("TAKER_FEES_BPS", &fees).map(Self),
+            Err(_) => Ok(Self::default()),
+        }
+    }
+
+    /// Taker fee of `exchange` in basis points, zero if it has none.
+    pub fn get(&self, exchange: Exchange) -> Decimal {
+        self.0.get(&exchange).copied().unwrap_or_else(Decimal::zero)
+    }
+
+    /// Price of `level`, quoted in ticks of `scale`, once the taker fee is
+    /// paid.
+    pub fn effective_price(&self, level: &OrderbookLevel, scale: Scale) -> Decimal {
+        let fee = self.get(level.exchange) / Decimal::from(10_000);
+        let price = scale.price(level.price);
+        match level.side {
+            LevelSide::Bid => price * (Decimal::one() - fee),
+            LevelSide::Ask => price * (Decimal::one() + fee),
+        }
+    }
+
+    /// The best `limit` levels of each side of `orderbook` ranked by their
+    /// fee-adjusted prices.
+    pub fn adjust(&self, orderbook: &Orderbook, limit: usize) -> AdjustedOrderbook {
+        let adjust = |levels: &[OrderbookLevel], side: LevelSide| {
+            let mut levels: Vec<AdjustedLevel> = levels
+                .iter()
+                .map(|level| AdjustedLevel {
+                    price: self.effective_price(level, orderbook.scale),
+                    level: *level,
+                })
+                .collect();
+            // Levels at the same adjusted price keep their raw order.
+            levels.sort_by(|a, b| match side {
+                LevelSide::Bid => b.price.cmp(&a.price).then(b.level.cmp(&a.level)),
+                LevelSide::Ask => a.price.cmp(&b.price).then(a.level.cmp(&b.level)),
+            });
+            levels.truncate(limit);
+            levels
+        };
+        AdjustedOrderbook {
+            bids: adjust(&orderbook.bids, LevelSide::Bid),
+            asks: adjust(&orderbook.asks, LevelSide::Ask),
+            scale: orderbook.scale,
+            state: orderbook.state(),
+        }
+    }
+}
+
+/// Level of an `AdjustedOrderbook`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AdjustedLevel {
+    /// Price once the taker fee is paid.
+    pub price: Decimal,
+    /// The level as quoted by its venue.
+    pub level: OrderbookLevel,
+}
+
+/// Orderbook ranked by fee-adjusted prices, best first.
+#[derive(Debug, Clone, Default)]
+pub struct AdjustedOrderbook {
+    pub bids: Vec<AdjustedLevel>,
+    pub asks: Vec<AdjustedLevel>,
+    /// Scale of the raw levels.
+    pub scale: Scale,
+    /// State of the raw orderbook.
+    pub state: BookState,
+}
+
+impl AdjustedOrderbook {
+    /// Difference of the best fee-adjusted ask and bid.
+    pub fn spread(&self) -> Option<Decimal> {
+        match (self.bids.first(), self.asks.first()) {
+            (Some(bid), Some(ask)) => Some(ask.price - bid.price),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+
+    const SCALE: Scale = Scale::new(2, 2);
+
+    fn fees() -> TakerFees {
+        TakerFees(
+            [
+                (Exchange::Binance, dec!(10)),
+                (Exchange::Bitstamp, dec!(50)),
+            ]
+            .iter()
+            .cloned()
+            .collect(),
+        )
+    }
+
+    #[test]
+    fn fees_reorder_levels() {
+        let orderbook = Orderbook::from_bids_asks(
+            SCALE,
+            vec![
+                OrderbookLevel::bid(10_010, 100, Exchange::Bitstamp),
+                OrderbookLevel::bid(10_000, 100, Exchange::Binance),
+            ],
+            vec![
+                OrderbookLevel::ask(10_090, 100, Exchange::Bitstamp),
+                OrderbookLevel::ask(10_100, 100, Exchange::Binance),
+            ],
+        );
+        let adjusted = fees().adjust(&orderbook, 10);
+
+        // Bitstamp quotes the better raw prices, Binance the better
+        // executable ones.
+        let bids: Vec<(Exchange, Decimal)> = adjusted
+            .bids
+            .iter()
+            .map(|bid| (bid.level.exchange, bid.price))
+            .collect();
+        assert_eq!(
+            bids,
+            vec![
+                (Exchange::Binance, dec!(99.9)),
+                (Exchange::Bitstamp, dec!(99.5995)),
+            ]
+        );
+        let asks: Vec<(Exchange, Decimal)> = adjusted
+            .asks
+            .iter()
+            .map(|ask| (ask.level.exchange, ask.price))
+            .collect();
+        assert_eq!(
+            asks,
+            vec![
+                (Exchange::Binance, dec!(101.101)),
+                (Exchange::Bitstamp, dec!(101.4045)),
+            ]
+        );
+        assert_eq!(adjusted.spread(), Some(dec!(1.201)));
+        assert_eq!(adjusted.bids[1].level.price, 10_010);
+    }
+
+    #[test]
+    fn adjust_keeps_limit_levels() {
+        let bids = (0..5)
+            .map(|i| OrderbookLevel::bid(100 - i, 1, Exchange::Binance))
+            .collect();
+        let orderbook = Orderbook::from_bids_asks(SCALE, bids, vec![]);
+        let adjusted = TakerFees::default().adjust(&orderbook, 3);
+        assert_eq!(adjusted.bids.len(), 3);
+        assert_eq!(adjusted.bids[0].price, dec!(1));
+        assert!(adjusted.asks.is_empty());
+    }
+}
@@ -0,0 +1,599 @@
+//! # history
+//!
+//! Historical top of book of the aggregated orderbook, kept in an SQLite
+//! database. Every change of the best bid or ask is stored as a `Quote`,
+//! and optionally the top levels of the book as a `BookSnapshot` at most
+//! once per interval. Rows older than their retention are pruned.
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use rusqlite::types::Type;
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use rust_decimal::Decimal;
+use tokio::sync::watch;
+use tokio::time::{self, Instant};
+use tracing::{debug, error};
+
+use crate::aggregator::Snapshot;
+use crate::error::Error;
+use crate::order_book::{Exchange, Orderbook, OrderbookLevel};
+use crate::trade;
+
+/// How often rows past their retention are deleted.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+const SCHEMA: &str = "
+    PRAGMA journal_mode = WAL;
+    PRAGMA synchronous = NORMAL;
+    CREATE TABLE IF NOT EXISTS quotes (
+        time INTEGER NOT NULL,
+        bid_exchange TEXT,
+        bid_price TEXT,
+        bid_size TEXT,
+        ask_exchange TEXT,
+        ask_price TEXT,
+        ask_size TEXT
+    );
+    CREATE INDEX IF NOT EXISTS quotes_time ON quotes (time);
+    CREATE TABLE IF NOT EXISTS book_levels (
+        time INTEGER NOT NULL,
+        side INTEGER NOT NULL,
+        rank INTEGER NOT NULL,
+        exchange TEXT NOT NULL,
+        price TEXT NOT NULL,
+        size TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS book_levels_time ON book_levels (time);
+";
+
+/// Where the history is stored, what is stored and for how long.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Config {
+    /// Path of the SQLite database, created if missing.
+    pub path: String,
+    /// Levels of each side stored in book snapshots, none if zero.
+    pub levels: usize,
+    /// Shortest time between two book snapshots.
+    pub book_interval: Duration,
+    /// How long quotes are kept.
+    pub quote_retention: Duration,
+    /// How long book snapshots are kept.
+    pub book_retention: Duration,
+}
+
+impl Config {
+    /// Read the configuration from the `HISTORY_PATH`, `HISTORY_LEVELS`
+    /// (default 0), `HISTORY_BOOK_INTERVAL_MS` (default 1000),
+    /// `HISTORY_RETENTION_SECS` (default a day) and
+    /// `HISTORY_BOOK_RETENTION_SECS` (default an hour) environment
+    /// variables, `None` without `HISTORY_PATH`.
+    pub fn from_env() -> crate::Result<Option<Self>> {
+        let path = match std::env::var("HISTORY_PATH") {
+            Ok(path) => path,
+            Err(_) => return Ok(None),
+        };
+        fn var<T: FromStr>(name: &str, default: T) -> crate::Result<T>
+        where
+            T::Err: Display,
+        {
+            match std::env::var(name) {
+                Ok(value) => value.trim().parse().map_err(|err| Error::config(name, err)),
+                Err(_) => Ok(default),
+            }
+        }
+        Ok(Some(Self {
+            path,
+            levels: var("HISTORY_LEVELS", 0)?,
+            book_interval: Duration::from_millis(var("HISTORY_BOOK_INTERVAL_MS", 1_000)?),
+            quote_retention: Duration::from_secs(var("HISTORY_RETENTION_SECS", 86_400)?),
+            book_retention: Duration::from_secs(var("HISTORY_BOOK_RETENTION_SECS", 3_600)?),
+        }))
+    }
+}
+
+/// Level of a stored book.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoredLevel {
+    pub exchange: Exchange,
+    pub price: Decimal,
+    pub size: Decimal,
+}
+
+impl StoredLevel {
+    fn new(level: &OrderbookLevel, orderbook: &Orderbook) -> Self {
+        Self {
+            exchange: level.exchange,
+            price: orderbook.scale.price(level.price),
+            size: orderbook.scale.size(level.size),
+        }
+    }
+}
+
+/// Best bid and ask of the aggregated orderbook from `time` on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Quote {
+    /// In microseconds since the epoch.
+    pub time: u64,
+    /// `None` while the side is empty.
+    pub bid: Option<StoredLevel>,
+    pub ask: Option<StoredLevel>,
+}
+
+impl Quote {
+    fn new(time: u64, orderbook: &Orderbook) -> Self {
+        Self {
+            time,
+            bid: orderbook
+                .bids
+                .first()
+                .map(|bid| StoredLevel::new(bid, orderbook)),
+            ask: orderbook
+                .asks
+                .first()
+                .map(|ask| StoredLevel::new(ask, orderbook)),
+        }
+    }
+
+    /// Whether `other` has the same best bid and ask.
+    fn same_top(&self, other: &Quote) -> bool {
+        self.bid == other.bid && self.ask == other.ask
+    }
+}
+
+/// Top levels of the aggregated orderbook at `time`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BookSnapshot {
+    /// In microseconds since the epoch.
+    pub time: u64,
+    pub bids: Vec<StoredLevel>,
+    pub asks: Vec<StoredLevel>,
+}
+
+impl BookSnapshot {
+    fn new(time: u64, orderbook: &Orderbook, levels: usize) -> Self {
+        let side = |levels: &mut dyn Iterator<Item = &OrderbookLevel>| {
+            levels
+                .map(|level| StoredLevel::new(level, orderbook))
+                .collect()
+        };
+        Self {
+            time,
+            bids: side(&mut orderbook.bids.iter().take(levels)),
+            asks: side(&mut orderbook.asks.iter().take(levels)),
+        }
+    }
+}
+
+/// Parse the text column `index` of `row`.
+fn parse<T>(row: &Row, index: usize) -> rusqlite::Result<Option<T>>
+where
+    T: FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    row.get::<_, Option<String>>(index)?
+        .map(|text| {
+            T::from_str(&text).map_err(|err| {
+                rusqlite::Error::FromSqlConversionFailure(index, Type::Text, Box::new(err))
+            })
+        })
+        .transpose()
+}
+
+/// Parse the level stored in the text columns `index..index + 3` of `row`.
+fn stored_level(row: &Row, index: usize) -> rusqlite::Result<Option<StoredLevel>> {
+    let exchange = parse(row, index)?;
+    let price = parse(row, index + 1)?;
+    let size = parse(row, index + 2)?;
+    Ok(match (exchange, price, size) {
+        (Some(exchange), Some(price), Some(size)) => Some(StoredLevel {
+            exchange,
+            price,
+            size,
+        }),
+        _ => None,
+    })
+}
+
+/// `time` as stored, clamped to the largest time SQLite can hold so that
+/// far-off bounds of a range don't wrap around.
+fn sql_time(time: u64) -> i64 {
+    time.min(i64::MAX as u64) as i64
+}
+
+/// Map a quotes row to its `Quote`.
+fn quote(row: &Row) -> rusqlite::Result<Quote> {
+    Ok(Quote {
+        time: row.get::<_, i64>(0)? as u64,
+        bid: stored_level(row, 1)?,
+        ask: stored_level(row, 4)?,
+    })
+}
+
+/// SQLite database holding the history.
+#[derive(Debug)]
+pub struct Store {
+    conn: Connection,
+}
+
+impl Store {
+    /// Open the database at `path`, `:memory:` for a private in-memory
+    /// one, creating the tables if missing.
+    pub fn open(path: &str) -> crate::Result<Self> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(SCHEMA)?;
+        Ok(Self { conn })
+    }
+
+    pub fn insert_quote(&mut self, quote: &Quote) -> crate::Result<()> {
+        let columns = |level: &Option<StoredLevel>| match level {
+            Some(level) => (
+                Some(level.exchange.to_string()),
+                Some(level.price.to_string()),
+                Some(level.size.to_string()),
+            ),
+            None => (None, None, None),
+        };
+        let (bid, ask) = (columns(&quote.bid), columns(&quote.ask));
+        self.conn.execute(
+            "INSERT INTO quotes VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![quote.time as i64, bid.0, bid.1, bid.2, ask.0, ask.1, ask.2],
+        )?;
+        Ok(())
+    }
+
+    pub fn insert_book(&mut self, book: &BookSnapshot) -> crate::Result<()> {
+        let tx = self.conn.transaction()?;
+        {
+            let mut insert =
+                tx.prepare("INSERT INTO book_levels VALUES (?1, ?2, ?3, ?4, ?5, ?6)")?;
+            for (side, levels) in [(0, &book.bids), (1, &book.asks)] {
+                for (rank, level) in levels.iter().enumerate() {
+                    insert.execute(params![
+                        book.time as i64,
+                        side,
+                        rank as i64,
+                        level.exchange.to_string(),
+                        level.price.to_string(),
+                        level.size.to_string(),
+                    ])?;
+                }
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// The quote in effect at `from`, if any, followed by every quote up
+    /// to `to`, `limit` quotes at most.
+    pub fn quotes(&self, from: u64, to: u64, limit: usize) -> crate::Result<Vec<Quote>> {
+        let mut quotes = Vec::new();
+        let first = self
+            .conn
+            .query_row(
+                "SELECT * FROM quotes WHERE time <= ?1 ORDER BY time DESC, rowid DESC LIMIT 1",
+                params![sql_time(from)],
+                quote,
+            )
+            .optional()?;
+        quotes.extend(first);
+        let mut select = self.conn.prepare(
+            "SELECT * FROM quotes WHERE time > ?1 AND time <= ?2 ORDER BY time, rowid LIMIT ?3",
+        )?;
+        let remaining = limit.saturating_sub(quotes.len()) as i64;
+        let rows = select.query_map(params![sql_time(from), sql_time(to), remaining], quote)?;
+        for row in rows {
+            quotes.push(row?);
+        }
+        quotes.truncate(limit);
+        Ok(quotes)
+    }
+
+    /// Book snapshots taken from `from` to `to`, `limit` at most.
+    pub fn books(&self, from: u64, to: u64, limit: usize) -> crate::Result<Vec<BookSnapshot>> {
+        let last = self.conn.query_row(
+            "SELECT MAX(time) FROM (SELECT DISTINCT time FROM book_levels
+                 WHERE time >= ?1 AND time <= ?2 ORDER BY time LIMIT ?3)",
+            params![sql_time(from), sql_time(to), limit as i64],
+            |row| row.get::<_, Option<i64>>(0),
+        )?;
+        let last = match last {
+            Some(last) => last,
+            None => return Ok(Vec::new()),
+        };
+        let mut select = self.conn.prepare(
+            "SELECT time, side, exchange, price, size FROM book_levels
+             WHERE time >= ?1 AND time <= ?2 ORDER BY time, side, rank",
+        )?;
+        let rows = select.query_map(params![sql_time(from), last], |row| {
+            let level = stored_level(row, 2)?.ok_or(rusqlite::Error::InvalidColumnType(
+                2,
+                "exchange".to_owned(),
+                Type::Null,
+            ))?;
+            Ok((row.get::<_, i64>(0)? as u64, row.get::<_, i64>(1)?, level))
+        })?;
+        let mut books: BTreeMap<u64, BookSnapshot> = BTreeMap::new();
+        for row in rows {
+            let (time, side, level) = row?;
+            let book = books.entry(time).or_insert_with(|| BookSnapshot {
+                time,
+                ..BookSnapshot::default()
+            });
+            match side {
+                0 => book.bids.push(level),
+                _ => book.asks.push(level),
+            }
+        }
+        Ok(books.into_values().collect())
+    }
+
+    /// Delete quotes older than `quotes_before` and book snapshots older
+    /// than `books_before`, returning the number of rows deleted.
+    pub fn prune(&mut self, quotes_before: u64, books_before: u64) -> crate::Result<usize> {
+        let quotes = self.conn.execute(
+            "DELETE FROM quotes WHERE time < ?1",
+            params![sql_time(quotes_before)],
+        )?;
+        let levels = self.conn.execute(
+            "DELETE FROM book_levels WHERE time < ?1",
+            params![sql_time(books_before)],
+        )?;
+        Ok(quotes + levels)
+    }
+}
+
+/// Shared handle to the `Store`, queried off the async runtime.
+#[derive(Debug, Clone)]
+pub struct History {
+    store: Arc<Mutex<Store>>,
+}
+
+impl History {
+    /// Open the database at `path`, see `Store::open`.
+    pub fn open(path: &str) -> crate::Result<Self> {
+        Ok(Self {
+            store: Arc::new(Mutex::new(Store::open(path)?)),
+        })
+    }
+
+    /// Run `f` with the store on a blocking thread.
+    async fn with_store<T, F>(&self, f: F) -> crate::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Store) -> crate::Result<T> + Send + 'static,
+    {
+        let store = self.store.clone();
+        tokio::task::spawn_blocking(move || f(&mut store.lock().unwrap())).await?
+    }
+
+    /// Quotes from `from` to `to`, see `Store::quotes`, and with `books`
+    /// set the book snapshots of the range too.
+    pub async fn query(
+        &self,
+        from: u64,
+        to: u64,
+        limit: usize,
+        books: bool,
+    ) -> crate::Result<(Vec<Quote>, Vec<BookSnapshot>)> {
+        self.with_store(move |store| {
+            let quotes = store.quotes(from, to, limit)?;
+            let books = match books {
+                true => store.books(from, to, limit)?,
+                false => Vec::new(),
+            };
+            Ok((quotes, books))
+        })
+        .await
+    }
+}
+
+/// Store the top of book of every snapshot published on `snapshot_rx`
+/// which changed it, book snapshots as `config` says, and prune the
+/// history, until the aggregator is gone.
+///
+/// Quotes and books are stamped with `Snapshot::published_at`. The
+/// channel only keeps the latest snapshot, so tops replaced before they
+/// are read are coalesced and never stored. Failing writes are logged and
+/// skipped.
+pub async fn run(history: History, config: Config, mut snapshot_rx: watch::Receiver<Snapshot>) {
+    let mut last_quote: Option<Quote> = None;
+    let mut last_book = None;
+    // A freshly opened store isn't pruned at once.
+    let mut prune = time::interval_at(Instant::now() + PRUNE_INTERVAL, PRUNE_INTERVAL);
+    loop {
+        tokio::select! {
+            res = snapshot_rx.changed() => {
+                if res.is_err() {
+                    break;
+                }
+                let (now, quote, book) = {
+                    let snapshot = snapshot_rx.borrow();
+                    let now = snapshot.published_at;
+                    let quote = Quote::new(now, &snapshot.orderbook);
+                    let book_due = last_book.is_none_or(|last| {
+                        now.saturating_sub(last) >= config.book_interval.as_micros() as u64
+                    });
+                    let book = (config.levels > 0 && book_due && !snapshot.orderbook.is_empty())
+                        .then(|| BookSnapshot::new(now, &snapshot.depth, config.levels));
+                    (now, quote, book)
+                };
+                let quote = match &last_quote {
+                    Some(last) if last.same_top(&quote) => None,
+                    _ => Some(quote),
+                };
+                if quote.is_none() && book.is_none() {
+                    continue;
+                }
+                if let Some(quote) = &quote {
+                    last_quote = Some(quote.clone());
+                }
+                if book.is_some() {
+                    last_book = Some(now);
+                }
+                let res = history.with_store(move |store| {
+                    if let Some(quote) = &quote {
+                        store.insert_quote(quote)?;
+                    }
+                    if let Some(book) = &book {
+                        store.insert_book(book)?;
+                    }
+                    Ok(())
+                });
+                if let Err(err) = res.await {
+                    error!(%err, "failed to store history");
+                }
+            }
+            _ = prune.tick() => {
+                let now = trade::now_micros();
+                let before = |retention: Duration| now.saturating_sub(retention.as_micros() as u64);
+                let (quotes_before, books_before) =
+                    (before(config.quote_retention), before(config.book_retention));
+                match history.with_store(move |store| store.prune(quotes_before, books_before)).await {
+                    Ok(deleted) => debug!(deleted, "pruned history"),
+                    Err(err) => error!(%err, "failed to prune history"),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ntest::timeout;
+    use rust_decimal_macros::dec;
+
+    use super::*;
+    use crate::scale::Scale;
+
+    fn level(exchange: Exchange, price: Decimal) -> Option<StoredLevel> {
+        Some(StoredLevel {
+            exchange,
+            price,
+            size: dec!(1.5),
+        })
+    }
+
+    fn quote(time: u64, bid: Decimal) -> Quote {
+        Quote {
+            time,
+            bid: level(Exchange::Binance, bid),
+            ask: level(Exchange::Bitstamp, dec!(0.0625)),
+        }
+    }
+
+    #[test]
+    fn quotes_start_with_the_one_in_effect() {
+        let mut store = Store::open(":memory:").unwrap();
+        for (time, bid) in [(10, dec!(0.061)), (20, dec!(0.0612)), (30, dec!(0.0613))] {
+            store.insert_quote(&quote(time, bid)).unwrap();
+        }
+        store
+            .insert_quote(&Quote {
+                time: 40,
+                bid: None,
+                ask: None,
+            })
+            .unwrap();
+
+        let quotes = store.quotes(25, 100, 10).unwrap();
+        let times: Vec<u64> = quotes.iter().map(|quote| quote.time).collect();
+        assert_eq!(times, vec![20, 30, 40]);
+        assert_eq!(quotes[0], quote(20, dec!(0.0612)));
+        assert_eq!(quotes[2].bid, None);
+
+        assert_eq!(store.quotes(25, 100, 2).unwrap().len(), 2);
+        assert_eq!(
+            store.quotes(5, 15, 10).unwrap(),
+            vec![quote(10, dec!(0.061))]
+        );
+
+        assert_eq!(store.prune(30, 0).unwrap(), 2);
+        assert_eq!(store.quotes(0, 100, 10).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn books_are_grouped_by_time() {
+        let mut store = Store::open(":memory:").unwrap();
+        for time in [10, 20, 30] {
+            let book = BookSnapshot {
+                time,
+                bids: vec![
+                    level(Exchange::Binance, dec!(0.0612)).unwrap(),
+                    level(Exchange::Bitstamp, dec!(0.061)).unwrap(),
+                ],
+                asks: vec![level(Exchange::Bitstamp, dec!(0.0625)).unwrap()],
+            };
+            store.insert_book(&book).unwrap();
+        }
+
+        let books = store.books(15, 100, 1).unwrap();
+        assert_eq!(books.len(), 1);
+        assert_eq!(books[0].time, 20);
+        assert_eq!(books[0].bids.len(), 2);
+        assert_eq!(books[0].bids[1].exchange, Exchange::Bitstamp);
+        assert_eq!(books[0].asks[0].price, dec!(0.0625));
+        assert_eq!(store.books(15, 100, 10).unwrap().len(), 2);
+        assert!(store.books(40, 100, 10).unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    #[timeout(5000)]
+    async fn run_stores_changes_of_the_top_of_book() {
+        let history = History::open(":memory:").unwrap();
+        let config = Config {
+            path: ":memory:".to_owned(),
+            levels: 1,
+            book_interval: Duration::from_secs(3_600),
+            quote_retention: Duration::from_secs(3_600),
+            book_retention: Duration::from_secs(3_600),
+        };
+        let (snapshot_tx, snapshot_rx) = watch::channel(Snapshot::default());
+        tokio::spawn(run(history.clone(), config, snapshot_rx));
+
+        let scale = Scale::new(4, 1);
+        let publish = |published_at: u64, bid: i64, ask: i64| {
+            let orderbook =
+                Orderbook::from_levels(scale, Exchange::Binance, &[(bid, 10)], &[(ask, 10)]);
+            snapshot_tx
+                .send(Snapshot {
+                    published_at,
+                    orderbook: orderbook.clone(),
+                    depth: Arc::new(orderbook),
+                    ..Snapshot::default()
+                })
+                .unwrap();
+        };
+        let stored = |count: usize| {
+            let history = history.clone();
+            async move {
+                loop {
+                    let (quotes, books) = history.query(0, u64::MAX >> 1, 10, true).await.unwrap();
+                    if quotes.len() >= count {
+                        return (quotes, books);
+                    }
+                    tokio::task::yield_now().await;
+                }
+            }
+        };
+
+        let start = trade::now_micros();
+        publish(start, 610, 625);
+        stored(1).await;
+        // Unchanged top of book isn't stored again.
+        publish(start + 1_000, 610, 625);
+        publish(start + 2_000, 612, 625);
+        let (quotes, books) = stored(2).await;
+        assert_eq!(quotes.len(), 2);
+        // Stamped with when the snapshots were published.
+        assert_eq!((quotes[0].time, quotes[1].time), (start, start + 2_000));
+        assert_eq!(quotes[1].bid.as_ref().unwrap().price, dec!(0.0612));
+        assert_eq!(quotes[1].ask.as_ref().unwrap().size, dec!(1));
+        // The next book is only due in an hour.
+        assert_eq!(books.len(), 1);
+        assert_eq!(books[0].time, start);
+        assert_eq!(books[0].bids[0].price, dec!(0.061));
+    }
+}
@@ -0,0 +1,232 @@
+//! # json
+//!
+//! Building blocks for parsing the exchanges' JSON messages without
+//! intermediate allocations. Strings are borrowed from the message and
+//! price levels are parsed straight into buffers owned by the connector's
+//! parser, which are reused from message to message.
+use std::borrow::Cow;
+use std::fmt;
+
+use rust_decimal::prelude::*;
+use serde::de::{self, Deserialize, DeserializeSeed, Deserializer, IgnoredAny, SeqAccess, Visitor};
+
+use crate::scale::{self, Scale};
+
+/// Price ticks and size lots of one level.
+pub(crate) type Level = (i64, i64);
+
+/// Deserializes `[["price","size"], ...]` into a reused buffer of ticks
+/// and lots of `scale`, replacing its contents.
+pub(crate) struct LevelsSeed<'b> {
+    pub scale: Scale,
+    pub levels: &'b mut Vec<Level>,
+}
+
+impl<'de, 'b> DeserializeSeed<'de> for LevelsSeed<'b> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de, 'b> Visitor<'de> for LevelsSeed<'b> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a list of [price, size] pairs")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+        self.levels.clear();
+        while let Some(level) = seq.next_element_seed(LevelSeed(self.scale))? {
+            self.levels.push(level);
+        }
+        Ok(())
+    }
+}
+
+/// Deserializes one `["price","size"]` pair.
+struct LevelSeed(Scale);
+
+impl<'de> DeserializeSeed<'de> for LevelSeed {
+    type Value = Level;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Level, D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de> Visitor<'de> for LevelSeed {
+    type Value = Level;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a [price, size] pair")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Level, A::Error> {
+        let price = seq
+            .next_element_seed(Fixed(self.0.price_decimals))?
+            .ok_or_else(|| de::Error::invalid_length(0, &self))?;
+        let size = seq
+            .next_element_seed(Fixed(self.0.size_decimals))?
+            .ok_or_else(|| de::Error::invalid_length(1, &self))?;
+        if seq.next_element::<IgnoredAny>()?.is_some() {
+            return Err(de::Error::invalid_length(3, &self));
+        }
+        Ok((price, size))
+    }
+}
+
+/// Deserializes a decimal, sent either as a string or as a JSON number,
+/// into units of `10^-decimals`. Values with more decimals are rejected
+/// rather than rounded.
+struct Fixed(u32);
+
+impl<'de> DeserializeSeed<'de> for Fixed {
+    type Value = i64;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<i64, D::Error> {
+        deserializer.deserialize_any(self)
+    }
+}
+
+impl<'de> Visitor<'de> for Fixed {
+    type Value = i64;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a decimal with at most {} decimals", self.0)
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<i64, E> {
+        scale::parse_fixed(value, self.0)
+            .ok_or_else(|| E::invalid_value(de::Unexpected::Str(value), &self))
+    }
+
+    fn visit_u64<E: de::Error>(self, value: u64) -> Result<i64, E> {
+        Decimal::from_u64(value)
+            .and_then(|value| scale::to_fixed(value, self.0))
+            .ok_or_else(|| E::invalid_value(de::Unexpected::Unsigned(value), &self))
+    }
+
+    fn visit_i64<E: de::Error>(self, value: i64) -> Result<i64, E> {
+        scale::to_fixed(Decimal::from(value), self.0)
+            .ok_or_else(|| E::invalid_value(de::Unexpected::Signed(value), &self))
+    }
+
+    fn visit_f64<E: de::Error>(self, value: f64) -> Result<i64, E> {
+        Decimal::from_f64(value)
+            .and_then(|value| scale::to_fixed(value, self.0))
+            .ok_or_else(|| E::invalid_value(de::Unexpected::Float(value), &self))
+    }
+}
+
+/// String borrowed from the message unless it contains escapes.
+#[derive(Debug, serde::Deserialize)]
+pub(crate) struct Text<'a>(#[serde(borrow)] pub Cow<'a, str>);
+
+/// Unsigned integer sent either as a JSON number or as a string.
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct Number(pub u64);
+
+impl<'de> Deserialize<'de> for Number {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct NumberVisitor;
+
+        impl<'de> Visitor<'de> for NumberVisitor {
+            type Value = Number;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("an unsigned integer or a string containing one")
+            }
+
+            fn visit_u64<E: de::Error>(self, value: u64) -> Result<Number, E> {
+                Ok(Number(value))
+            }
+
+            fn visit_str<E: de::Error>(self, value: &str) -> Result<Number, E> {
+                value.parse().map(Number).map_err(E::custom)
+            }
+        }
+
+        deserializer.deserialize_any(NumberVisitor)
+    }
+}
+
+/// Decimal sent either as a string or as a JSON number, kept exact.
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct Exact(pub Decimal);
+
+impl<'de> Deserialize<'de> for Exact {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct ExactVisitor;
+
+        impl<'de> Visitor<'de> for ExactVisitor {
+            type Value = Exact;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a decimal or a string containing one")
+            }
+
+            fn visit_str<E: de::Error>(self, value: &str) -> Result<Exact, E> {
+                Decimal::from_str(value).map(Exact).map_err(E::custom)
+            }
+
+            fn visit_u64<E: de::Error>(self, value: u64) -> Result<Exact, E> {
+                Ok(Exact(Decimal::from(value)))
+            }
+
+            fn visit_f64<E: de::Error>(self, value: f64) -> Result<Exact, E> {
+                Decimal::from_f64(value)
+                    .map(Exact)
+                    .ok_or_else(|| E::invalid_value(de::Unexpected::Float(value), &self))
+            }
+        }
+
+        deserializer.deserialize_any(ExactVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn levels(json: &str, levels: &mut Vec<Level>) -> serde_json::Result<()> {
+        let mut deserializer = serde_json::Deserializer::from_str(json);
+        LevelsSeed {
+            scale: Scale::new(2, 1),
+            levels,
+        }
+        .deserialize(&mut deserializer)
+    }
+
+    #[test]
+    fn levels_replace_buffer_contents() {
+        let mut buffer = vec![(9, 9)];
+        levels(r#"[["0.1","2"],["0.25","3.5"]]"#, &mut buffer).unwrap();
+        assert_eq!(buffer, vec![(10, 20), (25, 35)]);
+        levels(r#"[[1, 0.5]]"#, &mut buffer).unwrap();
+        assert_eq!(buffer, vec![(100, 5)]);
+
+        assert!(levels(r#"[["0.1"]]"#, &mut buffer).is_err());
+        assert!(levels(r#"[["0.1","1","2"]]"#, &mut buffer).is_err());
+        // More decimals than the scale would be rounded.
+        assert!(levels(r#"[["0.125","1"]]"#, &mut buffer).is_err());
+    }
+
+    #[test]
+    fn numbers_and_text() {
+        let numbers: Vec<Number> = serde_json::from_str(r#"[1, "2"]"#).unwrap();
+        assert_eq!((numbers[0].0, numbers[1].0), (1, 2));
+        assert!(serde_json::from_str::<Number>(r#""x""#).is_err());
+
+        let exact: Vec<Exact> = serde_json::from_str(r#"["0.0610", 2]"#).unwrap();
+        assert_eq!(exact[0].0.to_string(), "0.0610");
+        assert_eq!(exact[1].0, Decimal::from(2));
+
+        let text: Text = serde_json::from_str(r#""order_book_ethbtc""#).unwrap();
+        assert!(matches!(text.0, Cow::Borrowed("order_book_ethbtc")));
+        let text: Text = serde_json::from_str(r#""a\"b""#).unwrap();
+        assert_eq!(text.0, "a\"b");
+    }
+}
@@ -1,14 +1,32 @@
-#[macro_use]
-extern crate simple_error;
-
 pub mod aggregator;
+pub mod arbitrage;
 pub mod binance;
 pub mod bitstamp;
+pub mod candle;
+mod config;
+pub mod error;
+pub mod fees;
+pub mod history;
+mod json;
+pub mod metrics;
+#[cfg(feature = "test-support")]
+pub mod mock_exchange;
 pub mod order_book;
 pub mod proto;
+pub mod quote;
+pub mod recorder;
+pub mod replay;
+pub mod routing;
+pub mod scale;
+pub mod service;
+pub mod shutdown;
+pub mod supervisor;
+pub mod synthetic;
+pub mod telemetry;
+pub mod trade;
+mod websocket;
 
-/// Error returned by most functions.
-pub type Error = Box<dyn std::error::Error + Send + Sync>;
+pub use error::Error;
 
 /// A specialized `Result` type defined as a convenience.
 pub type Result<T> = std::result::Result<T, Error>;
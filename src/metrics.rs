@@ -0,0 +1,177 @@
+//! # metrics
+//!
+//! Prometheus metrics describing the aggregation pipeline and an HTTP
+//! endpoint exposing them at `/metrics`.
+use std::convert::Infallible;
+use std::net::SocketAddr;
+
+use hyper::header::CONTENT_TYPE;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use lazy_static::lazy_static;
+use prometheus::{
+    register_gauge_vec, register_histogram, register_int_counter_vec, register_int_gauge, Encoder,
+    GaugeVec, Histogram, IntCounterVec, IntGauge, TextEncoder,
+};
+use rust_decimal::prelude::ToPrimitive;
+
+use crate::order_book::Orderbook;
+
+/// Latency buckets in seconds, from 10us to ~2.6s.
+const LATENCY_BUCKETS: &[f64] = &[
+    0.00001, 0.00004, 0.00016, 0.00064, 0.00256, 0.01024, 0.04096, 0.16384, 0.65536, 2.62144,
+];
+
+lazy_static! {
+    /// Websocket messages received, per venue.
+    pub static ref MESSAGES_TOTAL: IntCounterVec = register_int_counter_vec!(
+        "orderbook_messages_total",
+        "Websocket messages received per venue.",
+        &["venue"]
+    )
+    .unwrap();
+
+    /// Websocket messages that could not be parsed, per venue.
+    pub static ref PARSE_ERRORS_TOTAL: IntCounterVec = register_int_counter_vec!(
+        "orderbook_parse_errors_total",
+        "Websocket messages that failed to parse per venue.",
+        &["venue"]
+    )
+    .unwrap();
+
+    /// Orderbook updates rejected by the aggregator, per venue.
+    pub static ref REJECTED_UPDATES_TOTAL: IntCounterVec = register_int_counter_vec!(
+        "orderbook_rejected_updates_total",
+        "Orderbook updates rejected by the aggregator per venue.",
+        &["venue"]
+    )
+    .unwrap();
+
+    /// Websocket reconnects, per venue.
+    pub static ref RECONNECTS_TOTAL: IntCounterVec = register_int_counter_vec!(
+        "orderbook_reconnects_total",
+        "Websocket reconnects per venue.",
+        &["venue"]
+    )
+    .unwrap();
+
+    /// Time from receiving a venue message to publishing the aggregate.
+    pub static ref PUBLISH_LATENCY_SECONDS: Histogram = register_histogram!(
+        "orderbook_publish_latency_seconds",
+        "Time from receiving a venue message to publishing the aggregated orderbook.",
+        LATENCY_BUCKETS.to_vec()
+    )
+    .unwrap();
+
+    /// Time spent computing the aggregated orderbook.
+    pub static ref AGGREGATE_DURATION_SECONDS: Histogram = register_histogram!(
+        "orderbook_aggregate_duration_seconds",
+        "Time spent computing the aggregated orderbook.",
+        LATENCY_BUCKETS.to_vec()
+    )
+    .unwrap();
+
+    /// Trades received, per venue.
+    pub static ref TRADES_TOTAL: IntCounterVec = register_int_counter_vec!(
+        "orderbook_trades_total",
+        "Trades received per venue.",
+        &["venue"]
+    )
+    .unwrap();
+
+    /// Currently connected `BookSummary` subscribers.
+    pub static ref ACTIVE_SUBSCRIBERS: IntGauge = register_int_gauge!(
+        "orderbook_active_subscribers",
+        "Currently connected gRPC BookSummary subscribers."
+    )
+    .unwrap();
+
+    /// Updates waiting in the channel between connectors and the aggregator.
+    pub static ref CHANNEL_BACKLOG: IntGauge = register_int_gauge!(
+        "orderbook_channel_backlog",
+        "Orderbook updates queued between the connectors and the aggregator."
+    )
+    .unwrap();
+
+    /// Spread of the aggregated orderbook, per pair.
+    pub static ref SPREAD: GaugeVec = register_gauge_vec!(
+        "orderbook_spread",
+        "Spread of the aggregated orderbook per pair.",
+        &["pair"]
+    )
+    .unwrap();
+
+    /// Best bid and ask price of the aggregated orderbook, per pair.
+    pub static ref TOP_OF_BOOK: GaugeVec = register_gauge_vec!(
+        "orderbook_top_of_book",
+        "Best bid and ask price of the aggregated orderbook per pair.",
+        &["pair", "side"]
+    )
+    .unwrap();
+}
+
+/// Record spread and top of book of the aggregated orderbook for `pair`.
+pub fn observe_orderbook(pair: &str, orderbook: &Orderbook) {
+    if let Some(spread) = orderbook.spread().and_then(|spread| spread.to_f64()) {
+        SPREAD.with_label_values(&[pair]).set(spread);
+    }
+    if let Some(bid) = orderbook.top_bid().and_then(|bid| bid.to_f64()) {
+        TOP_OF_BOOK.with_label_values(&[pair, "bid"]).set(bid);
+    }
+    if let Some(ask) = orderbook.top_ask().and_then(|ask| ask.to_f64()) {
+        TOP_OF_BOOK.with_label_values(&[pair, "ask"]).set(ask);
+    }
+}
+
+/// Serve the metrics over HTTP on `addr` until the server fails.
+pub async fn serve(addr: SocketAddr) -> crate::Result<()> {
+    let make_service = make_service_fn(|_conn| async { Ok::<_, Infallible>(service_fn(handle)) });
+    Server::bind(&addr).serve(make_service).await?;
+    Ok(())
+}
+
+/// Respond with the text encoded metrics on `GET /metrics`.
+async fn handle(request: Request<Body>) -> Result<Response<Body>, Infallible> {
+    let response = match (request.method(), request.uri().path()) {
+        (&Method::GET, "/metrics") => {
+            let encoder = TextEncoder::new();
+            let mut buffer = Vec::new();
+            match encoder.encode(&prometheus::gather(), &mut buffer) {
+                Ok(()) => Response::builder()
+                    .header(CONTENT_TYPE, encoder.format_type())
+                    .body(Body::from(buffer)),
+                Err(_) => Response::builder()
+                    .status(StatusCode::INTERNAL_SERVER_ERROR)
+                    .body(Body::empty()),
+            }
+        }
+        _ => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::empty()),
+    };
+    Ok(response.unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn metrics_endpoint_exposes_counters() {
+        MESSAGES_TOTAL.with_label_values(&["Binance"]).inc();
+        let request = Request::get("/metrics").body(Body::empty()).unwrap();
+        let response = handle(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains("orderbook_messages_total{venue=\"Binance\"}"));
+    }
+
+    #[tokio::test]
+    async fn unknown_path_is_not_found() {
+        let request = Request::get("/").body(Body::empty()).unwrap();
+        let response = handle(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,377 @@
+//! # mock_exchange
+//!
+//! Local websocket server standing in for Binance and Bitstamp in tests.
+//!
+//! Every accepted connection plays one `Script`: frames to send, delays,
+//! waits for client frames (e.g. the Bitstamp subscription) and abrupt
+//! disconnects. Once its script is exhausted the connection stays open
+//! until the client closes it. The `binance` and `bitstamp` modules build
+//! the venue specific messages.
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::sink::SinkExt;
+use futures::stream::StreamExt;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+use tokio::time;
+use tokio_util::codec::Framed;
+use websocket_codec::{ClientRequest, Message, MessageCodec, Opcode};
+
+use crate::error::BoxError;
+
+/// Single action of a `Script`.
+#[derive(Debug, Clone)]
+pub enum Step {
+    /// Send a text frame.
+    Text(String),
+    /// Send a text frame of arbitrary bytes, e.g. invalid UTF-8, which
+    /// the codec refuses to encode.
+    RawText(Vec<u8>),
+    /// Send a ping frame.
+    Ping,
+    /// Wait before the next step.
+    Delay(Duration),
+    /// Wait for the client to send a text frame.
+    ExpectText,
+    /// Send a close frame.
+    Close,
+    /// Drop the TCP connection without a close frame.
+    Disconnect,
+}
+
+/// Sequence of steps played on one connection.
+#[derive(Debug, Clone, Default)]
+pub struct Script {
+    steps: Vec<Step>,
+}
+
+impl Script {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Script sending previously recorded text frames in order.
+    pub fn recorded<I, S>(frames: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        Self {
+            steps: frames
+                .into_iter()
+                .map(|frame| Step::Text(frame.into()))
+                .collect(),
+        }
+    }
+
+    pub fn text(mut self, frame: impl Into<String>) -> Self {
+        self.steps.push(Step::Text(frame.into()));
+        self
+    }
+
+    pub fn raw_text(mut self, frame: impl Into<Vec<u8>>) -> Self {
+        self.steps.push(Step::RawText(frame.into()));
+        self
+    }
+
+    pub fn ping(mut self) -> Self {
+        self.steps.push(Step::Ping);
+        self
+    }
+
+    pub fn delay(mut self, delay: Duration) -> Self {
+        self.steps.push(Step::Delay(delay));
+        self
+    }
+
+    pub fn expect_text(mut self) -> Self {
+        self.steps.push(Step::ExpectText);
+        self
+    }
+
+    pub fn close(mut self) -> Self {
+        self.steps.push(Step::Close);
+        self
+    }
+
+    pub fn disconnect(mut self) -> Self {
+        self.steps.push(Step::Disconnect);
+        self
+    }
+}
+
+/// What the server has seen so far.
+#[derive(Debug, Default)]
+struct State {
+    /// Request paths of the accepted connections.
+    paths: Vec<String>,
+    /// Text frames received from clients.
+    received: Vec<String>,
+    /// Number of clients which sent a close frame.
+    closed: usize,
+}
+
+/// Running mock exchange server, stopped when dropped.
+#[derive(Debug)]
+pub struct MockExchange {
+    addr: SocketAddr,
+    state: Arc<Mutex<State>>,
+    handle: JoinHandle<()>,
+}
+
+impl MockExchange {
+    /// Start the server on an ephemeral port.
+    ///
+    /// The n-th accepted connection plays the n-th script; connections
+    /// beyond the given scripts play an empty one.
+    pub async fn start(scripts: Vec<Script>) -> std::io::Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let state = Arc::new(Mutex::new(State::default()));
+
+        let server_state = state.clone();
+        let handle = tokio::spawn(async move {
+            let mut scripts = scripts.into_iter();
+            while let Ok((stream, _)) = listener.accept().await {
+                let script = scripts.next().unwrap_or_default();
+                let state = server_state.clone();
+                tokio::spawn(async move {
+                    let _ = serve(stream, script, state).await;
+                });
+            }
+        });
+
+        Ok(Self {
+            addr,
+            state,
+            handle,
+        })
+    }
+
+    /// Base URL to hand to a connector instead of the exchange's.
+    pub fn url(&self) -> String {
+        format!("ws://{}/", self.addr)
+    }
+
+    /// Request paths of all connections accepted so far.
+    pub fn paths(&self) -> Vec<String> {
+        self.state.lock().unwrap().paths.clone()
+    }
+
+    /// Number of connections accepted so far.
+    pub fn connections(&self) -> usize {
+        self.state.lock().unwrap().paths.len()
+    }
+
+    /// Text frames received from clients so far.
+    pub fn received(&self) -> Vec<String> {
+        self.state.lock().unwrap().received.clone()
+    }
+
+    /// Number of clients which closed their connection with a close frame.
+    pub fn closed(&self) -> usize {
+        self.state.lock().unwrap().closed
+    }
+}
+
+impl Drop for MockExchange {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+/// Perform the server side of the websocket handshake and play `script`.
+async fn serve(
+    mut stream: TcpStream,
+    script: Script,
+    state: Arc<Mutex<State>>,
+) -> Result<(), BoxError> {
+    let request = read_request(&mut stream).await?;
+    let mut lines = request.split("\r\n");
+    let path = lines
+        .next()
+        .and_then(|line| line.split(' ').nth(1))
+        .unwrap_or("/")
+        .to_owned();
+    let headers: Vec<(&str, &str)> = lines.filter_map(|line| line.split_once(": ")).collect();
+    let client = ClientRequest::parse(|name| {
+        headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| *value)
+    })?;
+    let response = format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+        client.ws_accept()
+    );
+    stream.write_all(response.as_bytes()).await?;
+    state.lock().unwrap().paths.push(path);
+
+    let mut framed = Framed::new(stream, MessageCodec::server());
+    for step in script.steps {
+        match step {
+            Step::Text(frame) => framed.send(Message::text(frame)).await?,
+            Step::RawText(frame) => {
+                // A final, unmasked text frame of up to 125 bytes.
+                let mut raw = vec![0x81, frame.len() as u8];
+                raw.extend(frame);
+                framed.get_mut().write_all(&raw).await?;
+            }
+            Step::Ping => framed.send(Message::ping("ping")).await?,
+            Step::Delay(delay) => time::sleep(delay).await,
+            Step::ExpectText => loop {
+                match framed.next().await {
+                    Some(Ok(msg)) if msg.opcode() == Opcode::Text => {
+                        let text = msg.as_text().unwrap_or_default().to_owned();
+                        state.lock().unwrap().received.push(text);
+                        break;
+                    }
+                    Some(Ok(_)) => {}
+                    _ => return Ok(()),
+                }
+            },
+            Step::Close => framed.send(Message::close(None)).await?,
+            Step::Disconnect => return Ok(()),
+        }
+    }
+
+    while let Some(Ok(msg)) = framed.next().await {
+        match msg.opcode() {
+            Opcode::Text => {
+                let text = msg.as_text().unwrap_or_default().to_owned();
+                state.lock().unwrap().received.push(text);
+            }
+            Opcode::Close => {
+                state.lock().unwrap().closed += 1;
+                let _ = framed.send(Message::close(None)).await;
+                break;
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Read the client's HTTP upgrade request up to the empty line.
+async fn read_request(stream: &mut TcpStream) -> Result<String, BoxError> {
+    let mut request = Vec::new();
+    let mut byte = [0u8; 1];
+    while !request.ends_with(b"\r\n\r\n") {
+        if stream.read(&mut byte).await? == 0 {
+            return Err("connection closed during handshake".into());
+        }
+        request.push(byte[0]);
+    }
+    Ok(String::from_utf8(request)?)
+}
+
+/// Render price levels the way both exchanges do, as string pairs.
+fn levels(levels: &[(&str, &str)]) -> String {
+    let levels: Vec<String> = levels
+        .iter()
+        .map(|(price, size)| format!(r#"["{}","{}"]"#, price, size))
+        .collect();
+    format!("[{}]", levels.join(","))
+}
+
+/// Binance partial book depth stream messages.
+pub mod binance {
+    use super::levels;
+
+    /// `<symbol>@depth<levels>` stream update.
+    pub fn depth(last_update_id: u64, bids: &[(&str, &str)], asks: &[(&str, &str)]) -> String {
+        format!(
+            r#"{{"lastUpdateId":{},"bids":{},"asks":{}}}"#,
+            last_update_id,
+            levels(bids),
+            levels(asks)
+        )
+    }
+
+    /// `<symbol>@trade` stream event.
+    pub fn trade(id: u64, price: &str, quantity: &str, time: u64, buyer_is_maker: bool) -> String {
+        format!(
+            r#"{{"e":"trade","E":{},"s":"ETHBTC","t":{},"p":"{}","q":"{}","b":1,"a":2,"T":{},"m":{},"M":true}}"#,
+            time, id, price, quantity, time, buyer_is_maker
+        )
+    }
+
+    /// `<symbol>@aggTrade` stream event.
+    pub fn agg_trade(
+        id: u64,
+        price: &str,
+        quantity: &str,
+        time: u64,
+        buyer_is_maker: bool,
+    ) -> String {
+        format!(
+            r#"{{"e":"aggTrade","E":{},"s":"ETHBTC","a":{},"p":"{}","q":"{}","f":1,"l":2,"T":{},"m":{},"M":true}}"#,
+            time, id, price, quantity, time, buyer_is_maker
+        )
+    }
+}
+
+/// Bitstamp websocket API v2 messages.
+pub mod bitstamp {
+    use super::levels;
+
+    /// Reply to a successful `bts:subscribe` request.
+    pub fn subscription_succeeded(pair: &str) -> String {
+        format!(
+            r#"{{"event":"bts:subscription_succeeded","channel":"order_book_{}","data":{{}}}}"#,
+            pair
+        )
+    }
+
+    /// Reply to a rejected `bts:subscribe` request.
+    pub fn error(message: &str) -> String {
+        format!(
+            r#"{{"event":"bts:error","channel":"","data":{{"code":null,"message":"{}"}}}}"#,
+            message
+        )
+    }
+
+    /// `order_book_<pair>` channel update.
+    pub fn order_book(
+        pair: &str,
+        microtimestamp: u64,
+        bids: &[(&str, &str)],
+        asks: &[(&str, &str)],
+    ) -> String {
+        format!(
+            r#"{{"event":"data","channel":"order_book_{}","data":{{"timestamp":"{}","microtimestamp":"{}","bids":{},"asks":{}}}}}"#,
+            pair,
+            microtimestamp / 1_000_000,
+            microtimestamp,
+            levels(bids),
+            levels(asks)
+        )
+    }
+
+    /// `live_trades_<pair>` channel trade; `side` is 0 for a buy, 1 for a
+    /// sell.
+    pub fn live_trade(
+        pair: &str,
+        id: u64,
+        price: &str,
+        amount: &str,
+        microtimestamp: u64,
+        side: u8,
+    ) -> String {
+        format!(
+            r#"{{"event":"trade","channel":"live_trades_{}","data":{{"id":{},"timestamp":"{}","amount":{},"amount_str":"{}","price":{},"price_str":"{}","type":{},"microtimestamp":"{}","buy_order_id":1,"sell_order_id":2}}}}"#,
+            pair,
+            id,
+            microtimestamp / 1_000_000,
+            amount,
+            amount,
+            price,
+            price,
+            side,
+            microtimestamp
+        )
+    }
+}
@@ -1,27 +1,39 @@
 use rust_decimal::prelude::*;
 use sorted_vec::{ReverseSortedVec, SortedVec};
 use std::cmp::Ordering;
-use std::str::FromStr;
-use strum_macros::{EnumString, ToString};
+use std::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
+use strum_macros::{Display, EnumString, IntoStaticStr};
+use tokio::time::Instant;
 
-use crate::proto;
+use crate::error::Error;
+use crate::scale::Scale;
 
 pub type AsksVec = SortedVec<OrderbookLevel>;
 pub type BidsVec = ReverseSortedVec<OrderbookLevel>;
 
+/// Source of `OrderbookUpdateEvent` ids.
+static NEXT_UPDATE_ID: AtomicU64 = AtomicU64::new(1);
+
 /// Supported exchanges.
-#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, ToString, EnumString)]
+#[derive(
+    Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Display, EnumString, IntoStaticStr,
+)]
 pub enum Exchange {
     Unknown,
     Binance,
     Bitstamp,
+    /// Book implied by a chain of other books, see `synthetic`.
+    Synthetic,
 }
 
 /// Simple orderbook composed of bids and asks.
-#[derive(Debug, Clone)]
+///
+/// Prices and sizes of the levels are kept in ticks and lots of `scale`.
+#[derive(Debug, Clone, Default)]
 pub struct Orderbook {
     pub asks: AsksVec,
     pub bids: BidsVec,
+    pub scale: Scale,
 }
 
 impl Orderbook {
@@ -29,42 +41,230 @@ impl Orderbook {
         Self {
             asks: AsksVec::new(),
             bids: BidsVec::new(),
+            scale: Scale::default(),
         }
     }
 
-    pub fn from_bids_asks(bids: Vec<OrderbookLevel>, asks: Vec<OrderbookLevel>) -> Self {
+    pub fn from_bids_asks(
+        scale: Scale,
+        bids: Vec<OrderbookLevel>,
+        asks: Vec<OrderbookLevel>,
+    ) -> Self {
         Self {
             bids: BidsVec::from(bids),
             asks: AsksVec::from(asks),
+            scale,
         }
     }
 
+    /// Create the orderbook of `exchange` from its `(ticks, lots)` levels.
+    pub fn from_levels(
+        scale: Scale,
+        exchange: Exchange,
+        bids: &[(i64, i64)],
+        asks: &[(i64, i64)],
+    ) -> Self {
+        Self::from_bids_asks(
+            scale,
+            bids.iter()
+                .map(|&(price, size)| OrderbookLevel::bid(price, size, exchange))
+                .collect(),
+            asks.iter()
+                .map(|&(price, size)| OrderbookLevel::ask(price, size, exchange))
+                .collect(),
+        )
+    }
+
+    /// Whether the orderbook has neither bids nor asks.
+    pub fn is_empty(&self) -> bool {
+        self.bids.is_empty() && self.asks.is_empty()
+    }
+
     pub fn limit(&self, limit: usize) -> Orderbook {
         let bids = self.bids.iter().cloned().take(limit).collect();
         let asks = self.asks.iter().cloned().take(limit).collect();
-        Orderbook::from_bids_asks(bids, asks)
+        Orderbook::from_bids_asks(self.scale, bids, asks)
     }
 
     pub fn spread(&self) -> Option<Decimal> {
-        match (self.top_bid(), self.top_ask()) {
-            (Some(bid), Some(ask)) => Some(ask - bid),
-            _ => None
+        match (self.bids.first(), self.asks.first()) {
+            (Some(bid), Some(ask)) => Some(self.scale.price(ask.price - bid.price)),
+            _ => None,
         }
     }
 
     pub fn top_bid(&self) -> Option<Decimal> {
-        match self.bids.first() {
-            Some(bid) => Some(bid.price),
-            None => None,
-        }
+        self.bids.first().map(|bid| self.scale.price(bid.price))
     }
 
     pub fn top_ask(&self) -> Option<Decimal> {
-        match self.asks.first() {
-            Some(ask) => Some(ask.price),
-            None => None,
+        self.asks.first().map(|ask| self.scale.price(ask.price))
+    }
+
+    /// Whether the top bid is below, at or above the top ask.
+    pub fn state(&self) -> BookState {
+        match (self.bids.first(), self.asks.first()) {
+            (Some(bid), Some(ask)) => match bid.price.cmp(&ask.price) {
+                Ordering::Less => BookState::Normal,
+                Ordering::Equal => BookState::Locked,
+                Ordering::Greater => BookState::Crossed,
+            },
+            _ => BookState::Normal,
+        }
+    }
+
+    /// Price halfway between the top bid and the top ask.
+    pub fn mid(&self) -> Option<Decimal> {
+        match (self.top_bid(), self.top_ask()) {
+            (Some(bid), Some(ask)) => Some((bid + ask) / Decimal::from(2)),
+            _ => None,
+        }
+    }
+
+    /// Mid price weighted by the sizes of the top levels, leaning towards
+    /// the side with less size, which is the likelier to be taken next.
+    pub fn microprice(&self) -> Option<Decimal> {
+        let (bid, ask) = (self.bids.first()?, self.asks.first()?);
+        let (bid_size, ask_size) = (self.scale.size(bid.size), self.scale.size(ask.size));
+        let total = bid_size + ask_size;
+        if total.is_zero() {
+            return self.mid();
         }
+        let (bid_price, ask_price) = (self.scale.price(bid.price), self.scale.price(ask.price));
+        Some((bid_price * ask_size + ask_price * bid_size) / total)
     }
+
+    /// Spread relative to the mid price, in basis points.
+    pub fn spread_bps(&self) -> Option<Decimal> {
+        let mid = self.mid().filter(|mid| !mid.is_zero())?;
+        Some(self.spread()? / mid * Decimal::from(10_000))
+    }
+
+    /// Imbalance of the size of the best `levels` bids and asks, from -1
+    /// when there are only asks to 1 when there are only bids.
+    pub fn imbalance(&self, levels: usize) -> Option<Decimal> {
+        let volume = |side: &[OrderbookLevel]| -> Decimal {
+            side.iter()
+                .take(levels)
+                .map(|level| self.scale.size(level.size))
+                .sum()
+        };
+        let (bids, asks) = (volume(&self.bids), volume(&self.asks));
+        let total = bids + asks;
+        if total.is_zero() {
+            return None;
+        }
+        Some((bids - asks) / total)
+    }
+
+    /// Cumulative size of the bids and of the asks priced within `bps`
+    /// basis points of the mid price.
+    pub fn depth_within(&self, bps: Decimal) -> (Decimal, Decimal) {
+        let mid = match self.mid() {
+            Some(mid) => mid,
+            None => return (Decimal::zero(), Decimal::zero()),
+        };
+        let distance = mid * bps / Decimal::from(10_000);
+        let depth = |side: &[OrderbookLevel]| -> Decimal {
+            side.iter()
+                .take_while(|level| (self.scale.price(level.price) - mid).abs() <= distance)
+                .map(|level| self.scale.size(level.size))
+                .sum()
+        };
+        (depth(&self.bids), depth(&self.asks))
+    }
+
+    /// Estimate sweeping `side` of the book, best level first, for
+    /// `quantity`: the asks for a buy, the bids for a sell.
+    ///
+    /// If the side is too thin the estimate covers all of it and isn't
+    /// `complete`. Levels with a zero price or size are skipped. Returns
+    /// `None` if nothing could be filled or there is no non-zero mid price
+    /// to measure slippage against.
+    pub fn fill(&self, side: LevelSide, quantity: Quantity) -> Option<Fill> {
+        let mid = self.mid().filter(|mid| !mid.is_zero())?;
+        let levels: &[OrderbookLevel] = match side {
+            LevelSide::Bid => &self.bids,
+            LevelSide::Ask => &self.asks,
+        };
+
+        let (mut base, mut quote) = (Decimal::zero(), Decimal::zero());
+        let mut worst_price = None;
+        let mut complete = false;
+        for level in levels {
+            if level.price == 0 || level.size == 0 {
+                continue;
+            }
+            let (price, size) = (self.scale.price(level.price), self.scale.size(level.size));
+            let remaining = match quantity {
+                Quantity::Base(target) => target - base,
+                Quantity::Quote(target) => (target - quote) / price,
+            };
+            if remaining <= Decimal::zero() {
+                complete = true;
+                break;
+            }
+            let taken = remaining.min(size);
+            base += taken;
+            quote += taken * price;
+            worst_price = Some(price);
+            if taken == remaining {
+                complete = true;
+                break;
+            }
+        }
+
+        let worst_price = worst_price?;
+        let vwap = quote / base;
+        let slippage = match side {
+            LevelSide::Ask => vwap - mid,
+            LevelSide::Bid => mid - vwap,
+        };
+        Some(Fill {
+            base,
+            quote,
+            vwap,
+            worst_price,
+            slippage_bps: slippage / mid * Decimal::from(10_000),
+            complete,
+        })
+    }
+}
+
+/// Size of an order, in the base or in the quote currency.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Quantity {
+    Base(Decimal),
+    Quote(Decimal),
+}
+
+/// Estimated execution of an order sweeping one side of an orderbook.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fill {
+    /// Base quantity filled.
+    pub base: Decimal,
+    /// Quote notional paid or received.
+    pub quote: Decimal,
+    /// Volume-weighted average price.
+    pub vwap: Decimal,
+    /// Price of the last level reached.
+    pub worst_price: Decimal,
+    /// Cost of `vwap` relative to the mid price, in basis points.
+    pub slippage_bps: Decimal,
+    /// Whether the whole quantity was filled.
+    pub complete: bool,
+}
+
+/// Relation of the top bid to the top ask.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub enum BookState {
+    /// The top bid is below the top ask, or a side is empty.
+    #[default]
+    Normal,
+    /// The top bid equals the top ask.
+    Locked,
+    /// The top bid is above the top ask.
+    Crossed,
 }
 
 /// Orderbook level side
@@ -72,20 +272,24 @@ impl Orderbook {
 pub enum LevelSide {
     Bid,
     Ask,
-    Unknown,
 }
 
 /// Simple orderbook entry.
+///
+/// Levels are ordered by price, then by size (larger first among asks)
+/// and then by exchange, so levels of different exchanges are never
+/// equal. Price and size are in ticks and lots of the orderbook's
+/// `Scale`.
 #[derive(Debug, Eq, Clone, Copy)]
 pub struct OrderbookLevel {
-    pub price: Decimal,
-    pub size: Decimal,
+    pub price: i64,
+    pub size: i64,
     pub exchange: Exchange,
     pub side: LevelSide,
 }
 
 impl OrderbookLevel {
-    pub fn bid(price: Decimal, size: Decimal, exchange: Exchange) -> Self {
+    pub fn bid(price: i64, size: i64, exchange: Exchange) -> Self {
         Self {
             price,
             size,
@@ -94,7 +298,7 @@ impl OrderbookLevel {
         }
     }
 
-    pub fn ask(price: Decimal, size: Decimal, exchange: Exchange) -> Self {
+    pub fn ask(price: i64, size: i64, exchange: Exchange) -> Self {
         Self {
             price,
             size,
@@ -102,37 +306,6 @@ impl OrderbookLevel {
             side: LevelSide::Ask,
         }
     }
-
-    pub fn from_exchange(exchange: Exchange, tuple: (Decimal, Decimal)) -> Self {
-        OrderbookLevel {
-            price: tuple.0,
-            size: tuple.1,
-            exchange,
-            side: LevelSide::Unknown,
-        }
-    }
-}
-
-impl From<(Decimal, Decimal)> for OrderbookLevel {
-    fn from(tuple: (Decimal, Decimal)) -> Self {
-        OrderbookLevel {
-            price: tuple.0,
-            size: tuple.1,
-            exchange: Exchange::Unknown,
-            side: LevelSide::Unknown,
-        }
-    }
-}
-
-impl From<proto::Level> for OrderbookLevel {
-    fn from(level: proto::Level) -> Self {
-        OrderbookLevel {
-            price: Decimal::from_f64(level.price).unwrap(),
-            size: Decimal::from_f64(level.amount).unwrap(),
-            exchange: Exchange::from_str(&level.exchange).unwrap(),
-            side: LevelSide::Unknown,
-        }
-    }
 }
 
 impl PartialOrd for OrderbookLevel {
@@ -143,80 +316,313 @@ impl PartialOrd for OrderbookLevel {
 
 impl Ord for OrderbookLevel {
     fn cmp(&self, other: &Self) -> Ordering {
-        let order = self.price.cmp(&other.price);
-        if let Ordering::Equal = order {
-            let order = self.size.cmp(&other.size);
-            let order = match self.side {
-                LevelSide::Ask => order.reverse(),
-                _ => order,
-            };
-            order
-        } else {
-            order
-        }
+        let size = match self.side {
+            LevelSide::Ask => other.size.cmp(&self.size),
+            LevelSide::Bid => self.size.cmp(&other.size),
+        };
+        self.price
+            .cmp(&other.price)
+            .then(size)
+            .then(self.exchange.cmp(&other.exchange))
     }
 }
 
 impl PartialEq for OrderbookLevel {
     fn eq(&self, other: &Self) -> bool {
-        self.price == other.price && self.size == other.size && self.exchange == self.exchange
+        self.cmp(other) == Ordering::Equal
     }
 }
 
 #[derive(Debug)]
 pub struct OrderbookUpdateEvent {
+    /// Process-wide unique id used to follow the update through the logs.
+    pub id: u64,
     pub exchange: Exchange,
     pub orderbook: Orderbook,
+    /// When the update was received from the exchange.
+    pub received_at: Instant,
+    /// Rate the prices were converted into the reference quote currency
+    /// at, `None` if the venue quotes in it, see `quote`.
+    pub quote_rate: Option<Decimal>,
 }
 
 impl OrderbookUpdateEvent {
     pub fn new(exchange: Exchange, orderbook: Orderbook) -> Self {
         Self {
+            id: NEXT_UPDATE_ID.fetch_add(1, AtomicOrdering::Relaxed),
             exchange,
             orderbook,
+            received_at: Instant::now(),
+            quote_rate: None,
+        }
+    }
+
+    /// Check that every level was produced by the event's exchange.
+    pub fn validate(&self) -> crate::Result<()> {
+        let levels = self.orderbook.bids.iter().chain(self.orderbook.asks.iter());
+        match levels
+            .map(|level| level.exchange)
+            .find(|&e| e != self.exchange)
+        {
+            Some(found) => Err(Error::Provenance {
+                exchange: self.exchange,
+                found,
+            }),
+            None => Ok(()),
         }
     }
 }
 
+/// `proptest` strategies generating orderbooks.
+#[cfg(test)]
+pub(crate) mod strategy {
+    use proptest::collection::btree_map;
+    use proptest::prelude::*;
+
+    use super::*;
+
+    /// Scale of the generated books.
+    pub const SCALE: Scale = Scale::new(4, 3);
+
+    /// Price of the generated books, in ticks of 0.0001, which every bid
+    /// stays below and every ask above.
+    pub const MID_TICKS: i64 = 10_000;
+
+    fn levels(
+        ticks: std::ops::Range<i64>,
+        max_levels: usize,
+    ) -> impl Strategy<Value = Vec<(i64, i64)>> {
+        btree_map(ticks, 1i64..1_000_000, 0..=max_levels)
+            .prop_map(|levels| levels.into_iter().collect())
+    }
+
+    /// Uncrossed orderbook of `exchange` with unique prices per side and
+    /// up to `max_levels` levels each.
+    pub fn orderbook(exchange: Exchange, max_levels: usize) -> impl Strategy<Value = Orderbook> {
+        orderbook_around(exchange, MID_TICKS, MID_TICKS - 1, max_levels)
+    }
+
+    /// Uncrossed orderbook of `exchange` as `orderbook`, with its bids
+    /// within `width` ticks below `mid` and its asks within `width` ticks
+    /// above it.
+    pub fn orderbook_around(
+        exchange: Exchange,
+        mid: i64,
+        width: i64,
+        max_levels: usize,
+    ) -> impl Strategy<Value = Orderbook> {
+        (
+            levels(mid - width..mid, max_levels),
+            levels(mid + 1..mid + 1 + width, max_levels),
+        )
+            .prop_map(move |(bids, asks)| {
+                let bids = bids
+                    .into_iter()
+                    .map(|(price, size)| OrderbookLevel::bid(price, size, exchange))
+                    .collect();
+                let asks = asks
+                    .into_iter()
+                    .map(|(price, size)| OrderbookLevel::ask(price, size, exchange))
+                    .collect();
+                Orderbook::from_bids_asks(SCALE, bids, asks)
+            })
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use proptest::prelude::*;
     use rust_decimal_macros::*;
+
     use super::*;
 
+    const SCALE: Scale = Scale::new(8, 8);
+
+    fn bid(price: Decimal, size: Decimal) -> OrderbookLevel {
+        let (price, size) = (SCALE.ticks(price).unwrap(), SCALE.lots(size).unwrap());
+        OrderbookLevel::bid(price, size, Exchange::Unknown)
+    }
+
+    fn ask(price: Decimal, size: Decimal) -> OrderbookLevel {
+        let (price, size) = (SCALE.ticks(price).unwrap(), SCALE.lots(size).unwrap());
+        OrderbookLevel::ask(price, size, Exchange::Unknown)
+    }
+
     #[test]
     fn asks_are_sorted() {
         let asks = vec![
-            OrderbookLevel::ask(dec!(1.2), dec!(1.0), Exchange::Unknown),
-            OrderbookLevel::ask(dec!(1.1), dec!(1.0), Exchange::Unknown),
-            OrderbookLevel::ask(dec!(0.9), dec!(0.1), Exchange::Unknown),
-            OrderbookLevel::ask(dec!(0.9), dec!(10.0), Exchange::Unknown),
+            ask(dec!(1.2), dec!(1.0)),
+            ask(dec!(1.1), dec!(1.0)),
+            ask(dec!(0.9), dec!(0.1)),
+            ask(dec!(0.9), dec!(10.0)),
         ];
         let asks = AsksVec::from(asks);
         let top_ask = asks[0];
-        assert_eq!(top_ask.price, dec!(0.9));
-        assert_eq!(top_ask.size, dec!(10.0));
+        assert_eq!(SCALE.price(top_ask.price), dec!(0.9));
+        assert_eq!(SCALE.size(top_ask.size), dec!(10.0));
 
         let second_ask = asks[1];
-        assert_eq!(second_ask.price, dec!(0.9));
-        assert_eq!(second_ask.size, dec!(0.1));
+        assert_eq!(SCALE.price(second_ask.price), dec!(0.9));
+        assert_eq!(SCALE.size(second_ask.size), dec!(0.1));
     }
 
     #[test]
     fn bids_are_sorted() {
         let bids = vec![
-            OrderbookLevel::bid(dec!(1.1), dec!(1.0), Exchange::Unknown),
-            OrderbookLevel::bid(dec!(1.1), dec!(2.0), Exchange::Unknown),
-            OrderbookLevel::bid(dec!(0.8), dec!(1.0), Exchange::Unknown),
-            OrderbookLevel::bid(dec!(1.2), dec!(3.0), Exchange::Unknown),
+            bid(dec!(1.1), dec!(1.0)),
+            bid(dec!(1.1), dec!(2.0)),
+            bid(dec!(0.8), dec!(1.0)),
+            bid(dec!(1.2), dec!(3.0)),
         ];
         let bids = BidsVec::from(bids);
         let top_bid = bids[0];
-        assert_eq!(top_bid.price, dec!(1.2));
-        assert_eq!(top_bid.size, dec!(3.0));
+        assert_eq!(SCALE.price(top_bid.price), dec!(1.2));
+        assert_eq!(SCALE.size(top_bid.size), dec!(3.0));
 
         let second_bid = bids[1];
-        assert_eq!(second_bid.price, dec!(1.1));
-        assert_eq!(second_bid.size, dec!(2.0));
+        assert_eq!(SCALE.price(second_bid.price), dec!(1.1));
+        assert_eq!(SCALE.size(second_bid.size), dec!(2.0));
+    }
+
+    #[test]
+    fn from_levels_sets_sides() {
+        let orderbook = Orderbook::from_levels(
+            SCALE,
+            Exchange::Binance,
+            &[(10, 1), (10, 2)],
+            &[(11, 1), (11, 2)],
+        );
+        assert!(orderbook.bids.iter().all(|bid| bid.side == LevelSide::Bid));
+        assert!(orderbook.asks.iter().all(|ask| ask.side == LevelSide::Ask));
+        // Larger size first on both sides at the same price.
+        assert_eq!(orderbook.bids[0].size, 2);
+        assert_eq!(orderbook.asks[0].size, 2);
+    }
+
+    #[test]
+    fn update_with_foreign_levels_is_invalid() {
+        let orderbook = Orderbook::from_levels(SCALE, Exchange::Binance, &[(10, 1)], &[(11, 1)]);
+        assert!(
+            OrderbookUpdateEvent::new(Exchange::Binance, orderbook.clone())
+                .validate()
+                .is_ok()
+        );
+        match OrderbookUpdateEvent::new(Exchange::Bitstamp, orderbook).validate() {
+            Err(Error::Provenance { exchange, found }) => {
+                assert_eq!((exchange, found), (Exchange::Bitstamp, Exchange::Binance))
+            }
+            res => panic!("unexpected result {:?}", res),
+        }
+    }
+
+    #[test]
+    fn fill_sweeps_levels() {
+        let orderbook = Orderbook::from_bids_asks(
+            SCALE,
+            vec![bid(dec!(99), dec!(1)), bid(dec!(98), dec!(2))],
+            vec![ask(dec!(101), dec!(1)), ask(dec!(102), dec!(2))],
+        );
+        assert_eq!(orderbook.mid(), Some(dec!(100)));
+
+        let fill = orderbook
+            .fill(LevelSide::Ask, Quantity::Base(dec!(2)))
+            .unwrap();
+        assert_eq!(fill.base, dec!(2));
+        assert_eq!(fill.quote, dec!(203));
+        assert_eq!(fill.vwap, dec!(101.5));
+        assert_eq!(fill.worst_price, dec!(102));
+        assert_eq!(fill.slippage_bps, dec!(150));
+        assert!(fill.complete);
+
+        let fill = orderbook
+            .fill(LevelSide::Bid, Quantity::Quote(dec!(99)))
+            .unwrap();
+        assert_eq!(
+            (fill.base, fill.vwap, fill.worst_price),
+            (dec!(1), dec!(99), dec!(99))
+        );
+        assert_eq!(fill.slippage_bps, dec!(100));
+        assert!(fill.complete);
+
+        // Deeper than the book: everything is swept.
+        let fill = orderbook
+            .fill(LevelSide::Bid, Quantity::Base(dec!(5)))
+            .unwrap();
+        assert_eq!((fill.base, fill.quote), (dec!(3), dec!(295)));
+        assert!(!fill.complete);
+    }
+
+    #[test]
+    fn fill_skips_empty_levels() {
+        let orderbook = Orderbook::from_bids_asks(
+            SCALE,
+            vec![bid(dec!(99), dec!(1)), bid(dec!(0), dec!(1))],
+            vec![ask(dec!(101), dec!(0)), ask(dec!(102), dec!(1))],
+        );
+        let fill = orderbook
+            .fill(LevelSide::Ask, Quantity::Quote(dec!(204)))
+            .unwrap();
+        assert_eq!((fill.base, fill.worst_price), (dec!(1), dec!(102)));
+        assert!(!fill.complete);
+        let fill = orderbook
+            .fill(LevelSide::Bid, Quantity::Quote(dec!(500)))
+            .unwrap();
+        assert_eq!((fill.base, fill.worst_price), (dec!(1), dec!(99)));
+
+        let orderbook = Orderbook::from_bids_asks(
+            SCALE,
+            vec![bid(dec!(0), dec!(1))],
+            vec![ask(dec!(0), dec!(1))],
+        );
+        assert_eq!(
+            orderbook.fill(LevelSide::Ask, Quantity::Quote(dec!(1))),
+            None
+        );
+    }
+
+    #[test]
+    fn statistics() {
+        let orderbook = Orderbook::from_bids_asks(
+            SCALE,
+            vec![bid(dec!(99), dec!(3)), bid(dec!(98), dec!(2))],
+            vec![
+                ask(dec!(101), dec!(1)),
+                ask(dec!(102), dec!(1)),
+                ask(dec!(110), dec!(4)),
+            ],
+        );
+        assert_eq!(orderbook.mid(), Some(dec!(100)));
+        // Thin asks pull the microprice towards the ask.
+        assert_eq!(orderbook.microprice(), Some(dec!(100.5)));
+        assert_eq!(orderbook.spread_bps(), Some(dec!(200)));
+        assert_eq!(orderbook.imbalance(1), Some(dec!(0.5)));
+        assert_eq!(
+            orderbook.imbalance(3),
+            Some(dec!(-0.0909090909090909090909090909))
+        );
+        assert_eq!(orderbook.depth_within(dec!(200)), (dec!(5), dec!(2)));
+        assert_eq!(orderbook.depth_within(dec!(100)), (dec!(3), dec!(1)));
+    }
+
+    #[test]
+    fn statistics_of_empty_book() {
+        let orderbook = Orderbook::new();
+        assert_eq!(orderbook.microprice(), None);
+        assert_eq!(orderbook.spread_bps(), None);
+        assert_eq!(orderbook.imbalance(10), None);
+        assert_eq!(
+            orderbook.depth_within(dec!(10)),
+            (Decimal::zero(), Decimal::zero())
+        );
+    }
+
+    #[test]
+    fn fill_needs_both_sides() {
+        let orderbook = Orderbook::from_bids_asks(SCALE, vec![], vec![ask(dec!(1), dec!(1))]);
+        assert_eq!(
+            orderbook.fill(LevelSide::Ask, Quantity::Base(dec!(1))),
+            None
+        );
     }
 
     #[test]
@@ -227,10 +633,7 @@ mod tests {
 
     #[test]
     fn top_bid() {
-        let orderbook = Orderbook::from_bids_asks(
-            vec![OrderbookLevel::bid(dec!(1.0), dec!(1.0), Exchange::Unknown)],
-            vec![],
-        );
+        let orderbook = Orderbook::from_bids_asks(SCALE, vec![bid(dec!(1.0), dec!(1.0))], vec![]);
         assert!(orderbook.top_bid().is_some());
     }
 
@@ -242,16 +645,75 @@ mod tests {
 
     #[test]
     fn top_ask() {
-        let orderbook = Orderbook::from_bids_asks(
-            vec![],
-            vec![OrderbookLevel::ask(dec!(1.0), dec!(1.0), Exchange::Unknown)],
-        );
+        let orderbook = Orderbook::from_bids_asks(SCALE, vec![], vec![ask(dec!(1.0), dec!(1.0))]);
         assert!(orderbook.top_ask().is_some());
     }
 
+    #[test]
+    fn state_compares_top_levels() {
+        let state = |bid_price, ask_price| {
+            Orderbook::from_bids_asks(
+                SCALE,
+                vec![bid(bid_price, dec!(1))],
+                vec![ask(ask_price, dec!(1))],
+            )
+            .state()
+        };
+        assert_eq!(state(dec!(1), dec!(2)), BookState::Normal);
+        assert_eq!(state(dec!(2), dec!(2)), BookState::Locked);
+        assert_eq!(state(dec!(3), dec!(2)), BookState::Crossed);
+        assert_eq!(Orderbook::new().state(), BookState::Normal);
+    }
+
     #[test]
     fn empty_spread() {
         let orderbook = Orderbook::new();
         assert_eq!(orderbook.spread(), None);
     }
+
+    fn level() -> impl Strategy<Value = OrderbookLevel> {
+        let exchange = prop_oneof![Just(Exchange::Binance), Just(Exchange::Bitstamp)];
+        (0i64..20, 0i64..5, exchange, any::<bool>()).prop_map(|(price, size, exchange, bid)| {
+            if bid {
+                OrderbookLevel::bid(price, size, exchange)
+            } else {
+                OrderbookLevel::ask(price, size, exchange)
+            }
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn ordering_is_consistent_with_equality(a in level(), b in level()) {
+            // Levels are only ever compared within one side.
+            let b = OrderbookLevel { side: a.side, ..b };
+            prop_assert_eq!(a == b, a.cmp(&b) == Ordering::Equal);
+            prop_assert_eq!(a.cmp(&b), b.cmp(&a).reverse());
+            if a.exchange != b.exchange {
+                prop_assert_ne!(a, b);
+            }
+        }
+
+        #[test]
+        fn sides_are_sorted_by_price(orderbook in strategy::orderbook(Exchange::Binance, 20)) {
+            for pair in orderbook.bids.windows(2) {
+                prop_assert!(pair[0].price > pair[1].price);
+            }
+            for pair in orderbook.asks.windows(2) {
+                prop_assert!(pair[0].price < pair[1].price);
+            }
+        }
+
+        #[test]
+        fn limit_keeps_best_levels(
+            orderbook in strategy::orderbook(Exchange::Bitstamp, 20),
+            limit in 0usize..25,
+        ) {
+            let limited = orderbook.limit(limit);
+            prop_assert_eq!(limited.bids.len(), orderbook.bids.len().min(limit));
+            prop_assert_eq!(limited.asks.len(), orderbook.asks.len().min(limit));
+            prop_assert_eq!(&limited.bids[..], &orderbook.bids[..limited.bids.len()]);
+            prop_assert_eq!(&limited.asks[..], &orderbook.asks[..limited.asks.len()]);
+        }
+    }
 }
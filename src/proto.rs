@@ -1,15 +1,205 @@
-use crate::order_book::{Orderbook, OrderbookLevel};
-use rust_decimal::prelude::ToPrimitive;
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use crate::arbitrage;
+use crate::candle;
+use crate::fees::{AdjustedLevel, AdjustedOrderbook};
+use crate::history;
+use crate::order_book::{self, Exchange, Fill, LevelSide, Orderbook, OrderbookLevel};
+use crate::routing;
+use crate::scale::Scale;
+use crate::trade;
+use rust_decimal::prelude::{Decimal, ToPrimitive};
 use rust_decimal_macros::*;
 
 tonic::include_proto!("orderbook");
 
-impl From<&OrderbookLevel> for Level {
-    fn from(orderbook_level: &OrderbookLevel) -> Self {
+impl Level {
+    /// Convert `orderbook_level`, in ticks and lots of `scale`.
+    pub fn new(orderbook_level: &OrderbookLevel, scale: Scale) -> Self {
+        let price = scale.price(orderbook_level.price).to_f64().unwrap();
         Self {
             exchange: orderbook_level.exchange.to_string(),
-            price: orderbook_level.price.to_f64().unwrap(),
-            amount: orderbook_level.size.to_f64().unwrap(),
+            price,
+            raw_price: price,
+            amount: scale.size(orderbook_level.size).to_f64().unwrap(),
+            side: Side::from(orderbook_level.side) as i32,
+            legs: Vec::new(),
+            quote_rate: 1.0,
+        }
+    }
+}
+
+impl From<AdjustedOrderbook> for Summary {
+    fn from(orderbook: AdjustedOrderbook) -> Self {
+        let spread = orderbook.spread().unwrap_or(dec!(0.0));
+        let level = |adjusted: &AdjustedLevel| Level {
+            price: adjusted.price.to_f64().unwrap(),
+            ..Level::new(&adjusted.level, orderbook.scale)
+        };
+        let bids = orderbook.bids.iter().map(level).collect();
+        let asks = orderbook.asks.iter().map(level).collect();
+        Self {
+            spread: spread.to_f64().unwrap(),
+            bids,
+            asks,
+            state: BookState::from(orderbook.state) as i32,
+            ..Summary::default()
+        }
+    }
+}
+
+impl Summary {
+    /// Fill in the statistics of `orderbook`, measuring the imbalance
+    /// over its best `imbalance_levels` and the depth within `depth_bps`
+    /// of its mid price.
+    pub fn with_statistics(
+        self,
+        orderbook: &Orderbook,
+        imbalance_levels: usize,
+        depth_bps: Decimal,
+    ) -> Self {
+        let f64 = |value: Option<Decimal>| value.and_then(|value| value.to_f64()).unwrap_or(0.0);
+        let (bid_depth, ask_depth) = orderbook.depth_within(depth_bps);
+        Self {
+            mid: f64(orderbook.mid()),
+            microprice: f64(orderbook.microprice()),
+            spread_bps: f64(orderbook.spread_bps()),
+            imbalance: f64(orderbook.imbalance(imbalance_levels)),
+            bid_depth: f64(Some(bid_depth)),
+            ask_depth: f64(Some(ask_depth)),
+            ..self
+        }
+    }
+}
+
+impl Summary {
+    /// Tag the levels of the `Synthetic` venue with the `legs` implying
+    /// them.
+    pub fn with_legs(mut self, legs: &[String]) -> Self {
+        let synthetic = Exchange::Synthetic.to_string();
+        for level in self.bids.iter_mut().chain(self.asks.iter_mut()) {
+            if level.exchange == synthetic {
+                level.legs = legs.to_vec();
+            }
+        }
+        self
+    }
+}
+
+impl Summary {
+    /// Set the `quote_rates` the levels' venues were converted at.
+    pub fn with_quote_rates(mut self, quote_rates: &HashMap<Exchange, Decimal>) -> Self {
+        if quote_rates.is_empty() {
+            return self;
+        }
+        for level in self.bids.iter_mut().chain(self.asks.iter_mut()) {
+            let rate = Exchange::from_str(&level.exchange)
+                .ok()
+                .and_then(|exchange| quote_rates.get(&exchange));
+            if let Some(rate) = rate {
+                level.quote_rate = rate.to_f64().unwrap();
+            }
+        }
+        self
+    }
+}
+
+impl From<order_book::BookState> for BookState {
+    fn from(state: order_book::BookState) -> Self {
+        match state {
+            order_book::BookState::Normal => BookState::Normal,
+            order_book::BookState::Locked => BookState::Locked,
+            order_book::BookState::Crossed => BookState::Crossed,
+        }
+    }
+}
+
+impl From<trade::Trade> for Trade {
+    fn from(trade: trade::Trade) -> Self {
+        let aggressor = match trade.aggressor {
+            trade::Aggressor::Buy => Aggressor::Buy,
+            trade::Aggressor::Sell => Aggressor::Sell,
+        };
+        Self {
+            exchange: trade.exchange.to_string(),
+            id: trade.id,
+            price: trade.price.to_f64().unwrap(),
+            amount: trade.size.to_f64().unwrap(),
+            aggressor: aggressor as i32,
+            traded_at: trade.traded_at,
+            received_at: trade.received_at,
+        }
+    }
+}
+
+impl From<candle::Candle> for Candle {
+    fn from(candle: candle::Candle) -> Self {
+        let (source, exchange) = match candle.source {
+            candle::Source::Venue(exchange) => (CandleSource::Venue, exchange.to_string()),
+            candle::Source::Consolidated => (CandleSource::Consolidated, String::new()),
+            candle::Source::Mid => (CandleSource::Mid, String::new()),
+        };
+        Self {
+            source: source as i32,
+            exchange,
+            interval: candle.interval.to_string(),
+            open_time: candle.open_time,
+            open: candle.open.to_f64().unwrap(),
+            high: candle.high.to_f64().unwrap(),
+            low: candle.low.to_f64().unwrap(),
+            close: candle.close.to_f64().unwrap(),
+            volume: candle.volume.to_f64().unwrap(),
+            count: candle.count,
+            closed: candle.closed,
+        }
+    }
+}
+
+impl From<history::StoredLevel> for StoredLevel {
+    fn from(level: history::StoredLevel) -> Self {
+        Self {
+            exchange: level.exchange.to_string(),
+            price: level.price.to_f64().unwrap(),
+            amount: level.size.to_f64().unwrap(),
+        }
+    }
+}
+
+impl From<history::Quote> for Quote {
+    fn from(quote: history::Quote) -> Self {
+        Self {
+            time: quote.time,
+            bid: quote.bid.map(StoredLevel::from),
+            ask: quote.ask.map(StoredLevel::from),
+        }
+    }
+}
+
+impl From<history::BookSnapshot> for BookSnapshot {
+    fn from(book: history::BookSnapshot) -> Self {
+        Self {
+            time: book.time,
+            bids: book.bids.into_iter().map(StoredLevel::from).collect(),
+            asks: book.asks.into_iter().map(StoredLevel::from).collect(),
+        }
+    }
+}
+
+impl From<Side> for LevelSide {
+    fn from(side: Side) -> Self {
+        match side {
+            Side::Bid => LevelSide::Bid,
+            Side::Ask => LevelSide::Ask,
+        }
+    }
+}
+
+impl From<LevelSide> for Side {
+    fn from(side: LevelSide) -> Self {
+        match side {
+            LevelSide::Bid => Side::Bid,
+            LevelSide::Ask => Side::Ask,
         }
     }
 }
@@ -17,12 +207,61 @@ impl From<&OrderbookLevel> for Level {
 impl From<Orderbook> for Summary {
     fn from(orderbook: Orderbook) -> Self {
         let spread = orderbook.spread().unwrap_or(dec!(0.0));
-        let bids = orderbook.bids.iter().map(|bid| Level::from(bid)).collect();
-        let asks = orderbook.asks.iter().map(|ask| Level::from(ask)).collect();
+        let level = |level| Level::new(level, orderbook.scale);
+        let bids = orderbook.bids.iter().map(level).collect();
+        let asks = orderbook.asks.iter().map(level).collect();
         Self {
             spread: spread.to_f64().unwrap(),
-            bids: bids,
-            asks: asks,
+            bids,
+            asks,
+            state: BookState::from(orderbook.state()) as i32,
+            ..Summary::default()
+        }
+    }
+}
+
+impl From<Fill> for FillEstimate {
+    fn from(fill: Fill) -> Self {
+        Self {
+            base: fill.base.to_f64().unwrap(),
+            quote: fill.quote.to_f64().unwrap(),
+            vwap: fill.vwap.to_f64().unwrap(),
+            worst_price: fill.worst_price.to_f64().unwrap(),
+            slippage_bps: fill.slippage_bps.to_f64().unwrap(),
+            complete: fill.complete,
+        }
+    }
+}
+
+impl From<routing::Plan> for RoutePlan {
+    fn from(plan: routing::Plan) -> Self {
+        let orders = plan
+            .orders
+            .into_iter()
+            .map(|order| ChildOrder {
+                exchange: order.exchange.to_string(),
+                quantity: order.quantity.to_f64().unwrap(),
+                limit_price: order.limit_price.to_f64().unwrap(),
+            })
+            .collect();
+        Self {
+            orders,
+            quantity: plan.quantity.to_f64().unwrap(),
+            complete: plan.complete,
+        }
+    }
+}
+
+impl From<arbitrage::Opportunity> for Opportunity {
+    fn from(opportunity: arbitrage::Opportunity) -> Self {
+        Self {
+            buy_exchange: opportunity.buy.to_string(),
+            sell_exchange: opportunity.sell.to_string(),
+            quantity: opportunity.quantity.to_f64().unwrap(),
+            buy_price: opportunity.buy_price.to_f64().unwrap(),
+            sell_price: opportunity.sell_price.to_f64().unwrap(),
+            profit: opportunity.profit.to_f64().unwrap(),
+            profit_bps: opportunity.profit_bps.to_f64().unwrap(),
         }
     }
 }
@@ -0,0 +1,520 @@
+//! # quote
+//!
+//! Normalization of venues quoting the same base currency in different
+//! quote currencies, e.g. BTC in USDT on Binance and in USD on Bitstamp.
+//! The prices of a venue quoting another currency than the reference one
+//! are converted at the rate of its quote currency before they are
+//! aggregated. A rate is either configured or derived from the live book
+//! of a pair of the quote and the reference currency.
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use rust_decimal::prelude::*;
+use rust_decimal::RoundingStrategy;
+use tokio::sync::{mpsc, watch};
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::{StreamExt, StreamMap};
+use tracing::{debug, info, warn};
+
+use crate::aggregator::Snapshot;
+use crate::config::{key_values, venue_values};
+use crate::error::Error;
+use crate::order_book::{Exchange, Orderbook, OrderbookLevel, OrderbookUpdateEvent};
+use crate::synthetic::Leg;
+use crate::trade::Trade;
+
+/// Value of one unit of a quote currency in the reference currency.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Rate {
+    Fixed(Decimal),
+    /// Mid price of the leg's book, quoting the currency in the reference
+    /// currency unless inverted. The book is read in the leg's own scale,
+    /// so the rate keeps its decimals whatever the pair's scale.
+    Live(Leg),
+}
+
+impl FromStr for Rate {
+    type Err = String;
+
+    /// Parse a decimal, or a `Venue:pair[:inverse][:scale]` leg.
+    fn from_str(rate: &str) -> Result<Self, Self::Err> {
+        match Decimal::from_str(rate) {
+            Ok(rate) if rate.is_sign_positive() && !rate.is_zero() => Ok(Rate::Fixed(rate)),
+            Ok(rate) => Err(format!("rate must be positive, got {}", rate)),
+            Err(_) => rate.parse().map(Rate::Live),
+        }
+    }
+}
+
+/// Quote currencies of the venues and their rates.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Quotes {
+    /// Quote currency of each venue not quoting the reference currency.
+    pub venues: HashMap<Exchange, String>,
+    /// Rate of each quote currency.
+    pub rates: HashMap<String, Rate>,
+    /// Pair of each venue not named like the aggregated pair, e.g.
+    /// `btcusdt` on Binance for `btcusd`.
+    pub pairs: HashMap<Exchange, String>,
+}
+
+impl Quotes {
+    /// Read the quote currencies from `VENUE_QUOTES`, a comma-separated
+    /// list of `Venue=currency` pairs, e.g. `Binance=usdt`, and their
+    /// rates from `QUOTE_RATES`, a list of `currency=rate` pairs, e.g.
+    /// `usdt=0.9998` or `usdt=Bitstamp:usdtusd`. The venues' own names of
+    /// the pair are read from `VENUE_PAIRS`, e.g. `Binance=btcusdt`. `None`
+    /// if every venue quotes the reference currency.
+    pub fn from_env() -> crate::Result<Option<Self>> {
+        let venues = match std::env::var("VENUE_QUOTES") {
+            Ok(venues) => venue_values("VENUE_QUOTES", &venues)?,
+            Err(_) => return Ok(None),
+        };
+        let rates = std::env::var("QUOTE_RATES").unwrap_or_default();
+        let rates = key_values("QUOTE_RATES", &rates)?
+            .into_iter()
+            .map(|(currency, rate)| {
+                let rate = rate
+                    .parse()
+                    .map_err(|err| Error::config("QUOTE_RATES", err))?;
+                Ok((currency.to_owned(), rate))
+            })
+            .collect::<crate::Result<_>>()?;
+        let pairs = match std::env::var("VENUE_PAIRS") {
+            Ok(pairs) => venue_values("VENUE_PAIRS", &pairs)?,
+            Err(_) => HashMap::new(),
+        };
+        let quotes = Self {
+            venues,
+            rates,
+            pairs,
+        };
+        if let Some(currency) = quotes
+            .venues
+            .values()
+            .find(|currency| !quotes.rates.contains_key(*currency))
+        {
+            let reason = format!("no rate of `{}` in QUOTE_RATES", currency);
+            return Err(Error::config("VENUE_QUOTES", reason));
+        }
+        Ok(Some(quotes))
+    }
+
+    /// Currencies whose rates are derived from a live book, with the leg
+    /// of that book.
+    pub fn live_legs(&self) -> Vec<(String, Leg)> {
+        let mut legs: Vec<(String, Leg)> = self
+            .rates
+            .iter()
+            .filter_map(|(currency, rate)| match rate {
+                Rate::Live(leg) => Some((currency.clone(), leg.clone())),
+                Rate::Fixed(_) => None,
+            })
+            .collect();
+        legs.sort_by(|a, b| a.0.cmp(&b.0));
+        legs
+    }
+}
+
+/// Rate implied by `orderbook`, the latest book of `leg`: its mid price,
+/// inverted for an inverted leg.
+fn live_rate(leg: &Leg, orderbook: &Orderbook) -> Option<Decimal> {
+    let mid = orderbook.mid().filter(|mid| !mid.is_zero())?;
+    Some(if leg.inverse {
+        Decimal::one() / mid
+    } else {
+        mid
+    })
+}
+
+/// `orderbook` with its prices multiplied by `rate`, bids rounded down and
+/// asks up to its scale.
+pub fn convert(orderbook: &Orderbook, rate: Decimal) -> Orderbook {
+    let scale = orderbook.scale;
+    let convert = |level: &OrderbookLevel, rounding| {
+        let price = (scale.price(level.price) * rate)
+            .round_dp_with_strategy(scale.price_decimals, rounding);
+        scale
+            .ticks(price)
+            .map(|price| OrderbookLevel { price, ..*level })
+    };
+    let bids = orderbook
+        .bids
+        .iter()
+        .filter_map(|bid| convert(bid, RoundingStrategy::RoundDown))
+        .collect();
+    let asks = orderbook
+        .asks
+        .iter()
+        .filter_map(|ask| convert(ask, RoundingStrategy::RoundUp))
+        .collect();
+    Orderbook::from_bids_asks(scale, bids, asks)
+}
+
+/// Update of `exchange` with `orderbook` converted at `rate`, empty
+/// without a rate.
+fn converted(
+    exchange: Exchange,
+    orderbook: &Orderbook,
+    rate: Option<Decimal>,
+) -> OrderbookUpdateEvent {
+    match rate {
+        Some(rate) => OrderbookUpdateEvent {
+            quote_rate: Some(rate),
+            ..OrderbookUpdateEvent::new(exchange, convert(orderbook, rate))
+        },
+        None => OrderbookUpdateEvent::new(exchange, Orderbook::new()),
+    }
+}
+
+/// Run the conversion of the venues' books into the reference currency.
+///
+/// Forwards the updates received on `rx` to `tx`, converting those of the
+/// venues in `quotes`. `legs` receives the updates of the live rates'
+/// books, in the order of `Quotes::live_legs`. A venue is left out of the
+/// aggregate while the rate of its quote currency isn't known, and its
+/// book is converted again whenever the rate changes, as old as when it
+/// was received. Returns when the
+/// sender of `rx` is gone.
+pub async fn run(
+    quotes: Quotes,
+    mut rx: mpsc::Receiver<OrderbookUpdateEvent>,
+    legs: Vec<mpsc::Receiver<OrderbookUpdateEvent>>,
+    tx: mpsc::Sender<OrderbookUpdateEvent>,
+) -> crate::Result<()> {
+    let live_legs = quotes.live_legs();
+    let mut rates: HashMap<String, Decimal> = quotes
+        .rates
+        .iter()
+        .filter_map(|(currency, rate)| match rate {
+            Rate::Fixed(rate) => Some((currency.clone(), *rate)),
+            Rate::Live(_) => None,
+        })
+        .collect();
+    // Latest update of each converted venue, as quoted.
+    let mut books: HashMap<Exchange, OrderbookUpdateEvent> = HashMap::new();
+    let mut rate_updates = StreamMap::new();
+    for (leg, rx) in legs.into_iter().enumerate() {
+        rate_updates.insert(leg, ReceiverStream::new(rx));
+    }
+
+    loop {
+        tokio::select! {
+            msg = rx.recv() => {
+                let msg = match msg {
+                    Some(msg) => msg,
+                    None => return Ok(()),
+                };
+                let currency = match quotes.venues.get(&msg.exchange) {
+                    Some(currency) => currency,
+                    None => {
+                        tx.send(msg).await?;
+                        continue;
+                    }
+                };
+                let rate = rates.get(currency).copied();
+                if rate.is_some() || msg.orderbook.is_empty() {
+                    let update = OrderbookUpdateEvent {
+                        received_at: msg.received_at,
+                        ..converted(msg.exchange, &msg.orderbook, rate)
+                    };
+                    tx.send(update).await?;
+                }
+                books.insert(msg.exchange, msg);
+            }
+            Some((leg, msg)) = rate_updates.next() => {
+                let (currency, leg) = &live_legs[leg];
+                if let Err(err) = msg.validate() {
+                    warn!(%err, %leg, "rejected rate update");
+                    continue;
+                }
+                let rate = live_rate(leg, &msg.orderbook);
+                if rates.get(currency) == rate.as_ref() {
+                    continue;
+                }
+                match rate {
+                    Some(rate) => {
+                        debug!(%currency, %rate, "quote rate changed");
+                        rates.insert(currency.clone(), rate);
+                    }
+                    None => {
+                        info!(%currency, "quote rate unknown");
+                        rates.remove(currency);
+                    }
+                }
+                // The venue's book is as old as when it was received.
+                for (exchange, msg) in &books {
+                    if quotes.venues.get(exchange) == Some(currency) {
+                        let update = OrderbookUpdateEvent {
+                            received_at: msg.received_at,
+                            ..converted(*exchange, &msg.orderbook, rate)
+                        };
+                        tx.send(update).await?;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Run the conversion of the venues' trades into the reference currency.
+///
+/// Forwards the trades received on `rx` to `tx`, the prices of the venues
+/// in `quotes` multiplied by the rate their book was last aggregated at,
+/// see `Snapshot::quote_rates`, so that trades quote the same currency as
+/// the aggregated book. Trades of such a venue are dropped while its book
+/// isn't in the published aggregate. Returns when the sender of `rx` is
+/// gone.
+pub async fn run_trades(
+    quotes: Quotes,
+    mut rx: mpsc::Receiver<Trade>,
+    snapshots: watch::Receiver<Snapshot>,
+    tx: mpsc::Sender<Trade>,
+) -> crate::Result<()> {
+    while let Some(mut trade) = rx.recv().await {
+        if quotes.venues.contains_key(&trade.exchange) {
+            let rate = snapshots.borrow().quote_rates.get(&trade.exchange).copied();
+            match rate {
+                Some(rate) => trade.price *= rate,
+                None => {
+                    debug!(venue = ?trade.exchange, id = trade.id, "dropped trade without a quote rate");
+                    continue;
+                }
+            }
+        }
+        tx.send(trade).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+    use crate::aggregator::{self, CrossPolicy};
+    use crate::scale::Scale;
+    use crate::trade::Aggressor;
+    use std::time::Duration;
+    use tokio::time;
+
+    const SCALE: Scale = Scale::new(2, 2);
+
+    fn orderbook(exchange: Exchange, bid: Decimal, ask: Decimal) -> Orderbook {
+        Orderbook::from_levels(
+            SCALE,
+            exchange,
+            &[(SCALE.ticks(bid).unwrap(), 100)],
+            &[(SCALE.ticks(ask).unwrap(), 100)],
+        )
+    }
+
+    /// Book of the `Bitstamp:usdtusd` rate, in the leg's own scale.
+    fn rate_book(bid: Decimal, ask: Decimal) -> OrderbookUpdateEvent {
+        let scale = "Bitstamp:usdtusd".parse::<Leg>().unwrap().scale;
+        let usdtusd = Orderbook::from_levels(
+            scale,
+            Exchange::Bitstamp,
+            &[(scale.ticks(bid).unwrap(), 100)],
+            &[(scale.ticks(ask).unwrap(), 100)],
+        );
+        OrderbookUpdateEvent::new(Exchange::Bitstamp, usdtusd)
+    }
+
+    fn quotes(rate: &str) -> Quotes {
+        Quotes {
+            venues: [(Exchange::Binance, "usdt".to_owned())]
+                .iter()
+                .cloned()
+                .collect(),
+            rates: [("usdt".to_owned(), rate.parse().unwrap())]
+                .iter()
+                .cloned()
+                .collect(),
+            pairs: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn rates_are_parsed() {
+        assert_eq!("0.9998".parse(), Ok(Rate::Fixed(dec!(0.9998))));
+        let leg = "Bitstamp:usdtusd".parse().unwrap();
+        assert_eq!("Bitstamp:usdtusd".parse(), Ok(Rate::Live(leg)));
+        assert!("0".parse::<Rate>().is_err());
+        assert!("usd".parse::<Rate>().is_err());
+    }
+
+    #[test]
+    fn conversion_rounds_against_the_taker() {
+        let converted = convert(
+            &orderbook(Exchange::Binance, dec!(100), dec!(101)),
+            dec!(0.9999),
+        );
+        assert_eq!(converted.top_bid(), Some(dec!(99.99)));
+        assert_eq!(converted.top_ask(), Some(dec!(100.99)));
+        assert_eq!(converted.bids[0].size, 100);
+        assert_eq!(converted.asks[0].exchange, Exchange::Binance);
+    }
+
+    #[tokio::test]
+    async fn venues_are_converted_at_fixed_rates() {
+        let (raw_tx, raw_rx) = mpsc::channel(8);
+        let (tx, mut rx) = mpsc::channel(8);
+        tokio::spawn(run(quotes("0.5"), raw_rx, vec![], tx));
+
+        let binance = orderbook(Exchange::Binance, dec!(100), dec!(101));
+        raw_tx
+            .send(OrderbookUpdateEvent::new(Exchange::Binance, binance))
+            .await
+            .unwrap();
+        let update = rx.recv().await.unwrap();
+        assert_eq!(update.orderbook.top_bid(), Some(dec!(50)));
+        assert_eq!(update.quote_rate, Some(dec!(0.5)));
+
+        // Venues quoting the reference currency pass through.
+        let bitstamp = orderbook(Exchange::Bitstamp, dec!(100), dec!(101));
+        raw_tx
+            .send(OrderbookUpdateEvent::new(Exchange::Bitstamp, bitstamp))
+            .await
+            .unwrap();
+        let update = rx.recv().await.unwrap();
+        assert_eq!(update.orderbook.top_bid(), Some(dec!(100)));
+        assert_eq!(update.quote_rate, None);
+    }
+
+    #[tokio::test]
+    async fn live_rates_follow_their_book() {
+        let (raw_tx, raw_rx) = mpsc::channel(8);
+        let (leg_tx, leg_rx) = mpsc::channel(8);
+        let (tx, mut rx) = mpsc::channel(8);
+        tokio::spawn(run(quotes("Bitstamp:usdtusd"), raw_rx, vec![leg_rx], tx));
+
+        // Without a rate Binance is left out.
+        let binance = orderbook(Exchange::Binance, dec!(100), dec!(101));
+        raw_tx
+            .send(OrderbookUpdateEvent::new(Exchange::Binance, binance))
+            .await
+            .unwrap();
+        // The rate keeps the decimals of its own book, finer than the
+        // pair's.
+        leg_tx
+            .send(rate_book(dec!(0.9997), dec!(0.99991)))
+            .await
+            .unwrap();
+        let update = rx.recv().await.unwrap();
+        assert_eq!(update.quote_rate, Some(dec!(0.999805)));
+        assert_eq!(update.orderbook.top_bid(), Some(dec!(99.98)));
+        assert_eq!(update.orderbook.top_ask(), Some(dec!(100.99)));
+
+        leg_tx
+            .send(rate_book(dec!(0.98), dec!(0.98)))
+            .await
+            .unwrap();
+        let update = rx.recv().await.unwrap();
+        assert_eq!(update.exchange, Exchange::Binance);
+        assert_eq!(update.orderbook.top_ask(), Some(dec!(98.98)));
+
+        // Losing the rate removes Binance from the aggregate.
+        leg_tx
+            .send(OrderbookUpdateEvent::new(
+                Exchange::Bitstamp,
+                Orderbook::new(),
+            ))
+            .await
+            .unwrap();
+        assert!(rx.recv().await.unwrap().orderbook.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rate_change_keeps_stale_venue_removed() {
+        let (raw_tx, raw_rx) = mpsc::channel(8);
+        let (leg_tx, leg_rx) = mpsc::channel(8);
+        let (tx, rx) = mpsc::channel(8);
+        let (snapshot_tx, mut snapshot_rx) = watch::channel(Snapshot::default());
+        let max_age = Duration::from_secs(5);
+        tokio::spawn(run(quotes("Bitstamp:usdtusd"), raw_rx, vec![leg_rx], tx));
+        tokio::spawn(aggregator::run(
+            "btcusd".to_owned(),
+            rx,
+            snapshot_tx,
+            max_age,
+            CrossPolicy::Flag,
+        ));
+        let bitstamp = || {
+            let bitstamp = orderbook(Exchange::Bitstamp, dec!(100), dec!(101));
+            OrderbookUpdateEvent::new(Exchange::Bitstamp, bitstamp)
+        };
+
+        leg_tx
+            .send(rate_book(dec!(0.99), dec!(1.01)))
+            .await
+            .unwrap();
+        time::sleep(Duration::from_millis(1)).await;
+        let binance = orderbook(Exchange::Binance, dec!(100), dec!(101));
+        raw_tx
+            .send(OrderbookUpdateEvent::new(Exchange::Binance, binance))
+            .await
+            .unwrap();
+        raw_tx.send(bitstamp()).await.unwrap();
+        snapshot_rx.changed().await.unwrap();
+        assert_eq!(snapshot_rx.borrow_and_update().quote_rates.len(), 1);
+
+        // Binance goes silent, Bitstamp keeps updating.
+        for _ in 0..6 {
+            time::sleep(Duration::from_secs(2)).await;
+            raw_tx.send(bitstamp()).await.unwrap();
+        }
+        time::sleep(Duration::from_millis(1)).await;
+        assert!(snapshot_rx.borrow_and_update().quote_rates.is_empty());
+
+        // The new rate converts Binance's old book again, which stays out.
+        leg_tx.send(rate_book(dec!(0.98), dec!(1))).await.unwrap();
+        time::sleep(Duration::from_millis(1)).await;
+        raw_tx.send(bitstamp()).await.unwrap();
+        snapshot_rx.changed().await.unwrap();
+        let snapshot = snapshot_rx.borrow_and_update().clone();
+        assert!(snapshot.quote_rates.is_empty());
+        assert!(snapshot
+            .orderbook
+            .bids
+            .iter()
+            .all(|level| level.exchange == Exchange::Bitstamp));
+    }
+
+    #[tokio::test]
+    async fn trades_are_converted_at_the_aggregated_rate() {
+        let (snapshot_tx, snapshot_rx) = watch::channel(Snapshot::default());
+        let (raw_tx, raw_rx) = mpsc::channel(8);
+        let (tx, mut rx) = mpsc::channel(8);
+        tokio::spawn(run_trades(quotes("0.5"), raw_rx, snapshot_rx, tx));
+
+        let trade = |exchange, id| Trade {
+            exchange,
+            id,
+            price: dec!(100.5),
+            size: dec!(2),
+            aggressor: Aggressor::Buy,
+            traded_at: 1,
+            received_at: 2,
+        };
+        // Binance isn't aggregated yet, so its trade is dropped.
+        raw_tx.send(trade(Exchange::Binance, 1)).await.unwrap();
+        raw_tx.send(trade(Exchange::Bitstamp, 2)).await.unwrap();
+        let received = rx.recv().await.unwrap();
+        assert_eq!((received.id, received.price), (2, dec!(100.5)));
+
+        snapshot_tx
+            .send(Snapshot {
+                quote_rates: [(Exchange::Binance, dec!(0.9998))]
+                    .iter()
+                    .cloned()
+                    .collect(),
+                ..Snapshot::default()
+            })
+            .unwrap();
+        raw_tx.send(trade(Exchange::Binance, 3)).await.unwrap();
+        let received = rx.recv().await.unwrap();
+        assert_eq!((received.id, received.price), (3, dec!(100.47990)));
+        assert_eq!(received.size, dec!(2));
+    }
+}
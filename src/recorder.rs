@@ -0,0 +1,292 @@
+//! # recorder
+//!
+//! Records the raw websocket messages of the exchanges to disk so they can
+//! be replayed later, see `replay`.
+//!
+//! A recording starts with the `MAGIC` header followed by one record per
+//! message:
+//!
+//! | bytes | content                                           |
+//! |-------|---------------------------------------------------|
+//! | 8     | receive time, microseconds since the Unix epoch   |
+//! | 1     | exchange, see `exchange_id`                       |
+//! | 4     | payload length                                    |
+//! | n     | payload, the message text as received             |
+//!
+//! All integers are little endian. Recordings are only ever appended to,
+//! so the same file can be reused across restarts.
+use std::convert::TryInto;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use tokio::fs::OpenOptions;
+use tokio::io::{AsyncWrite, AsyncWriteExt, BufWriter};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tracing::warn;
+
+use crate::error::Error;
+use crate::order_book::Exchange;
+
+/// Header identifying a recording and its format version.
+pub const MAGIC: &[u8; 6] = b"OBREC\x01";
+
+/// Size of a record without its payload.
+const RECORD_HEADER_LEN: usize = 8 + 1 + 4;
+
+/// Raw message received from an exchange.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    /// Receive time in microseconds since the Unix epoch.
+    pub timestamp: u64,
+    pub exchange: Exchange,
+    /// The message text as received.
+    pub payload: String,
+}
+
+impl Frame {
+    /// Create a frame received from `exchange` just now.
+    pub fn now(exchange: Exchange, payload: &str) -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_micros() as u64);
+        Self {
+            timestamp,
+            exchange,
+            payload: payload.to_owned(),
+        }
+    }
+
+    /// Append the encoded record to `buf`.
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.timestamp.to_le_bytes());
+        buf.push(exchange_id(self.exchange));
+        buf.extend_from_slice(&(self.payload.len() as u32).to_le_bytes());
+        buf.extend_from_slice(self.payload.as_bytes());
+    }
+}
+
+/// Stable on-disk identifier of `exchange`.
+fn exchange_id(exchange: Exchange) -> u8 {
+    match exchange {
+        Exchange::Unknown => 0,
+        Exchange::Binance => 1,
+        Exchange::Bitstamp => 2,
+        Exchange::Synthetic => 3,
+    }
+}
+
+fn exchange_from_id(id: u8) -> Option<Exchange> {
+    match id {
+        0 => Some(Exchange::Unknown),
+        1 => Some(Exchange::Binance),
+        2 => Some(Exchange::Bitstamp),
+        3 => Some(Exchange::Synthetic),
+        _ => None,
+    }
+}
+
+/// Decode a whole recording.
+///
+/// A truncated last record, as left behind by a crash mid-write, is
+/// skipped with a warning.
+pub fn decode(bytes: &[u8]) -> crate::Result<Vec<Frame>> {
+    let (frames, complete) = decode_complete(bytes)?;
+    if complete < bytes.len() {
+        warn!(
+            frames = frames.len(),
+            "recording ends with a truncated record"
+        );
+    }
+    Ok(frames)
+}
+
+/// Decode the complete records of a recording, returning them with the
+/// length of the recording up to the end of the last one.
+fn decode_complete(mut bytes: &[u8]) -> crate::Result<(Vec<Frame>, usize)> {
+    if !bytes.starts_with(MAGIC) {
+        return Err(Error::Recording("missing header".to_owned()));
+    }
+    bytes = &bytes[MAGIC.len()..];
+
+    let mut frames = Vec::new();
+    let mut complete = MAGIC.len();
+    while bytes.len() >= RECORD_HEADER_LEN {
+        let timestamp = u64::from_le_bytes(bytes[..8].try_into().unwrap());
+        let exchange = exchange_from_id(bytes[8]).ok_or_else(|| {
+            Error::Recording(format!(
+                "unknown exchange {} in record {}",
+                bytes[8],
+                frames.len()
+            ))
+        })?;
+        let len = u32::from_le_bytes(bytes[9..13].try_into().unwrap()) as usize;
+        let payload = match bytes.get(RECORD_HEADER_LEN..RECORD_HEADER_LEN + len) {
+            Some(payload) => payload,
+            None => break,
+        };
+        let payload = String::from_utf8(payload.to_vec()).map_err(|err| {
+            Error::Recording(format!(
+                "invalid payload in record {}: {}",
+                frames.len(),
+                err
+            ))
+        })?;
+        frames.push(Frame {
+            timestamp,
+            exchange,
+            payload,
+        });
+        bytes = &bytes[RECORD_HEADER_LEN + len..];
+        complete += RECORD_HEADER_LEN + len;
+    }
+    Ok((frames, complete))
+}
+
+/// Read the recording at `path`.
+pub async fn read(path: impl AsRef<Path>) -> crate::Result<Vec<Frame>> {
+    let bytes = tokio::fs::read(path).await?;
+    decode(&bytes)
+}
+
+/// Handle used by the connectors to record their messages.
+///
+/// Recording never blocks the connector; frames are queued and written by
+/// a background task, which finishes once every handle is dropped.
+#[derive(Debug, Clone)]
+pub struct Recorder {
+    tx: mpsc::UnboundedSender<Frame>,
+}
+
+impl Recorder {
+    /// Open `path` for appending, creating it if needed, and spawn the
+    /// task writing the recorded frames to it.
+    ///
+    /// A truncated last record left behind by a crash is cut off first, so
+    /// the new records follow the last complete one.
+    pub async fn open(
+        path: impl AsRef<Path>,
+    ) -> crate::Result<(Recorder, JoinHandle<crate::Result<()>>)> {
+        let path = path.as_ref();
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?;
+        let bytes = tokio::fs::read(path).await?;
+        if MAGIC.starts_with(&bytes) {
+            // Empty, or crashed while writing the header.
+            file.set_len(0).await?;
+            file.write_all(MAGIC).await?;
+        } else {
+            let (frames, complete) = decode_complete(&bytes)?;
+            if complete < bytes.len() {
+                warn!(
+                    frames = frames.len(),
+                    truncated = bytes.len() - complete,
+                    "cutting off a truncated record"
+                );
+                file.set_len(complete as u64).await?;
+            }
+        }
+        let (tx, rx) = mpsc::unbounded_channel();
+        let handle = tokio::spawn(write(BufWriter::new(file), rx));
+        Ok((Recorder { tx }, handle))
+    }
+
+    /// Record `payload` received from `exchange` just now.
+    pub fn record(&self, exchange: Exchange, payload: &str) {
+        // A failed writer is reported through its task handle.
+        let _ = self.tx.send(Frame::now(exchange, payload));
+    }
+}
+
+/// Write frames received on `rx` to `out`, flushing whenever the queue
+/// runs empty.
+async fn write<W>(mut out: W, mut rx: mpsc::UnboundedReceiver<Frame>) -> crate::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = Vec::new();
+    while let Some(frame) = rx.recv().await {
+        buf.clear();
+        frame.encode(&mut buf);
+        out.write_all(&buf).await?;
+        if rx.is_empty() {
+            out.flush().await?;
+        }
+    }
+    out.flush().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(timestamp: u64, exchange: Exchange, payload: &str) -> Frame {
+        Frame {
+            timestamp,
+            exchange,
+            payload: payload.to_owned(),
+        }
+    }
+
+    #[test]
+    fn frames_round_trip() {
+        let frames = vec![
+            frame(1, Exchange::Binance, r#"{"lastUpdateId":1}"#),
+            frame(2, Exchange::Bitstamp, ""),
+        ];
+        let mut buf = MAGIC.to_vec();
+        for frame in &frames {
+            frame.encode(&mut buf);
+        }
+        assert_eq!(decode(&buf).unwrap(), frames);
+
+        // Losing the end of the last record loses only that record.
+        buf.truncate(buf.len() - 1);
+        assert_eq!(decode(&buf).unwrap(), frames[..1]);
+        assert!(matches!(decode(b"{}"), Err(Error::Recording(_))));
+    }
+
+    #[tokio::test]
+    async fn recordings_are_appended() {
+        let path = std::env::temp_dir().join(format!("recorder-{}.rec", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        for payload in &["first", "second"] {
+            let (recorder, writer) = Recorder::open(&path).await.unwrap();
+            recorder.record(Exchange::Bitstamp, payload);
+            drop(recorder);
+            writer.await.unwrap().unwrap();
+        }
+
+        let frames = read(&path).await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let payloads: Vec<&str> = frames.iter().map(|f| f.payload.as_str()).collect();
+        assert_eq!(payloads, vec!["first", "second"]);
+        assert!(frames.iter().all(|f| f.exchange == Exchange::Bitstamp));
+        assert!(frames[0].timestamp <= frames[1].timestamp);
+    }
+
+    #[tokio::test]
+    async fn truncated_record_is_cut_off_on_open() {
+        let path = std::env::temp_dir().join(format!("recorder-torn-{}.rec", std::process::id()));
+        let mut buf = MAGIC.to_vec();
+        frame(1, Exchange::Binance, "first").encode(&mut buf);
+        let complete = buf.len();
+        frame(2, Exchange::Binance, "torn").encode(&mut buf);
+        std::fs::write(&path, &buf[..complete + RECORD_HEADER_LEN + 2]).unwrap();
+
+        let (recorder, writer) = Recorder::open(&path).await.unwrap();
+        recorder.record(Exchange::Bitstamp, "second");
+        drop(recorder);
+        writer.await.unwrap().unwrap();
+
+        let frames = read(&path).await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let payloads: Vec<&str> = frames.iter().map(|f| f.payload.as_str()).collect();
+        assert_eq!(payloads, vec!["first", "second"]);
+    }
+}
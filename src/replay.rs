@@ -0,0 +1,273 @@
+//! # replay
+//!
+//! Feeds a recording made by `recorder` through the same parsers as the
+//! live connectors, so the aggregator sees the updates it saw while
+//! recording and publishes the same summaries.
+use std::str::FromStr;
+use std::time::Duration;
+
+use tokio::sync::mpsc::Sender;
+use tokio::time::{self, Instant};
+use tracing::{info, warn};
+
+use crate::binance;
+use crate::bitstamp;
+use crate::error::Error;
+use crate::order_book::{Exchange, Orderbook, OrderbookUpdateEvent};
+use crate::recorder::Frame;
+use crate::scale::Scale;
+
+/// How fast a recording is replayed.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Speed {
+    /// Keep the recorded spacing of the frames divided by the factor, so
+    /// `1.0` is the original speed and `10.0` ten times faster.
+    Factor(f64),
+    /// Send frames as fast as the aggregator takes them.
+    Max,
+}
+
+impl FromStr for Speed {
+    type Err = String;
+
+    /// Parse either `max` or a positive speed factor.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("max") {
+            return Ok(Speed::Max);
+        }
+        match s.parse::<f64>() {
+            Ok(factor) if factor > 0.0 && factor.is_finite() => Ok(Speed::Factor(factor)),
+            _ => Err(format!("expected `max` or a positive number, got `{}`", s)),
+        }
+    }
+}
+
+/// Parser state of every venue, as if each had one live connection.
+#[derive(Debug)]
+struct Parsers {
+    scale: Scale,
+    binance: binance::Parser,
+    bitstamp: bitstamp::Parser,
+}
+
+impl Parsers {
+    fn new(scale: Scale) -> Self {
+        Self {
+            scale,
+            binance: binance::Parser::new(scale),
+            bitstamp: bitstamp::Parser::new(scale),
+        }
+    }
+
+    fn parse(&mut self, exchange: Exchange, msg: &str) -> crate::Result<Option<Orderbook>> {
+        match exchange {
+            Exchange::Binance => self.binance.parse(msg),
+            Exchange::Bitstamp => self.bitstamp.parse(msg),
+            Exchange::Unknown => Err(Error::Recording("frame of unknown exchange".to_owned())),
+            // Implied books are derived, never received.
+            Exchange::Synthetic => Err(Error::Recording("frame of synthetic book".to_owned())),
+        }
+    }
+
+    /// Start over as a reconnected connector would.
+    fn reset(&mut self, exchange: Exchange) {
+        match exchange {
+            Exchange::Binance => self.binance = binance::Parser::new(self.scale),
+            Exchange::Bitstamp => self.bitstamp = bitstamp::Parser::new(self.scale),
+            Exchange::Unknown | Exchange::Synthetic => {}
+        }
+    }
+}
+
+/// Replay `frames` at `speed`, sending the updates parsed in ticks and
+/// lots of `scale` on `tx`.
+///
+/// A frame the live connector would have failed on is handled the way the
+/// supervisor handles the failure: the venue is cleared from the aggregate
+/// and its parser starts over. Returns once every frame was sent.
+pub async fn run(
+    frames: Vec<Frame>,
+    scale: Scale,
+    speed: Speed,
+    tx: Sender<OrderbookUpdateEvent>,
+) -> crate::Result<()> {
+    let mut parsers = Parsers::new(scale);
+    let started_at = Instant::now();
+    let first_timestamp = frames.first().map_or(0, |frame| frame.timestamp);
+    info!(frames = frames.len(), ?speed, "replaying recording");
+
+    for frame in frames {
+        if let Speed::Factor(factor) = speed {
+            let offset = Duration::from_micros(frame.timestamp.saturating_sub(first_timestamp));
+            time::sleep_until(started_at + offset.div_f64(factor)).await;
+        }
+        match parsers.parse(frame.exchange, &frame.payload) {
+            Ok(Some(orderbook)) => {
+                tx.send(OrderbookUpdateEvent::new(frame.exchange, orderbook))
+                    .await?
+            }
+            Ok(None) => {}
+            Err(err) => {
+                warn!(%err, venue = %frame.exchange, "replayed connector failed");
+                parsers.reset(frame.exchange);
+                tx.send(OrderbookUpdateEvent::new(frame.exchange, Orderbook::new()))
+                    .await?;
+            }
+        }
+    }
+    info!("replay finished");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use ntest::timeout;
+    use rust_decimal_macros::dec;
+    use tokio::sync::{mpsc, watch};
+
+    use super::*;
+    use crate::aggregator::Aggregator;
+    use crate::mock_exchange::binance::depth;
+    use crate::mock_exchange::bitstamp::{order_book, subscription_succeeded};
+    use crate::mock_exchange::{MockExchange, Script};
+    use crate::proto::Summary;
+    use crate::recorder::{self, Recorder};
+    use crate::shutdown::Shutdown;
+
+    /// Summaries the aggregator produces for `updates`, one per update.
+    fn summaries(updates: Vec<OrderbookUpdateEvent>) -> Vec<Summary> {
+        let mut aggregator = Aggregator::new();
+        updates
+            .into_iter()
+            .map(|update| {
+                aggregator.update(update.exchange, update.orderbook);
+                Summary::from(aggregator.aggregate())
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    #[timeout(5000)]
+    async fn replay_matches_live_run() {
+        let gap = Duration::from_millis(60);
+        let binance = MockExchange::start(vec![Script::new()
+            .text(depth(1, &[("0.061", "2")], &[("0.063", "1")]))
+            .delay(gap)
+            .text(depth(2, &[("0.0615", "1")], &[("0.063", "2")]))
+            .delay(gap)
+            .text(depth(3, &[("0.061", "1")], &[("0.0625", "3")]))])
+        .await
+        .unwrap();
+        let bitstamp = MockExchange::start(vec![Script::new()
+            .expect_text()
+            .text(subscription_succeeded("ethbtc"))
+            .delay(gap / 2)
+            .text(order_book(
+                "ethbtc",
+                1,
+                &[("0.0612", "1")],
+                &[("0.0628", "1")],
+            ))
+            .delay(gap)
+            .text(order_book(
+                "ethbtc",
+                2,
+                &[("0.0618", "4")],
+                &[("0.064", "1")],
+            ))])
+        .await
+        .unwrap();
+
+        let path = std::env::temp_dir().join(format!("replay-{}.rec", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let (recorder, writer) = Recorder::open(&path).await.unwrap();
+        let (notify_shutdown, shutdown) = watch::channel(false);
+        let (tx, mut rx) = mpsc::channel(8);
+        let connectors = vec![
+            tokio::spawn({
+                let (url, tx, recorder) = (binance.url(), tx.clone(), recorder.clone());
+                let shutdown = Shutdown::new(shutdown.clone());
+                async move {
+                    binance::run(
+                        &url,
+                        "ethbtc",
+                        Scale::default(),
+                        tx,
+                        Some(recorder),
+                        shutdown,
+                    )
+                    .await
+                }
+            }),
+            tokio::spawn({
+                let (url, recorder) = (bitstamp.url(), recorder);
+                let shutdown = Shutdown::new(shutdown);
+                async move {
+                    bitstamp::run(
+                        &url,
+                        "ethbtc",
+                        Scale::default(),
+                        tx,
+                        Some(recorder),
+                        shutdown,
+                    )
+                    .await
+                }
+            }),
+        ];
+        let mut live = Vec::new();
+        while live.len() < 5 {
+            live.push(rx.recv().await.unwrap());
+        }
+        notify_shutdown.send(true).unwrap();
+        for connector in connectors {
+            connector.await.unwrap().unwrap();
+        }
+        writer.await.unwrap().unwrap();
+
+        let frames = recorder::read(&path).await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(frames.len(), 6);
+        let (tx, mut rx) = mpsc::channel(8);
+        tokio::spawn(run(frames, Scale::default(), Speed::Max, tx));
+        let mut replayed = Vec::new();
+        while let Some(update) = rx.recv().await {
+            replayed.push(update);
+        }
+
+        let live = summaries(live);
+        assert_eq!(live.last().unwrap().bids[0].price, 0.0618);
+        assert_eq!(summaries(replayed), live);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn accelerated_replay_keeps_relative_timing() {
+        let frames = vec![0, 1_000_000, 3_000_000]
+            .into_iter()
+            .enumerate()
+            .map(|(id, timestamp)| Frame {
+                timestamp,
+                exchange: Exchange::Binance,
+                payload: depth(id as u64, &[("1", "1")], &[("2", "1")]),
+            })
+            .collect();
+        let (tx, mut rx) = mpsc::channel(8);
+        let started_at = Instant::now();
+        tokio::spawn(run(frames, Scale::default(), "2".parse().unwrap(), tx));
+
+        let mut offsets = Vec::new();
+        while let Some(update) = rx.recv().await {
+            assert_eq!(update.orderbook.top_bid(), Some(dec!(1)));
+            offsets.push(started_at.elapsed().as_millis());
+        }
+        assert_eq!(offsets, vec![0, 500, 1500]);
+    }
+
+    #[test]
+    fn speed_from_str() {
+        assert_eq!(Speed::from_str("max").unwrap(), Speed::Max);
+        assert_eq!(Speed::from_str("1").unwrap(), Speed::Factor(1.0));
+        assert!(Speed::from_str("0").is_err());
+        assert!(Speed::from_str("fast").is_err());
+    }
+}
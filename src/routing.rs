@@ -0,0 +1,278 @@
+//! # routing
+//!
+//! Splits an order across venues by walking the aggregated orderbook, so
+//! that each venue gets a child order for the liquidity it contributes
+//! at the best prices.
+use std::collections::{HashMap, HashSet};
+
+use rust_decimal::prelude::*;
+
+use crate::config::venue_decimals;
+use crate::order_book::{Exchange, LevelSide, Orderbook};
+
+/// Smallest order each venue accepts, in the base currency.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MinOrderSizes(pub HashMap<Exchange, Decimal>);
+
+impl MinOrderSizes {
+    /// Read the sizes from the `MIN_ORDER_SIZES` environment variable, a
+    /// comma-separated list of `Venue=size` pairs, e.g.
+    /// `Binance=0.0001,Bitstamp=0.001`. Venues not listed have no minimum.
+    pub fn from_env() -> crate::Result<Self> {
+        match std::env::var("MIN_ORDER_SIZES") {
+            Ok(sizes) => venue_decimals("MIN_ORDER_SIZES", &sizes).map(Self),
+            Err(_) => Ok(Self::default()),
+        }
+    }
+
+    /// Minimum order size of `exchange`, zero if it has none.
+    pub fn get(&self, exchange: Exchange) -> Decimal {
+        self.0.get(&exchange).copied().unwrap_or_else(Decimal::zero)
+    }
+}
+
+/// Order sent to one venue as part of a `Plan`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChildOrder {
+    pub exchange: Exchange,
+    /// Base quantity.
+    pub quantity: Decimal,
+    /// Worst price of the venue's levels the order takes.
+    pub limit_price: Decimal,
+}
+
+/// Child orders splitting one order across venues.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Plan {
+    /// One order per venue, the venue offering the best price first.
+    pub orders: Vec<ChildOrder>,
+    /// Base quantity covered by `orders`.
+    pub quantity: Decimal,
+    /// Whether the whole quantity is covered.
+    pub complete: bool,
+}
+
+/// Split an order for `quantity` of the base currency across the venues
+/// of `orderbook`, a full-depth aggregate.
+///
+/// `side` is the side of the book the order takes: the asks for a buy,
+/// the bids for a sell. Levels are taken best price first, whichever venue
+/// they belong to, except the `Synthetic` venue's, which can't be traded
+/// on directly. A venue whose child order would be smaller than its
+/// minimum order size is left out and its share taken from the next best
+/// levels of the other venues. Venues are left out one at a time, the one
+/// with the worst limit price first, as the shares moved to the others may
+/// lift them over their minimums.
+pub fn plan(
+    orderbook: &Orderbook,
+    side: LevelSide,
+    quantity: Decimal,
+    min_sizes: &MinOrderSizes,
+) -> Plan {
+    let mut excluded = HashSet::new();
+    loop {
+        let plan = walk(orderbook, side, quantity, &excluded);
+        let worst = plan
+            .orders
+            .iter()
+            .filter(|order| order.quantity < min_sizes.get(order.exchange))
+            .max_by(|a, b| match side {
+                LevelSide::Bid => b.limit_price.cmp(&a.limit_price),
+                LevelSide::Ask => a.limit_price.cmp(&b.limit_price),
+            });
+        match worst {
+            Some(order) => excluded.insert(order.exchange),
+            None => return plan,
+        };
+    }
+}
+
+/// Take `quantity` from the levels of `side` of all but the `excluded`
+/// venues.
+fn walk(
+    orderbook: &Orderbook,
+    side: LevelSide,
+    quantity: Decimal,
+    excluded: &HashSet<Exchange>,
+) -> Plan {
+    let levels = match side {
+        LevelSide::Bid => &orderbook.bids[..],
+        LevelSide::Ask => &orderbook.asks[..],
+    };
+    let mut plan = Plan::default();
+    for level in levels {
+        let remaining = quantity - plan.quantity;
+        if remaining <= Decimal::zero() {
+            break;
+        }
+        if level.exchange == Exchange::Synthetic || excluded.contains(&level.exchange) {
+            continue;
+        }
+        let taken = remaining.min(orderbook.scale.size(level.size));
+        let price = orderbook.scale.price(level.price);
+        plan.quantity += taken;
+        match plan
+            .orders
+            .iter_mut()
+            .find(|order| order.exchange == level.exchange)
+        {
+            Some(order) => {
+                order.quantity += taken;
+                order.limit_price = price;
+            }
+            None => plan.orders.push(ChildOrder {
+                exchange: level.exchange,
+                quantity: taken,
+                limit_price: price,
+            }),
+        }
+    }
+    plan.complete = plan.quantity >= quantity;
+    plan
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+    use crate::order_book::OrderbookLevel;
+    use crate::scale::Scale;
+
+    const SCALE: Scale = Scale::new(2, 2);
+
+    fn ask(price: Decimal, size: Decimal, exchange: Exchange) -> OrderbookLevel {
+        OrderbookLevel::ask(
+            SCALE.ticks(price).unwrap(),
+            SCALE.lots(size).unwrap(),
+            exchange,
+        )
+    }
+
+    fn orderbook() -> Orderbook {
+        Orderbook::from_bids_asks(
+            SCALE,
+            vec![],
+            vec![
+                ask(dec!(100), dec!(1), Exchange::Binance),
+                ask(dec!(101), dec!(0.5), Exchange::Bitstamp),
+                ask(dec!(102), dec!(2), Exchange::Binance),
+                ask(dec!(103), dec!(5), Exchange::Bitstamp),
+            ],
+        )
+    }
+
+    fn order(exchange: Exchange, quantity: Decimal, limit_price: Decimal) -> ChildOrder {
+        ChildOrder {
+            exchange,
+            quantity,
+            limit_price,
+        }
+    }
+
+    #[test]
+    fn orders_follow_best_prices() {
+        let routed = plan(
+            &orderbook(),
+            LevelSide::Ask,
+            dec!(3),
+            &MinOrderSizes::default(),
+        );
+        assert_eq!(
+            routed.orders,
+            vec![
+                order(Exchange::Binance, dec!(2.5), dec!(102)),
+                order(Exchange::Bitstamp, dec!(0.5), dec!(101)),
+            ]
+        );
+        assert_eq!(routed.quantity, dec!(3));
+        assert!(routed.complete);
+    }
+
+    #[test]
+    fn venues_below_minimum_are_left_out() {
+        let min_sizes = MinOrderSizes([(Exchange::Bitstamp, dec!(1))].iter().cloned().collect());
+        let routed = plan(&orderbook(), LevelSide::Ask, dec!(3), &min_sizes);
+        assert_eq!(
+            routed.orders,
+            vec![order(Exchange::Binance, dec!(3), dec!(102))]
+        );
+
+        // A larger order leaves Bitstamp a large enough share.
+        let routed = plan(&orderbook(), LevelSide::Ask, dec!(5), &min_sizes);
+        assert_eq!(
+            routed.orders,
+            vec![
+                order(Exchange::Binance, dec!(3), dec!(102)),
+                order(Exchange::Bitstamp, dec!(2), dec!(103)),
+            ]
+        );
+    }
+
+    #[test]
+    fn venues_below_minimum_are_left_out_one_at_a_time() {
+        let orderbook = Orderbook::from_bids_asks(
+            SCALE,
+            vec![],
+            vec![
+                ask(dec!(100), dec!(1), Exchange::Binance),
+                ask(dec!(101), dec!(1), Exchange::Bitstamp),
+                ask(dec!(102), dec!(5), Exchange::Binance),
+            ],
+        );
+        let min_sizes = MinOrderSizes(
+            [
+                (Exchange::Binance, dec!(1.5)),
+                (Exchange::Bitstamp, dec!(1.5)),
+            ]
+            .iter()
+            .cloned()
+            .collect(),
+        );
+        // Both venues start below their minimums, leaving out Bitstamp
+        // alone lifts Binance over its own.
+        let routed = plan(&orderbook, LevelSide::Ask, dec!(2), &min_sizes);
+        assert_eq!(
+            routed.orders,
+            vec![order(Exchange::Binance, dec!(2), dec!(102))]
+        );
+        assert!(routed.complete);
+    }
+
+    #[test]
+    fn synthetic_levels_are_not_routed() {
+        let mut asks = orderbook().asks.to_vec();
+        asks.push(ask(dec!(99), dec!(10), Exchange::Synthetic));
+        let orderbook = Orderbook::from_bids_asks(SCALE, vec![], asks);
+        let routed = plan(
+            &orderbook,
+            LevelSide::Ask,
+            dec!(1),
+            &MinOrderSizes::default(),
+        );
+        assert_eq!(
+            routed.orders,
+            vec![order(Exchange::Binance, dec!(1), dec!(100))]
+        );
+    }
+
+    #[test]
+    fn thin_book_is_incomplete() {
+        let routed = plan(
+            &orderbook(),
+            LevelSide::Ask,
+            dec!(10),
+            &MinOrderSizes::default(),
+        );
+        assert_eq!(routed.quantity, dec!(8.5));
+        assert!(!routed.complete);
+        assert!(plan(
+            &orderbook(),
+            LevelSide::Bid,
+            dec!(1),
+            &MinOrderSizes::default()
+        )
+        .orders
+        .is_empty());
+    }
+}
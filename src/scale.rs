@@ -0,0 +1,204 @@
+//! # scale
+//!
+//! Fixed-point representation of prices and sizes. Orderbooks keep prices
+//! as integer ticks and sizes as integer lots of the instrument's `Scale`,
+//! which compare and sort much faster than `Decimal`. They are converted
+//! to `Decimal` only at the API boundaries.
+use std::str::FromStr;
+
+use rust_decimal::prelude::*;
+
+use crate::error::Error;
+
+/// Largest supported number of decimals, so that one unit fits an `i64`.
+const MAX_DECIMALS: u32 = 18;
+
+/// Number of decimals of an instrument's prices and sizes.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Scale {
+    /// A price tick is `10^-price_decimals`.
+    pub price_decimals: u32,
+    /// A size lot is `10^-size_decimals`.
+    pub size_decimals: u32,
+}
+
+impl Scale {
+    pub const fn new(price_decimals: u32, size_decimals: u32) -> Self {
+        Self {
+            price_decimals,
+            size_decimals,
+        }
+    }
+
+    /// Read the scale of the served pair from the `PRICE_DECIMALS` and
+    /// `SIZE_DECIMALS` environment variables, defaulting to
+    /// `Scale::default()`.
+    pub fn from_env() -> crate::Result<Self> {
+        let mut scale = Self::default();
+        for (name, decimals) in &mut [
+            ("PRICE_DECIMALS", &mut scale.price_decimals),
+            ("SIZE_DECIMALS", &mut scale.size_decimals),
+        ] {
+            if let Ok(value) = std::env::var(*name) {
+                let value: u32 = value.parse().map_err(|err| Error::config(name, err))?;
+                if value > MAX_DECIMALS {
+                    let reason = format!("at most {} decimals are supported", MAX_DECIMALS);
+                    return Err(Error::config(name, reason));
+                }
+                **decimals = value;
+            }
+        }
+        Ok(scale)
+    }
+
+    /// Price of `ticks`.
+    pub fn price(&self, ticks: i64) -> Decimal {
+        Decimal::new(ticks, self.price_decimals)
+    }
+
+    /// Size of `lots`.
+    pub fn size(&self, lots: i64) -> Decimal {
+        Decimal::new(lots, self.size_decimals)
+    }
+
+    /// Ticks of `price`, `None` if it has more decimals than the scale or
+    /// is out of range.
+    pub fn ticks(&self, price: Decimal) -> Option<i64> {
+        to_fixed(price, self.price_decimals)
+    }
+
+    /// Lots of `size`, `None` if it has more decimals than the scale or is
+    /// out of range.
+    pub fn lots(&self, size: Decimal) -> Option<i64> {
+        to_fixed(size, self.size_decimals)
+    }
+}
+
+impl FromStr for Scale {
+    type Err = String;
+
+    /// Parse `price_decimals/size_decimals`, e.g. `2/8`.
+    fn from_str(scale: &str) -> Result<Self, Self::Err> {
+        let (price, size) = scale
+            .split_once('/')
+            .ok_or_else(|| format!("expected `price_decimals/size_decimals`, got `{}`", scale))?;
+        let decimals = |decimals: &str| match decimals.parse() {
+            Ok(decimals) if decimals <= MAX_DECIMALS => Ok(decimals),
+            _ => Err(format!(
+                "expected at most {} decimals, got `{}`",
+                MAX_DECIMALS, decimals
+            )),
+        };
+        Ok(Self::new(decimals(price)?, decimals(size)?))
+    }
+}
+
+impl Default for Scale {
+    /// Binance and Bitstamp quote spot pairs with at most 8 decimals.
+    fn default() -> Self {
+        Self::new(8, 8)
+    }
+}
+
+/// `value` in units of `10^-decimals`.
+pub(crate) fn to_fixed(value: Decimal, decimals: u32) -> Option<i64> {
+    let unit = Decimal::from_i64(10i64.checked_pow(decimals)?)?;
+    let fixed = value.checked_mul(unit)?;
+    if fixed.fract().is_zero() {
+        fixed.to_i64()
+    } else {
+        None
+    }
+}
+
+/// Parse a decimal string such as `"-0.0625"` straight into units of
+/// `10^-decimals`.
+///
+/// Extra fractional digits are accepted only if they are zeros, so that
+/// the value is never rounded. Returns `None` for malformed input or
+/// values out of range.
+pub(crate) fn parse_fixed(value: &str, decimals: u32) -> Option<i64> {
+    let (negative, digits) = match value.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, value),
+    };
+    let (int, frac) = digits.split_once('.').unwrap_or((digits, ""));
+    if int.is_empty() && frac.is_empty() {
+        return None;
+    }
+
+    let mut fixed: i64 = 0;
+    for digit in int.bytes() {
+        if !digit.is_ascii_digit() {
+            return None;
+        }
+        fixed = fixed
+            .checked_mul(10)?
+            .checked_add(i64::from(digit - b'0'))?;
+    }
+    let mut frac_digits = 0;
+    for digit in frac.bytes() {
+        if !digit.is_ascii_digit() {
+            return None;
+        }
+        if frac_digits < decimals {
+            fixed = fixed
+                .checked_mul(10)?
+                .checked_add(i64::from(digit - b'0'))?;
+            frac_digits += 1;
+        } else if digit != b'0' {
+            return None;
+        }
+    }
+    fixed = fixed.checked_mul(10i64.checked_pow(decimals - frac_digits)?)?;
+    Some(if negative { -fixed } else { fixed })
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+    use rust_decimal_macros::dec;
+
+    use super::*;
+
+    #[test]
+    fn parse_fixed_values() {
+        assert_eq!(parse_fixed("0.06250000", 8), Some(6_250_000));
+        assert_eq!(parse_fixed("12", 2), Some(1_200));
+        assert_eq!(parse_fixed("-1.5", 1), Some(-15));
+        assert_eq!(parse_fixed(".5", 1), Some(5));
+        assert_eq!(parse_fixed("1.", 0), Some(1));
+        assert_eq!(parse_fixed("0.123", 2), None);
+        assert_eq!(parse_fixed("", 2), None);
+        assert_eq!(parse_fixed("1e5", 2), None);
+        assert_eq!(parse_fixed("99999999999", 8), None);
+    }
+
+    #[test]
+    fn decimal_conversions() {
+        let scale = Scale::new(4, 2);
+        assert_eq!(scale.ticks(dec!(0.0625)), Some(625));
+        assert_eq!(scale.ticks(dec!(0.06251)), None);
+        assert_eq!(scale.lots(dec!(1.50)), Some(150));
+        assert_eq!(scale.price(625), dec!(0.0625));
+        assert_eq!(scale.size(150), dec!(1.5));
+    }
+
+    #[test]
+    fn scales_are_parsed() {
+        assert_eq!("2/8".parse(), Ok(Scale::new(2, 8)));
+        assert_eq!("18/0".parse(), Ok(Scale::new(18, 0)));
+        assert!("19/0".parse::<Scale>().is_err());
+        assert!("8".parse::<Scale>().is_err());
+        assert!("a/8".parse::<Scale>().is_err());
+    }
+
+    proptest! {
+        #[test]
+        fn parse_fixed_matches_decimal(mantissa in -1_000_000_000i64..1_000_000_000, scale in 0u32..9) {
+            let value = Decimal::new(mantissa, scale);
+            prop_assert_eq!(parse_fixed(&value.to_string(), 8), to_fixed(value, 8));
+            prop_assert_eq!(Scale::default().price(parse_fixed(&value.to_string(), 8).unwrap()), value);
+        }
+    }
+}
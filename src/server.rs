@@ -1,105 +1,413 @@
-use futures_core::Stream;
+use futures::future::{FusedFuture, FutureExt};
 use std::env;
-use std::pin::Pin;
-use tokio::sync::{mpsc, watch};
-use tonic::{transport::Server, Request, Response, Status};
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc, watch};
+use tokio::task::JoinHandle;
+use tokio::time;
+use tonic::transport::Server;
+use tracing::{error, info, info_span, warn, Instrument};
 
 use orderbook_aggregator::{
-    aggregator::Aggregator,
-    binance, bitstamp,
-    order_book::Orderbook,
-    proto::{
-        orderbook_aggregator_server::{OrderbookAggregator, OrderbookAggregatorServer},
-        Empty, Summary,
-    },
+    aggregator::{self, CrossPolicy, Snapshot},
+    arbitrage::Threshold,
+    binance::{self, TradeStream},
+    bitstamp,
+    candle::{self, Candles},
+    fees::TakerFees,
+    history::{self, History},
+    metrics,
+    order_book::{Exchange, OrderbookUpdateEvent},
+    proto::orderbook_aggregator_server::OrderbookAggregatorServer,
+    quote::{self, Quotes},
+    recorder::{self, Recorder},
+    replay::{self, Speed},
+    routing::MinOrderSizes,
+    scale::Scale,
+    service::AggregatorService,
+    shutdown::{self, Shutdown},
+    supervisor::{RestartPolicy, Supervisor},
+    synthetic::{self, Leg, Synthetic},
+    telemetry,
+    trade::{self, Trade},
+    Error,
 };
 
-/// gRPC service state.
-pub struct AggregatorService {
-    rx: watch::Receiver<Orderbook>,
+/// Read a duration in milliseconds from the environment variable `name`.
+fn env_duration_ms(name: &str, default: u64) -> orderbook_aggregator::Result<Duration> {
+    match env::var(name) {
+        Ok(value) => value
+            .parse()
+            .map(Duration::from_millis)
+            .map_err(|err| Error::config(name, err)),
+        Err(_) => Ok(Duration::from_millis(default)),
+    }
 }
 
-#[tonic::async_trait]
-impl OrderbookAggregator for AggregatorService {
-    type BookSummaryStream =
-        Pin<Box<dyn Stream<Item = Result<Summary, Status>> + Send + Sync + 'static>>;
-
-    async fn book_summary(
-        &self,
-        _request: Request<Empty>,
-    ) -> Result<Response<Self::BookSummaryStream>, Status> {
-        let (tx, rx) = mpsc::channel(4);
-        let mut orderbook_rx = self.rx.clone();
-
-        tokio::spawn(async move {
-            while orderbook_rx.changed().await.is_ok() {
-                let orderbook = orderbook_rx.borrow().clone();
-                let res = tx.send(Ok(Summary::from(orderbook))).await;
-                if let Err(_) = res {
-                    break;
-                }
-            }
-        });
+/// Tasks feeding the aggregator and the aggregator task itself.
+///
+/// The first finishes once every source of updates stopped, the second
+/// only when the aggregator failed, every source gave up or the server is
+/// shutting down.
+type Tasks = (JoinHandle<()>, JoinHandle<orderbook_aggregator::Result<()>>);
 
-        Ok(Response::new(Box::pin(
-            tokio_stream::wrappers::ReceiverStream::new(rx),
-        )))
+/// Spawn the aggregator task publishing the orderbooks received on `rx`.
+fn spawn_aggregator(
+    pair: String,
+    rx: mpsc::Receiver<OrderbookUpdateEvent>,
+    snapshot_tx: watch::Sender<Snapshot>,
+) -> orderbook_aggregator::Result<JoinHandle<orderbook_aggregator::Result<()>>> {
+    let max_age = env_duration_ms("STALE_AFTER_MS", 30_000)?;
+    let cross_policy = CrossPolicy::from_env()?;
+    let aggregate = aggregator::run(pair, rx, snapshot_tx, max_age, cross_policy);
+    Ok(tokio::spawn(aggregate.instrument(info_span!("aggregator"))))
+}
+
+/// Spawn the supervised connector of `exchange` streaming the books of
+/// `pair` to `tx`.
+fn spawn_connector(
+    supervisor: &mut Supervisor,
+    exchange: Exchange,
+    pair: String,
+    scale: Scale,
+    tx: mpsc::Sender<OrderbookUpdateEvent>,
+    recorder: Option<Recorder>,
+) {
+    match exchange {
+        Exchange::Binance => {
+            let url = env::var("BINANCE_URL").unwrap_or_else(|_| binance::URL.to_owned());
+            supervisor.spawn(exchange, tx, move |tx, shutdown| {
+                let (url, pair, recorder) = (url.clone(), pair.clone(), recorder.clone());
+                async move { binance::run(&url, &pair, scale, tx, recorder, shutdown).await }
+            });
+        }
+        Exchange::Bitstamp => {
+            let url = env::var("BITSTAMP_URL").unwrap_or_else(|_| bitstamp::URL.to_owned());
+            supervisor.spawn(exchange, tx, move |tx, shutdown| {
+                let (url, pair, recorder) = (url.clone(), pair.clone(), recorder.clone());
+                async move { bitstamp::run(&url, &pair, scale, tx, recorder, shutdown).await }
+            });
+        }
+        Exchange::Unknown | Exchange::Synthetic => unreachable!("no connector for {}", exchange),
+    }
+}
+
+/// Spawn the supervised trade connector of `exchange` streaming the
+/// trades of `pair` to `tx`.
+fn spawn_trade_connector(
+    supervisor: &mut Supervisor,
+    exchange: Exchange,
+    pair: String,
+    stream: TradeStream,
+    tx: mpsc::Sender<Trade>,
+) {
+    match exchange {
+        Exchange::Binance => {
+            let url = env::var("BINANCE_URL").unwrap_or_else(|_| binance::URL.to_owned());
+            supervisor.spawn(exchange, tx, move |tx, shutdown| {
+                let (url, pair) = (url.clone(), pair.clone());
+                async move { binance::run_trades(&url, &pair, stream, tx, shutdown).await }
+            });
+        }
+        Exchange::Bitstamp => {
+            let url = env::var("BITSTAMP_URL").unwrap_or_else(|_| bitstamp::URL.to_owned());
+            supervisor.spawn(exchange, tx, move |tx, shutdown| {
+                let (url, pair) = (url.clone(), pair.clone());
+                async move { bitstamp::run_trades(&url, &pair, tx, shutdown).await }
+            });
+        }
+        Exchange::Unknown | Exchange::Synthetic => unreachable!("no trades of {}", exchange),
+    }
+}
+
+/// Spawn a supervised connector for each of `legs`, parsing their books
+/// in each leg's own scale, returning the receivers of their updates in
+/// the same order.
+fn spawn_legs<'a>(
+    supervisor: &mut Supervisor,
+    legs: impl Iterator<Item = &'a Leg>,
+) -> Vec<mpsc::Receiver<OrderbookUpdateEvent>> {
+    legs.map(|leg| {
+        let (tx, rx) = mpsc::channel(32);
+        let pair = leg.pair.clone();
+        spawn_connector(supervisor, leg.exchange, pair, leg.scale, tx, None);
+        rx
+    })
+    .collect()
+}
+
+/// Wait for the `stage` task between the connectors and the aggregator.
+async fn join_stage(stage: &str, handle: Option<JoinHandle<orderbook_aggregator::Result<()>>>) {
+    if let Some(handle) = handle {
+        match handle.await {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => error!(%err, "{} failed", stage),
+            Err(err) => error!(%err, "{} task failed", stage),
+        }
     }
 }
 
 /// Connect to exchanges and manage aggregation.
 ///
-/// Spawns a new task for each exchange plus one task for aggregating
-/// the orderbooks.
+/// Spawns a supervised task for each exchange plus one task for
+/// aggregating the orderbooks. With `synthetic` set the legs get their
+/// own connectors and a task combining them into the implied book. With
+/// `quotes` set the venues' books go through a task converting them into
+/// the reference quote currency, and the books of live rates get their
+/// own connectors too. The trades of each exchange come from one more
+/// connector and are published on `trade_tx` and `last_trade_tx`, their
+/// prices converted like the books' with `quotes` set. With
+/// `RECORD_PATH` set every orderbook message received from the exchanges
+/// quoting the pair is appended to that file.
+#[allow(clippy::too_many_arguments)]
 async fn connect_exchanges(
     pair: String,
-    orderbook_tx: watch::Sender<Orderbook>,
-) -> orderbook_aggregator::Result<()> {
-    let (tx, mut rx) = mpsc::channel(32);
-    let tx2 = tx.clone();
-    let pair2 = pair.clone();
+    scale: Scale,
+    synthetic: Option<Synthetic>,
+    quotes: Option<Quotes>,
+    snapshot_tx: watch::Sender<Snapshot>,
+    trade_tx: broadcast::Sender<Trade>,
+    last_trade_tx: watch::Sender<Option<Trade>>,
+    shutdown: Shutdown,
+) -> orderbook_aggregator::Result<Tasks> {
+    let (tx, rx) = mpsc::channel(32);
+    let trade_stream = TradeStream::from_env()?;
 
-    tokio::spawn(async move {
-        bitstamp::run(&pair2, tx).await?;
-        Ok::<(), orderbook_aggregator::Error>(())
-    });
+    let (recorder, writer) = match env::var("RECORD_PATH") {
+        Ok(path) => {
+            info!(%path, "recording exchange messages");
+            let (recorder, writer) = Recorder::open(&path).await?;
+            (Some(recorder), Some(writer))
+        }
+        Err(_) => (None, None),
+    };
 
-    tokio::spawn(async move {
-        binance::run(&pair, tx2).await?;
-        Ok::<(), orderbook_aggregator::Error>(())
+    let mut supervisor = Supervisor::new(RestartPolicy::from_env()?, shutdown);
+    let venue_pairs = quotes
+        .as_ref()
+        .map(|quotes| quotes.pairs.clone())
+        .unwrap_or_default();
+    let trade_quotes = quotes.clone();
+    let (venue_tx, converting) = match quotes {
+        Some(quotes) => {
+            let (venue_tx, venue_rx) = mpsc::channel(32);
+            let live_legs = quotes.live_legs();
+            let legs = spawn_legs(&mut supervisor, live_legs.iter().map(|(_, leg)| leg));
+            info!(venues = ?quotes.venues, "converting quote currencies");
+            let converting = quote::run(quotes, venue_rx, legs, tx.clone());
+            let converting = tokio::spawn(converting.instrument(info_span!("quote")));
+            (venue_tx, Some(converting))
+        }
+        None => (tx.clone(), None),
+    };
+    let (trades_tx, trades_rx) = mpsc::channel(256);
+    for exchange in [Exchange::Bitstamp, Exchange::Binance] {
+        let pair = venue_pairs.get(&exchange).unwrap_or(&pair).clone();
+        let (tx, recorder) = (venue_tx.clone(), recorder.clone());
+        spawn_connector(&mut supervisor, exchange, pair.clone(), scale, tx, recorder);
+        let tx = trades_tx.clone();
+        spawn_trade_connector(&mut supervisor, exchange, pair, trade_stream, tx);
+    }
+    drop(trades_tx);
+    let (trades_rx, converting_trades) = match trade_quotes {
+        Some(quotes) => {
+            let (converted_tx, converted_rx) = mpsc::channel(256);
+            let converting =
+                quote::run_trades(quotes, trades_rx, snapshot_tx.subscribe(), converted_tx);
+            let converting = tokio::spawn(converting.instrument(info_span!("quote")));
+            (converted_rx, Some(converting))
+        }
+        None => (trades_rx, None),
+    };
+    let publishing = trade::run(trades_rx, trade_tx, last_trade_tx);
+    let publishing = tokio::spawn(publishing.instrument(info_span!("trades")));
+    // The connectors hold the last recorder handles and venue senders.
+    drop((recorder, venue_tx));
+
+    let implied = synthetic.map(|synthetic| {
+        let legs = spawn_legs(&mut supervisor, synthetic.legs.iter());
+        info!(legs = ?synthetic.legs, "implying synthetic orderbook");
+        let implied = synthetic::run(synthetic, scale, legs, tx.clone());
+        tokio::spawn(implied.instrument(info_span!("synthetic")))
     });
+    drop(tx);
 
-    tokio::spawn(async move {
-        let mut aggregator = Aggregator::new();
-        while let Some(msg) = rx.recv().await {
-            aggregator.update(msg.exchange, msg.orderbook);
-            if aggregator.orderbooks.len() > 1 {
-                let orderbook = aggregator.aggregate();
-                orderbook_tx.send(orderbook)?;
+    let connectors = tokio::spawn(async move {
+        supervisor.join().await;
+        join_stage("synthetic orderbook", implied).await;
+        join_stage("quote conversion", converting).await;
+        join_stage("trade quote conversion", converting_trades).await;
+        let _ = publishing.await;
+        // The connectors held the last recorder handles, so the writer
+        // flushes and finishes now.
+        if let Some(writer) = writer {
+            match writer.await {
+                Ok(Ok(())) => info!("recording complete"),
+                Ok(Err(err)) => error!(%err, "recording failed"),
+                Err(err) => error!(%err, "recording task failed"),
             }
         }
-        Ok::<(), orderbook_aggregator::Error>(())
     });
+    Ok((connectors, spawn_aggregator(pair, rx, snapshot_tx)?))
+}
 
-    Ok(())
+/// Replay the recording at `path` instead of connecting to exchanges.
+///
+/// The replay speed is read from `REPLAY_SPEED`, either a factor of the
+/// original speed or `max`. Once the recording is exhausted the server
+/// keeps running until shut down, with the books going stale as usual.
+async fn replay_recording(
+    path: String,
+    pair: String,
+    scale: Scale,
+    snapshot_tx: watch::Sender<Snapshot>,
+    mut shutdown: Shutdown,
+) -> orderbook_aggregator::Result<Tasks> {
+    let speed = match env::var("REPLAY_SPEED") {
+        Ok(speed) => speed
+            .parse::<Speed>()
+            .map_err(|err| Error::config("REPLAY_SPEED", err))?,
+        Err(_) => Speed::Factor(1.0),
+    };
+    info!(%path, "replaying recording");
+    let frames = recorder::read(&path).await?;
+    let (tx, rx) = mpsc::channel(32);
+
+    let replay = tokio::spawn(
+        async move {
+            tokio::select! {
+                res = replay::run(frames, scale, speed, tx.clone()) => {
+                    if let Err(err) = res {
+                        error!(%err, "replay failed");
+                    }
+                }
+                _ = shutdown.recv() => return,
+            }
+            // Finishing the replay shouldn't stop the aggregator.
+            shutdown.recv().await;
+            drop(tx);
+        }
+        .instrument(info_span!("replay")),
+    );
+    Ok((replay, spawn_aggregator(pair, rx, snapshot_tx)?))
 }
 
 #[tokio::main]
 async fn main() -> orderbook_aggregator::Result<()> {
-    let (tx, rx) = watch::channel(Orderbook::new());
+    telemetry::init()?;
+    let (notify_shutdown, _) = watch::channel(false);
+    let shutdown_timeout = env_duration_ms("SHUTDOWN_TIMEOUT_MS", 5_000)?;
+
+    let (tx, rx) = watch::channel(Snapshot::default());
+    let (trade_tx, _) = broadcast::channel(1024);
+    let (last_trade_tx, last_trade_rx) = watch::channel(None);
     let pair = env::var("PAIR").expect("Set the PAIR environment variable");
-    println!("Subscribing for updates on {}", pair);
-    connect_exchanges(pair, tx).await?;
+    let scale = Scale::from_env()?;
+    let synthetic = Synthetic::from_env()?;
+    info!(%pair, ?scale, "subscribing for updates");
+    let sources_shutdown = Shutdown::new(notify_shutdown.subscribe());
+    // Trades aren't recorded, so replays come without them.
+    let replay_path = env::var("REPLAY_PATH").ok();
+    let live = replay_path.is_none();
+    let (sources, aggregator_handle) = match replay_path {
+        Some(path) => replay_recording(path, pair, scale, tx, sources_shutdown).await?,
+        None => {
+            let quotes = Quotes::from_env()?;
+            connect_exchanges(
+                pair,
+                scale,
+                synthetic.clone(),
+                quotes,
+                tx,
+                trade_tx.clone(),
+                last_trade_tx,
+                sources_shutdown,
+            )
+            .await?
+        }
+    };
+    let mut aggregator_handle = aggregator_handle.fuse();
+
+    let metrics_addr = env::var("METRICS_ADDR")
+        .unwrap_or_else(|_| "127.0.0.1:9090".to_owned())
+        .parse()
+        .expect("Invalid metrics address provided. Proper format: [IP]:[PORT]");
+    info!(%metrics_addr, "serving metrics");
+    tokio::spawn(async move {
+        if let Err(err) = metrics::serve(metrics_addr).await {
+            error!(%err, "metrics server failed");
+        }
+    });
 
-    let aggregator = AggregatorService { rx: rx };
+    let candles = Candles::new(candle::Config::from_env()?);
+    let history_rx = rx.clone();
+    let building = candle::run(candles.clone(), trade_tx.subscribe(), rx.clone());
+    tokio::spawn(building.instrument(info_span!("candles")));
+
+    let mut aggregator = AggregatorService::new(rx, Shutdown::new(notify_shutdown.subscribe()))
+        .with_candles(candles)
+        .with_min_order_sizes(MinOrderSizes::from_env()?)
+        .with_taker_fees(TakerFees::from_env()?)
+        .with_arbitrage_threshold(Threshold::from_env()?);
+    if let Some(synthetic) = &synthetic {
+        aggregator = aggregator.with_synthetic(synthetic);
+    }
+    if live {
+        aggregator = aggregator.with_trades(trade_tx, last_trade_rx);
+    }
+    if let Some(config) = history::Config::from_env()? {
+        info!(path = %config.path, levels = config.levels, "storing top of book history");
+        let history = History::open(&config.path)?;
+        let storing = history::run(history.clone(), config, history_rx);
+        tokio::spawn(storing.instrument(info_span!("history")));
+        aggregator = aggregator.with_history(history);
+    }
     let addr = env::var("SERVER_ADDR")
-        .unwrap_or("127.0.0.1:50051".to_owned())
+        .unwrap_or_else(|_| "127.0.0.1:50051".to_owned())
         .parse()
         .expect("Invalid address provided. Proper format: [IP]:[PORT]");
-    println!("Server listening on {}", addr);
-    Server::builder()
+    info!(%addr, "server listening");
+    let mut server_shutdown = Shutdown::new(notify_shutdown.subscribe());
+    let server = Server::builder()
         .add_service(OrderbookAggregatorServer::new(aggregator))
-        .serve(addr)
-        .await?;
-    Ok(())
+        .serve_with_shutdown(addr, async move { server_shutdown.recv().await });
+    let mut server = Box::pin(server.fuse());
+
+    // Serving frozen data is worse than not serving at all, so the
+    // server goes down together with the aggregator.
+    let result = tokio::select! {
+        res = &mut server => res.map_err(Error::from),
+        res = &mut aggregator_handle => {
+            let err = match res {
+                Ok(Ok(())) => Error::ConnectorsStopped,
+                Ok(Err(err)) => err,
+                Err(err) => err.into(),
+            };
+            error!(%err, "aggregator stopped; shutting down");
+            Err(err)
+        }
+        res = shutdown::signal() => {
+            info!("received shutdown signal");
+            res.map_err(Error::from)
+        }
+    };
+
+    // Closes the subscriber streams, the gRPC server and the exchange
+    // connections. The aggregator stops once all connectors are gone.
+    let _ = notify_shutdown.send(true);
+    let drain = async {
+        if !server.is_terminated() {
+            if let Err(err) = server.await {
+                error!(%err, "server failed while shutting down");
+            }
+        }
+        let _ = sources.await;
+        if !aggregator_handle.is_terminated() {
+            let _ = aggregator_handle.await;
+        }
+    };
+    match time::timeout(shutdown_timeout, drain).await {
+        Ok(()) => info!("shutdown complete"),
+        Err(_) => warn!(?shutdown_timeout, "graceful shutdown timed out"),
+    }
+    result
 }
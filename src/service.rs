@@ -0,0 +1,454 @@
+//! # service
+//!
+//! The `OrderbookAggregator` gRPC service.
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use futures_core::Stream;
+use rust_decimal::prelude::*;
+use rust_decimal_macros::dec;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::{mpsc, watch};
+use tonic::{Request, Response, Status};
+use tracing::{info, info_span, trace, warn, Instrument};
+
+use crate::aggregator::{Snapshot, LIMIT};
+use crate::arbitrage::{self, Threshold};
+use crate::candle::{Candles, Interval, Source};
+use crate::fees::TakerFees;
+use crate::history::History;
+use crate::metrics;
+use crate::order_book::{Exchange, LevelSide, Quantity};
+use crate::proto::{
+    self, fill_request, orderbook_aggregator_server::OrderbookAggregator, ArbitrageRequest,
+    BookSnapshot, Candle, CandleSource, CandlesRequest, FillEstimate, FillRequest, HistoryRequest,
+    Opportunities, Opportunity, PriceMode, Quote, RoutePlan, RouteRequest, Summary, SummaryRequest,
+    Trade, TradesRequest,
+};
+use crate::routing::{self, MinOrderSizes};
+use crate::shutdown::Shutdown;
+use crate::synthetic::{Leg, Synthetic};
+use crate::trade;
+
+/// Distance from the mid price the depth of a summary is measured within,
+/// in basis points, unless the subscriber asks for another.
+const DEFAULT_DEPTH_BPS: Decimal = dec!(10);
+
+/// Largest distance from the mid price a subscriber can ask the depth to
+/// be measured within, in basis points.
+const MAX_DEPTH_BPS: Decimal = dec!(10_000);
+
+/// Quotes and book snapshots returned by `GetHistory` unless the request
+/// asks for fewer.
+const DEFAULT_HISTORY_LIMIT: usize = 1_000;
+
+/// Most quotes and book snapshots returned by `GetHistory`.
+const MAX_HISTORY_LIMIT: usize = 10_000;
+
+/// gRPC service state.
+pub struct AggregatorService {
+    rx: watch::Receiver<Snapshot>,
+    next_subscriber_id: AtomicU64,
+    shutdown: Shutdown,
+    min_order_sizes: MinOrderSizes,
+    taker_fees: TakerFees,
+    arbitrage_threshold: Threshold,
+    /// Legs of the `Synthetic` venue, empty without one.
+    synthetic_legs: Vec<String>,
+    /// Trades published to subscribers, `None` without trade feeds.
+    trade_tx: Option<broadcast::Sender<trade::Trade>>,
+    last_trade: watch::Receiver<Option<trade::Trade>>,
+    /// Candles streamed to subscribers, `None` if none are built.
+    candles: Option<Candles>,
+    /// Stored top of book, `None` if it isn't stored.
+    history: Option<History>,
+}
+
+impl AggregatorService {
+    /// Create the service streaming the snapshots published on `rx`.
+    ///
+    /// Open streams end with an `UNAVAILABLE` status once `shutdown`
+    /// fires.
+    pub fn new(rx: watch::Receiver<Snapshot>, shutdown: Shutdown) -> Self {
+        Self {
+            rx,
+            next_subscriber_id: AtomicU64::new(1),
+            shutdown,
+            min_order_sizes: MinOrderSizes::default(),
+            taker_fees: TakerFees::default(),
+            arbitrage_threshold: Threshold::default(),
+            synthetic_legs: Vec::new(),
+            trade_tx: None,
+            last_trade: watch::channel(None).1,
+            candles: None,
+            history: None,
+        }
+    }
+
+    /// Respect `min_order_sizes` when routing orders.
+    pub fn with_min_order_sizes(mut self, min_order_sizes: MinOrderSizes) -> Self {
+        self.min_order_sizes = min_order_sizes;
+        self
+    }
+
+    /// Adjust prices by `taker_fees` in `FEE_ADJUSTED` summaries.
+    pub fn with_taker_fees(mut self, taker_fees: TakerFees) -> Self {
+        self.taker_fees = taker_fees;
+        self
+    }
+
+    /// Tag the levels of the `Synthetic` venue in summaries with the legs
+    /// of `synthetic`.
+    pub fn with_synthetic(mut self, synthetic: &Synthetic) -> Self {
+        self.synthetic_legs = synthetic.legs.iter().map(Leg::to_string).collect();
+        self
+    }
+
+    /// Stream the trades published on `trade_tx` and add the latest one,
+    /// kept in `last_trade`, to summaries.
+    pub fn with_trades(
+        mut self,
+        trade_tx: broadcast::Sender<trade::Trade>,
+        last_trade: watch::Receiver<Option<trade::Trade>>,
+    ) -> Self {
+        self.trade_tx = Some(trade_tx);
+        self.last_trade = last_trade;
+        self
+    }
+
+    /// Stream the `candles` built elsewhere.
+    pub fn with_candles(mut self, candles: Candles) -> Self {
+        self.candles = Some(candles);
+        self
+    }
+
+    /// Answer `GetHistory` from `history`.
+    pub fn with_history(mut self, history: History) -> Self {
+        self.history = Some(history);
+        self
+    }
+
+    /// Leave out arbitrage opportunities below `threshold`, unless a
+    /// subscriber asks for its own minimums.
+    pub fn with_arbitrage_threshold(mut self, threshold: Threshold) -> Self {
+        self.arbitrage_threshold = threshold;
+        self
+    }
+}
+
+/// Stream of messages derived from the published snapshots.
+pub type SnapshotStream<T> = Pin<Box<dyn Stream<Item = Result<T, Status>> + Send + Sync + 'static>>;
+
+impl AggregatorService {
+    /// Stream `message` of every snapshot published from now on, skipping
+    /// snapshots it returns `None` for.
+    ///
+    /// `stream` names the messages in the subscriber's logs.
+    fn subscribe<T, F>(&self, stream: &'static str, mut message: F) -> SnapshotStream<T>
+    where
+        T: Send + Sync + 'static,
+        F: FnMut(Snapshot) -> Option<T> + Send + 'static,
+    {
+        let (tx, rx) = mpsc::channel(4);
+        let mut snapshot_rx = self.rx.clone();
+        let subscriber_id = self.next_subscriber_id.fetch_add(1, Ordering::Relaxed);
+        let mut shutdown = self.shutdown.clone();
+
+        let subscriber = async move {
+            info!("subscribed");
+            metrics::ACTIVE_SUBSCRIBERS.inc();
+            loop {
+                tokio::select! {
+                    res = snapshot_rx.changed() => {
+                        if res.is_err() {
+                            break;
+                        }
+                    }
+                    _ = shutdown.recv() => {
+                        let status = Status::unavailable("server is shutting down");
+                        let _ = tx.send(Err(status)).await;
+                        break;
+                    }
+                }
+                let snapshot = snapshot_rx.borrow().clone();
+                let update_id = snapshot.update_id;
+                let message = match message(snapshot) {
+                    Some(message) => message,
+                    None => continue,
+                };
+                let res = tx.send(Ok(message)).await;
+                if res.is_err() {
+                    break;
+                }
+                trace!(update_id, "sent {}", stream);
+            }
+            metrics::ACTIVE_SUBSCRIBERS.dec();
+            info!("unsubscribed");
+        };
+        tokio::spawn(subscriber.instrument(info_span!("subscriber", id = subscriber_id, stream)));
+
+        Box::pin(tokio_stream::wrappers::ReceiverStream::new(rx))
+    }
+
+    /// Stream `backlog`, then `message` of every value received on `rx`
+    /// from now on, skipping values it returns `None` for.
+    ///
+    /// A subscriber too slow to keep up skips the values it missed.
+    fn subscribe_broadcast<T, U, F>(
+        &self,
+        stream: &'static str,
+        backlog: Vec<U>,
+        mut values: broadcast::Receiver<T>,
+        mut message: F,
+    ) -> SnapshotStream<U>
+    where
+        T: Clone + Send + 'static,
+        U: Send + Sync + 'static,
+        F: FnMut(T) -> Option<U> + Send + 'static,
+    {
+        let (tx, rx) = mpsc::channel(4);
+        let subscriber_id = self.next_subscriber_id.fetch_add(1, Ordering::Relaxed);
+        let mut shutdown = self.shutdown.clone();
+
+        let subscriber = async move {
+            info!("subscribed");
+            metrics::ACTIVE_SUBSCRIBERS.inc();
+            for message in backlog {
+                if tx.send(Ok(message)).await.is_err() {
+                    break;
+                }
+            }
+            loop {
+                let value = tokio::select! {
+                    res = values.recv() => match res {
+                        Ok(value) => value,
+                        Err(RecvError::Lagged(skipped)) => {
+                            warn!(skipped, "subscriber lagging; skipped {}", stream);
+                            continue;
+                        }
+                        Err(RecvError::Closed) => break,
+                    },
+                    _ = shutdown.recv() => {
+                        let status = Status::unavailable("server is shutting down");
+                        let _ = tx.send(Err(status)).await;
+                        break;
+                    }
+                };
+                let message = match message(value) {
+                    Some(message) => message,
+                    None => continue,
+                };
+                if tx.send(Ok(message)).await.is_err() {
+                    break;
+                }
+                trace!("sent {}", stream);
+            }
+            metrics::ACTIVE_SUBSCRIBERS.dec();
+            info!("unsubscribed");
+        };
+        tokio::spawn(subscriber.instrument(info_span!("subscriber", id = subscriber_id, stream)));
+
+        Box::pin(tokio_stream::wrappers::ReceiverStream::new(rx))
+    }
+}
+
+/// `value` of a request field, if it is positive.
+fn positive(value: f64) -> Option<Decimal> {
+    Decimal::from_f64(value).filter(|value| value.is_sign_positive() && !value.is_zero())
+}
+
+#[tonic::async_trait]
+impl OrderbookAggregator for AggregatorService {
+    type BookSummaryStream = SnapshotStream<Summary>;
+    type ArbitrageOpportunitiesStream = SnapshotStream<Opportunities>;
+    type TradesStream = SnapshotStream<Trade>;
+    type CandlesStream = SnapshotStream<Candle>;
+
+    async fn book_summary(
+        &self,
+        request: Request<SummaryRequest>,
+    ) -> Result<Response<Self::BookSummaryStream>, Status> {
+        let request = request.into_inner();
+        let price_mode = request.price_mode();
+        let imbalance_levels = match request.imbalance_levels {
+            0 => LIMIT,
+            levels => levels as usize,
+        };
+        let depth_bps = if request.depth_bps == 0.0 {
+            DEFAULT_DEPTH_BPS
+        } else {
+            positive(request.depth_bps)
+                .filter(|bps| *bps <= MAX_DEPTH_BPS)
+                .ok_or_else(|| Status::invalid_argument("depth_bps must be within (0, 10000]"))?
+        };
+        let taker_fees = self.taker_fees.clone();
+        let synthetic_legs = self.synthetic_legs.clone();
+        let last_trade = self.last_trade.clone();
+        let summaries = self.subscribe("summary", move |snapshot| {
+            let summary = match price_mode {
+                PriceMode::Raw => Summary::from(snapshot.orderbook),
+                PriceMode::FeeAdjusted => Summary::from(taker_fees.adjust(&snapshot.depth, LIMIT)),
+            };
+            let summary = summary.with_statistics(&snapshot.depth, imbalance_levels, depth_bps);
+            let summary = summary.with_quote_rates(&snapshot.quote_rates);
+            let mut summary = summary.with_legs(&synthetic_legs);
+            summary.last_trade = last_trade.borrow().clone().map(Trade::from);
+            Some(summary)
+        });
+        Ok(Response::new(summaries))
+    }
+
+    async fn estimate_fill(
+        &self,
+        request: Request<FillRequest>,
+    ) -> Result<Response<FillEstimate>, Status> {
+        let request = request.into_inner();
+        let side = LevelSide::from(request.side());
+        let quantity = match request.quantity {
+            Some(fill_request::Quantity::Base(base)) => positive(base).map(Quantity::Base),
+            Some(fill_request::Quantity::Quote(quote)) => positive(quote).map(Quantity::Quote),
+            None => None,
+        }
+        .ok_or_else(|| Status::invalid_argument("base or quote must be set and positive"))?;
+        let depth = self.rx.borrow().depth.clone();
+        let fill = depth
+            .fill(side, quantity)
+            .ok_or_else(|| Status::failed_precondition("aggregated orderbook is empty"))?;
+        Ok(Response::new(FillEstimate::from(fill)))
+    }
+
+    async fn route_order(
+        &self,
+        request: Request<RouteRequest>,
+    ) -> Result<Response<RoutePlan>, Status> {
+        let request = request.into_inner();
+        let side = LevelSide::from(request.side());
+        let quantity = positive(request.quantity)
+            .ok_or_else(|| Status::invalid_argument("quantity must be positive"))?;
+        let depth = self.rx.borrow().depth.clone();
+        let plan = routing::plan(&depth, side, quantity, &self.min_order_sizes);
+        Ok(Response::new(RoutePlan::from(plan)))
+    }
+
+    async fn arbitrage_opportunities(
+        &self,
+        request: Request<ArbitrageRequest>,
+    ) -> Result<Response<Self::ArbitrageOpportunitiesStream>, Status> {
+        let request = request.into_inner();
+        let threshold = Threshold {
+            min_profit: positive(request.min_profit).unwrap_or(self.arbitrage_threshold.min_profit),
+            min_profit_bps: positive(request.min_profit_bps)
+                .unwrap_or(self.arbitrage_threshold.min_profit_bps),
+        };
+        let taker_fees = self.taker_fees.clone();
+        // Only the first empty list after opportunities is sent.
+        let mut crossed = false;
+        let opportunities = self.subscribe("opportunities", move |snapshot| {
+            let opportunities: Vec<Opportunity> =
+                arbitrage::detect(&snapshot.raw_depth, &taker_fees)
+                    .into_iter()
+                    .filter(|opportunity| threshold.accepts(opportunity))
+                    .map(Opportunity::from)
+                    .collect();
+            let was_crossed = std::mem::replace(&mut crossed, !opportunities.is_empty());
+            if opportunities.is_empty() && !was_crossed {
+                return None;
+            }
+            Some(Opportunities { opportunities })
+        });
+        Ok(Response::new(opportunities))
+    }
+
+    async fn trades(
+        &self,
+        request: Request<TradesRequest>,
+    ) -> Result<Response<Self::TradesStream>, Status> {
+        let trade_tx = self
+            .trade_tx
+            .as_ref()
+            .ok_or_else(|| Status::failed_precondition("trades aren't ingested"))?;
+        let mut exchanges = Vec::new();
+        for exchange in request.into_inner().exchanges {
+            let parsed = Exchange::from_str(&exchange)
+                .map_err(|_| Status::invalid_argument(format!("unknown exchange {}", exchange)))?;
+            exchanges.push(parsed);
+        }
+        let trade_rx = trade_tx.subscribe();
+        let trades = self.subscribe_broadcast("trades", Vec::new(), trade_rx, move |trade| {
+            let wanted = exchanges.is_empty() || exchanges.contains(&trade.exchange);
+            wanted.then(|| Trade::from(trade))
+        });
+        Ok(Response::new(trades))
+    }
+
+    async fn candles(
+        &self,
+        request: Request<CandlesRequest>,
+    ) -> Result<Response<Self::CandlesStream>, Status> {
+        let candles = self
+            .candles
+            .as_ref()
+            .ok_or_else(|| Status::failed_precondition("candles aren't built"))?;
+        let request = request.into_inner();
+        let source = match request.source() {
+            CandleSource::Consolidated => Source::Consolidated,
+            CandleSource::Mid => Source::Mid,
+            CandleSource::Venue => {
+                let exchange = Exchange::from_str(&request.exchange).map_err(|_| {
+                    Status::invalid_argument(format!("unknown exchange {}", request.exchange))
+                })?;
+                Source::Venue(exchange)
+            }
+        };
+        let interval = match request.interval.as_str() {
+            "" => Interval::OneMinute,
+            interval => Interval::from_str(interval)
+                .map_err(|_| Status::invalid_argument(format!("unknown interval {}", interval)))?,
+        };
+        if !candles.builds(interval) {
+            let message = format!("candles aren't built at {}", interval);
+            return Err(Status::invalid_argument(message));
+        }
+        let history = match request.history {
+            0 => None,
+            history => Some(history as usize),
+        };
+        let (backlog, candle_rx) = candles.subscribe(source, interval, history);
+        let backlog = backlog.into_iter().map(Candle::from).collect();
+        let candles = self.subscribe_broadcast("candles", backlog, candle_rx, move |candle| {
+            let wanted = candle.source == source && candle.interval == interval;
+            wanted.then(|| Candle::from(candle))
+        });
+        Ok(Response::new(candles))
+    }
+
+    async fn get_history(
+        &self,
+        request: Request<HistoryRequest>,
+    ) -> Result<Response<proto::History>, Status> {
+        let history = self
+            .history
+            .as_ref()
+            .ok_or_else(|| Status::failed_precondition("history isn't stored"))?;
+        let request = request.into_inner();
+        let to = match request.to {
+            0 => trade::now_micros(),
+            to => to,
+        };
+        if request.from > to {
+            return Err(Status::invalid_argument("from must not be after to"));
+        }
+        let limit = match request.limit {
+            0 => DEFAULT_HISTORY_LIMIT,
+            limit => (limit as usize).min(MAX_HISTORY_LIMIT),
+        };
+        let (quotes, books) = history
+            .query(request.from, to, limit, request.books)
+            .await?;
+        Ok(Response::new(proto::History {
+            quotes: quotes.into_iter().map(Quote::from).collect(),
+            books: books.into_iter().map(BookSnapshot::from).collect(),
+        }))
+    }
+}
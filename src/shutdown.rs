@@ -0,0 +1,82 @@
+//! # shutdown
+//!
+//! Server shutdown signalling.
+use tokio::signal;
+use tokio::sync::watch;
+
+/// Listens for the server shutdown signal.
+///
+/// Shutdown is signalled by sending `true` on the `watch::Sender` the
+/// listener was created from. Dropping the sender counts as shutdown too.
+#[derive(Debug, Clone)]
+pub struct Shutdown {
+    /// `true` once the shutdown signal has been received.
+    is_shutdown: bool,
+    notify: watch::Receiver<bool>,
+}
+
+impl Shutdown {
+    /// Create a new `Shutdown` backed by the given `watch::Receiver`.
+    pub fn new(notify: watch::Receiver<bool>) -> Self {
+        Self {
+            is_shutdown: false,
+            notify,
+        }
+    }
+
+    /// Returns `true` if the shutdown signal has been sent.
+    pub fn is_shutdown(&self) -> bool {
+        self.is_shutdown || *self.notify.borrow()
+    }
+
+    /// Wait for the shutdown signal, returning immediately if it was
+    /// already received.
+    pub async fn recv(&mut self) {
+        if self.is_shutdown {
+            return;
+        }
+        // Either the signal was sent or the sender is gone.
+        let _ = self.notify.wait_for(|shutdown| *shutdown).await;
+        self.is_shutdown = true;
+    }
+}
+
+/// Wait for SIGINT (CTRL+C) or, on unix, SIGTERM.
+pub async fn signal() -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        let mut terminate = signal::unix::signal(signal::unix::SignalKind::terminate())?;
+        tokio::select! {
+            res = signal::ctrl_c() => res,
+            _ = terminate.recv() => Ok(()),
+        }
+    }
+    #[cfg(not(unix))]
+    signal::ctrl_c().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn recv_returns_after_signal() {
+        let (notify, rx) = watch::channel(false);
+        let mut shutdown = Shutdown::new(rx);
+        assert!(!shutdown.is_shutdown());
+
+        notify.send(true).unwrap();
+        shutdown.recv().await;
+        assert!(shutdown.is_shutdown());
+        shutdown.recv().await;
+    }
+
+    #[tokio::test]
+    async fn dropped_sender_means_shutdown() {
+        let (notify, rx) = watch::channel(false);
+        let mut shutdown = Shutdown::new(rx);
+        drop(notify);
+        shutdown.recv().await;
+        assert!(shutdown.is_shutdown());
+    }
+}
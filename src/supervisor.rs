@@ -0,0 +1,344 @@
+//! # supervisor
+//!
+//! Keeps the exchange connectors running. Each connector runs in its own
+//! task; when it stops the failure is logged, its venue is dropped from
+//! the aggregate, for book connectors, and it is restarted according to
+//! the `RestartPolicy`.
+use std::future::Future;
+use std::str::FromStr;
+use std::time::Duration;
+
+use strum_macros::EnumString;
+use tokio::sync::mpsc::Sender;
+use tokio::task::JoinHandle;
+use tokio::time::{self, Instant};
+use tracing::{error, info, info_span, warn, Instrument};
+
+use crate::error::Error;
+use crate::metrics;
+use crate::order_book::{Exchange, Orderbook, OrderbookUpdateEvent};
+use crate::shutdown::Shutdown;
+use crate::trade::Trade;
+
+/// When a stopped connector is started again.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, EnumString)]
+#[strum(serialize_all = "kebab-case")]
+pub enum Restart {
+    /// Restart whenever the connector stops.
+    Always,
+    /// Restart only when the connector failed or panicked.
+    OnFailure,
+    /// Never restart.
+    Never,
+}
+
+/// Restart policy applied to every supervised connector.
+#[derive(Debug, Copy, Clone)]
+pub struct RestartPolicy {
+    pub restart: Restart,
+    /// Maximum number of consecutive restarts, unlimited if `None`.
+    pub max_restarts: Option<u32>,
+    /// Delay before the first restart, doubled on each consecutive restart.
+    pub min_backoff: Duration,
+    /// Upper bound of the restart delay.
+    pub max_backoff: Duration,
+}
+
+impl RestartPolicy {
+    /// Read the policy from the `RESTART_POLICY`, `RESTART_MAX` and
+    /// `RESTART_BACKOFF_MS` environment variables.
+    pub fn from_env() -> crate::Result<Self> {
+        let mut policy = Self::default();
+        if let Ok(restart) = std::env::var("RESTART_POLICY") {
+            policy.restart =
+                Restart::from_str(&restart).map_err(|err| Error::config("RESTART_POLICY", err))?;
+        }
+        if let Ok(max_restarts) = std::env::var("RESTART_MAX") {
+            let max_restarts = max_restarts
+                .parse()
+                .map_err(|err| Error::config("RESTART_MAX", err))?;
+            policy.max_restarts = Some(max_restarts);
+        }
+        if let Ok(backoff) = std::env::var("RESTART_BACKOFF_MS") {
+            let backoff = backoff
+                .parse()
+                .map_err(|err| Error::config("RESTART_BACKOFF_MS", err))?;
+            policy.min_backoff = Duration::from_millis(backoff);
+        }
+        Ok(policy)
+    }
+
+    /// Whether a connector which stopped with `exit` after `restarts`
+    /// consecutive restarts should be started again.
+    fn should_restart(&self, exit: Exit, restarts: u32) -> bool {
+        let restart = match self.restart {
+            Restart::Always => true,
+            Restart::OnFailure => exit != Exit::Completed,
+            Restart::Never => false,
+        };
+        restart && self.max_restarts.is_none_or(|max| restarts < max)
+    }
+
+    /// Delay before the restart following `restarts` consecutive restarts.
+    fn backoff(&self, restarts: u32) -> Duration {
+        let factor = 2u32.saturating_pow(restarts.min(16));
+        self.min_backoff
+            .checked_mul(factor)
+            .map_or(self.max_backoff, |backoff| backoff.min(self.max_backoff))
+    }
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        Self {
+            restart: Restart::Always,
+            max_restarts: None,
+            min_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Message a supervised connector sends.
+pub trait Update: Send + 'static {
+    /// Message telling the receiver that the connector of `exchange`
+    /// stopped, if it has to forget what the connector sent.
+    fn stopped(exchange: Exchange) -> Option<Self>
+    where
+        Self: Sized;
+}
+
+impl Update for OrderbookUpdateEvent {
+    /// Empty book, so subscribers don't see the frozen book of a dead
+    /// connection.
+    fn stopped(exchange: Exchange) -> Option<Self> {
+        Some(OrderbookUpdateEvent::new(exchange, Orderbook::new()))
+    }
+}
+
+impl Update for Trade {
+    /// Nothing, the trades already sent stay valid.
+    fn stopped(_exchange: Exchange) -> Option<Self> {
+        None
+    }
+}
+
+/// How a connector run ended.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Exit {
+    Completed,
+    Failed,
+    Panicked,
+}
+
+/// Spawns connectors and keeps track of their tasks.
+#[derive(Debug)]
+pub struct Supervisor {
+    policy: RestartPolicy,
+    /// Stops restarting connectors and is handed to each of them.
+    shutdown: Shutdown,
+    handles: Vec<JoinHandle<()>>,
+}
+
+impl Supervisor {
+    pub fn new(policy: RestartPolicy, shutdown: Shutdown) -> Self {
+        Self {
+            policy,
+            shutdown,
+            handles: Vec::new(),
+        }
+    }
+
+    /// Spawn the connector for `exchange` under supervision.
+    ///
+    /// `connect` is called with a clone of `tx` and of the shutdown
+    /// listener every time the connector is (re)started.
+    pub fn spawn<T, F, Fut>(&mut self, exchange: Exchange, tx: Sender<T>, connect: F)
+    where
+        T: Update,
+        F: FnMut(Sender<T>, Shutdown) -> Fut + Send + 'static,
+        Fut: Future<Output = crate::Result<()>> + Send + 'static,
+    {
+        let task = supervise(exchange, self.policy, self.shutdown.clone(), tx, connect);
+        let span = info_span!("supervisor", venue = %exchange);
+        self.handles.push(tokio::spawn(task.instrument(span)));
+    }
+
+    /// Wait until every supervised connector gave up or shut down.
+    pub async fn join(self) {
+        for handle in self.handles {
+            let _ = handle.await;
+        }
+    }
+}
+
+/// Run the connector produced by `connect` until the policy says stop
+/// or the server shuts down.
+async fn supervise<T, F, Fut>(
+    exchange: Exchange,
+    policy: RestartPolicy,
+    mut shutdown: Shutdown,
+    tx: Sender<T>,
+    mut connect: F,
+) where
+    T: Update,
+    F: FnMut(Sender<T>, Shutdown) -> Fut,
+    Fut: Future<Output = crate::Result<()>> + Send + 'static,
+{
+    let venue: &'static str = exchange.into();
+    let mut restarts = 0;
+    loop {
+        let started_at = Instant::now();
+        let connector = connect(tx.clone(), shutdown.clone());
+        let exit = match tokio::spawn(connector).await {
+            Ok(Ok(())) => {
+                info!("connector stopped");
+                Exit::Completed
+            }
+            Ok(Err(err)) => {
+                error!(%err, "connector failed");
+                Exit::Failed
+            }
+            Err(err) => {
+                error!(%err, "connector panicked");
+                Exit::Panicked
+            }
+        };
+        if shutdown.is_shutdown() {
+            return;
+        }
+
+        let receiver_gone = match T::stopped(exchange) {
+            Some(stopped) => tx.send(stopped).await.is_err(),
+            None => tx.is_closed(),
+        };
+        if receiver_gone {
+            warn!("receiver is gone; not restarting");
+            return;
+        }
+
+        // A connector which ran for a while is considered healthy again.
+        if started_at.elapsed() >= policy.max_backoff {
+            restarts = 0;
+        }
+        if !policy.should_restart(exit, restarts) {
+            warn!(restarts, "giving up on connector");
+            return;
+        }
+
+        let backoff = policy.backoff(restarts);
+        info!(?backoff, "restarting connector");
+        tokio::select! {
+            _ = time::sleep(backoff) => {}
+            _ = shutdown.recv() => return,
+        }
+        metrics::RECONNECTS_TOTAL.with_label_values(&[venue]).inc();
+        restarts += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+
+    use tokio::sync::{mpsc, watch};
+
+    use super::*;
+
+    fn policy(restart: Restart, max_restarts: Option<u32>) -> RestartPolicy {
+        RestartPolicy {
+            restart,
+            max_restarts,
+            ..RestartPolicy::default()
+        }
+    }
+
+    #[test]
+    fn restart_decisions() {
+        let always = policy(Restart::Always, None);
+        assert!(always.should_restart(Exit::Completed, 100));
+        assert!(always.should_restart(Exit::Panicked, 0));
+
+        let on_failure = policy(Restart::OnFailure, Some(2));
+        assert!(!on_failure.should_restart(Exit::Completed, 0));
+        assert!(on_failure.should_restart(Exit::Failed, 1));
+        assert!(!on_failure.should_restart(Exit::Failed, 2));
+
+        let never = policy(Restart::Never, None);
+        assert!(!never.should_restart(Exit::Failed, 0));
+    }
+
+    #[test]
+    fn backoff_is_exponential_and_capped() {
+        let policy = RestartPolicy::default();
+        assert_eq!(policy.backoff(0), Duration::from_millis(500));
+        assert_eq!(policy.backoff(2), Duration::from_secs(2));
+        assert_eq!(policy.backoff(10), Duration::from_secs(30));
+        assert_eq!(policy.backoff(u32::MAX), Duration::from_secs(30));
+    }
+
+    #[test]
+    fn restart_from_str() {
+        assert_eq!(Restart::from_str("on-failure").unwrap(), Restart::OnFailure);
+        assert!(Restart::from_str("sometimes").is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failing_connector_is_restarted_and_cleared() {
+        let (tx, mut rx) = mpsc::channel::<OrderbookUpdateEvent>(8);
+        let starts = Arc::new(AtomicU32::new(0));
+        let counter = starts.clone();
+
+        let (_notify_shutdown, shutdown) = watch::channel(false);
+        let policy = policy(Restart::OnFailure, Some(2));
+        let mut supervisor = Supervisor::new(policy, Shutdown::new(shutdown));
+        supervisor.spawn(Exchange::Binance, tx, move |_tx, _shutdown| {
+            let starts = counter.clone();
+            async move {
+                if starts.fetch_add(1, Ordering::SeqCst) == 0 {
+                    panic!("connector panicked");
+                }
+                Err(Error::connection(Exchange::Binance, "connection reset"))
+            }
+        });
+        supervisor.join().await;
+
+        assert_eq!(starts.load(Ordering::SeqCst), 3);
+        let mut cleared = 0;
+        while let Ok(event) = rx.try_recv() {
+            assert_eq!(event.exchange, Exchange::Binance);
+            assert!(event.orderbook.is_empty());
+            cleared += 1;
+        }
+        assert_eq!(cleared, 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stopped_trade_connector_sends_nothing() {
+        let (tx, mut rx) = mpsc::channel::<Trade>(8);
+        let (_notify_shutdown, shutdown) = watch::channel(false);
+        let policy = policy(Restart::Never, None);
+        let mut supervisor = Supervisor::new(policy, Shutdown::new(shutdown));
+        supervisor.spawn(Exchange::Binance, tx, |_tx, _shutdown| async {
+            Err(Error::connection(Exchange::Binance, "connection reset"))
+        });
+        supervisor.join().await;
+
+        assert!(rx.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn shutdown_stops_restarts() {
+        let (tx, _rx) = mpsc::channel::<OrderbookUpdateEvent>(8);
+        let (notify_shutdown, shutdown) = watch::channel(false);
+        let mut supervisor = Supervisor::new(RestartPolicy::default(), Shutdown::new(shutdown));
+        supervisor.spawn(Exchange::Bitstamp, tx, |_tx, mut shutdown| async move {
+            shutdown.recv().await;
+            Ok(())
+        });
+
+        notify_shutdown.send(true).unwrap();
+        supervisor.join().await;
+    }
+}
@@ -0,0 +1,519 @@
+//! # synthetic
+//!
+//! Implied books of pairs quoted through a chain of other pairs, e.g.
+//! BTC/EUR through BTC/USDT and EUR/USDT. Each leg is the book of one pair
+//! on one venue, optionally inverted, and the quote currency of a leg is
+//! the base currency of the next. The implied book is aggregated as the
+//! `Synthetic` venue alongside the venues quoting the pair directly.
+use std::fmt;
+use std::str::FromStr;
+
+use rust_decimal::prelude::*;
+use rust_decimal::RoundingStrategy;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::{StreamExt, StreamMap};
+use tracing::{debug, warn};
+
+use crate::error::Error;
+use crate::order_book::{Exchange, Orderbook, OrderbookLevel, OrderbookUpdateEvent};
+use crate::scale::Scale;
+
+/// Book of one pair on one venue in a chain of legs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Leg {
+    pub exchange: Exchange,
+    /// Pair as the venue names it, e.g. `eurusdt`.
+    pub pair: String,
+    /// Whether the pair is quoted the other way round than the chain
+    /// needs, e.g. EUR/USDT for a USDT/EUR leg.
+    pub inverse: bool,
+    /// Scale the leg's book is parsed in, independent of the aggregated
+    /// pair's as the leg quotes another instrument; `Scale::default()`
+    /// unless given.
+    pub scale: Scale,
+}
+
+impl FromStr for Leg {
+    type Err = String;
+
+    /// Parse `Venue:pair`, followed by `:inverse` for an inverted leg
+    /// and by `:price_decimals/size_decimals` for its scale, e.g.
+    /// `Binance:eurusdt:inverse:5/1`.
+    fn from_str(leg: &str) -> Result<Self, Self::Err> {
+        let mut parts = leg.trim().split(':');
+        let (exchange, pair) = match (parts.next(), parts.next()) {
+            (Some(exchange), Some(pair)) if !pair.is_empty() => (exchange, pair),
+            _ => return Err(format!("expected `Venue:pair`, got `{}`", leg)),
+        };
+        let exchange = match Exchange::from_str(exchange) {
+            Ok(exchange @ (Exchange::Binance | Exchange::Bitstamp)) => exchange,
+            _ => return Err(format!("`{}` is not a venue", exchange)),
+        };
+        let mut next = parts.next();
+        let inverse = next == Some("inverse");
+        if inverse {
+            next = parts.next();
+        }
+        let scale = match next {
+            None => Scale::default(),
+            Some(scale) if scale.contains('/') => scale.parse()?,
+            Some(other) => return Err(format!("expected `inverse` or a scale, got `{}`", other)),
+        };
+        if let Some(other) = parts.next() {
+            return Err(format!("unexpected `{}` in `{}`", other, leg));
+        }
+        Ok(Self {
+            exchange,
+            pair: pair.to_owned(),
+            inverse,
+            scale,
+        })
+    }
+}
+
+impl fmt::Display for Leg {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.exchange, self.pair)?;
+        if self.inverse {
+            f.write_str(":inverse")?;
+        }
+        if self.scale != Scale::default() {
+            let scale = self.scale;
+            write!(f, ":{}/{}", scale.price_decimals, scale.size_decimals)?;
+        }
+        Ok(())
+    }
+}
+
+/// Pair implied by a chain of legs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Synthetic {
+    pub legs: Vec<Leg>,
+}
+
+impl Synthetic {
+    /// Read the legs from the `SYNTHETIC_LEGS` environment variable, a
+    /// comma-separated chain of `Venue:pair[:inverse][:scale]` legs, e.g.
+    /// `Binance:btcusdt,Binance:eurusdt:inverse`. `None` if unset.
+    pub fn from_env() -> crate::Result<Option<Self>> {
+        match std::env::var("SYNTHETIC_LEGS") {
+            Ok(legs) => legs
+                .parse()
+                .map(Some)
+                .map_err(|err| Error::config("SYNTHETIC_LEGS", err)),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// Book of the pair implied by `books`, the latest book of each leg,
+    /// with levels of the `Synthetic` venue in `scale`.
+    ///
+    /// The legs are chained in exact decimals, whatever the scale of each
+    /// leg's book.
+    ///
+    /// Prices are rounded away from the other side, bids down and asks
+    /// up, and sizes down, so the implied book never looks better than
+    /// the legs. Empty if any leg is.
+    pub fn implied(&self, scale: Scale, books: &[Orderbook]) -> Orderbook {
+        let mut legs = self.legs.iter().zip(books).map(|(leg, book)| {
+            let book = Book::from(book);
+            if leg.inverse {
+                book.inverse()
+            } else {
+                book
+            }
+        });
+        let implied = match legs.next() {
+            Some(first) => legs.fold(first, |implied, leg| implied.chain(&leg)),
+            None => Book::default(),
+        };
+        implied.to_orderbook(scale)
+    }
+}
+
+impl FromStr for Synthetic {
+    type Err = String;
+
+    fn from_str(legs: &str) -> Result<Self, Self::Err> {
+        let legs = legs
+            .split(',')
+            .filter(|leg| !leg.trim().is_empty())
+            .map(Leg::from_str)
+            .collect::<Result<Vec<Leg>, String>>()?;
+        if legs.is_empty() {
+            return Err("no legs".to_owned());
+        }
+        Ok(Self { legs })
+    }
+}
+
+/// Levels as `(price, size)`, best first.
+type Levels = Vec<(Decimal, Decimal)>;
+
+/// Orderbook in exact decimals, free of any scale while legs are chained.
+#[derive(Debug, Clone, Default, PartialEq)]
+struct Book {
+    bids: Levels,
+    asks: Levels,
+}
+
+impl From<&Orderbook> for Book {
+    fn from(orderbook: &Orderbook) -> Self {
+        let scale = orderbook.scale;
+        let levels = |levels: &[OrderbookLevel]| {
+            levels
+                .iter()
+                .map(|level| (scale.price(level.price), scale.size(level.size)))
+                .collect()
+        };
+        Self {
+            bids: levels(&orderbook.bids),
+            asks: levels(&orderbook.asks),
+        }
+    }
+}
+
+impl Book {
+    /// Book of the reverse pair: buying the base currency at an ask is
+    /// selling the quote currency at its inverse, for its notional.
+    fn inverse(&self) -> Self {
+        let inverse = |levels: &Levels| {
+            levels
+                .iter()
+                .filter(|(price, _)| !price.is_zero())
+                .map(|&(price, size)| (Decimal::one() / price, size * price))
+                .collect()
+        };
+        Self {
+            bids: inverse(&self.asks),
+            asks: inverse(&self.bids),
+        }
+    }
+
+    /// Book of A/C from this A/B book and the B/C book `next`.
+    fn chain(&self, next: &Book) -> Self {
+        Self {
+            bids: chain(&self.bids, &next.bids),
+            asks: chain(&self.asks, &next.asks),
+        }
+    }
+
+    /// Round to `scale` and tag the levels as `Synthetic`.
+    fn to_orderbook(&self, scale: Scale) -> Orderbook {
+        let levels = |levels: &Levels, rounding| -> Vec<(i64, i64)> {
+            let mut fixed: Vec<(i64, i64)> = Vec::with_capacity(levels.len());
+            for &(price, size) in levels {
+                let price = price.round_dp_with_strategy(scale.price_decimals, rounding);
+                let size =
+                    size.round_dp_with_strategy(scale.size_decimals, RoundingStrategy::RoundDown);
+                let (price, size) = match (scale.ticks(price), scale.lots(size)) {
+                    (Some(price), Some(size)) if size > 0 => (price, size),
+                    _ => continue,
+                };
+                // Rounding can bring consecutive levels to the same price.
+                match fixed.last_mut() {
+                    Some(last) if last.0 == price => last.1 += size,
+                    _ => fixed.push((price, size)),
+                }
+            }
+            fixed
+        };
+        Orderbook::from_levels(
+            scale,
+            Exchange::Synthetic,
+            &levels(&self.bids, RoundingStrategy::RoundDown),
+            &levels(&self.asks, RoundingStrategy::RoundUp),
+        )
+    }
+}
+
+/// Levels of one side of A/C from the same side of A/B and B/C.
+///
+/// Each A/B level converts into B/C size at its own price, so the levels
+/// are matched like two books being swept together: every implied level
+/// takes as much as both legs' current levels can carry.
+fn chain(first: &Levels, second: &Levels) -> Levels {
+    let mut implied = Vec::new();
+    let (mut first, mut second) = (first.iter().copied(), second.iter().copied());
+    let (mut a, mut b) = (first.next(), second.next());
+    while let (Some((price_a, size_a)), Some((price_b, size_b))) = (a, b) {
+        // `size_b` is in B, the quote currency of the first leg.
+        let price = price_a * price_b;
+        if size_a * price_a <= size_b {
+            implied.push((price, size_a));
+            b = Some((price_b, size_b - size_a * price_a));
+            a = first.next();
+        } else {
+            let size = size_b / price_a;
+            implied.push((price, size));
+            a = Some((price_a, size_a - size));
+            b = second.next();
+        }
+    }
+    implied
+}
+
+/// Run the implied book of `synthetic`.
+///
+/// Receives the updates of each leg on the receiver at its position in
+/// `legs` and sends the implied book as an update of the `Synthetic`
+/// venue on `tx` after each of them. The implied book is as old as the
+/// oldest leg's book, so it goes stale with any of its legs. Returns when
+/// every leg's sender is gone.
+pub async fn run(
+    synthetic: Synthetic,
+    scale: Scale,
+    legs: Vec<mpsc::Receiver<OrderbookUpdateEvent>>,
+    tx: mpsc::Sender<OrderbookUpdateEvent>,
+) -> crate::Result<()> {
+    let mut books = vec![Orderbook::new(); synthetic.legs.len()];
+    let mut received_at = vec![None; synthetic.legs.len()];
+    let mut updates = StreamMap::new();
+    for (leg, rx) in legs.into_iter().enumerate() {
+        updates.insert(leg, ReceiverStream::new(rx));
+    }
+    let mut was_empty = true;
+
+    while let Some((leg, msg)) = updates.next().await {
+        if let Err(err) = msg.validate() {
+            warn!(%err, leg = %synthetic.legs[leg], "rejected leg update");
+            continue;
+        }
+        books[leg] = msg.orderbook;
+        received_at[leg] = Some(msg.received_at);
+        let orderbook = synthetic.implied(scale, &books);
+        // Until every leg has reported there's nothing to remove.
+        if orderbook.is_empty() && std::mem::replace(&mut was_empty, true) {
+            continue;
+        }
+        was_empty = orderbook.is_empty();
+        let oldest = received_at.iter().flatten().min().copied();
+        let update = OrderbookUpdateEvent {
+            received_at: oldest.unwrap_or(msg.received_at),
+            ..OrderbookUpdateEvent::new(Exchange::Synthetic, orderbook)
+        };
+        debug!(update_id = update.id, leg = %synthetic.legs[leg], "implied orderbook");
+        tx.send(update).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use rust_decimal_macros::dec;
+    use tokio::sync::watch;
+    use tokio::time;
+
+    use super::*;
+    use crate::aggregator::{self, CrossPolicy, Snapshot};
+
+    const SCALE: Scale = Scale::new(4, 4);
+
+    fn orderbook(
+        exchange: Exchange,
+        bids: &[(Decimal, Decimal)],
+        asks: &[(Decimal, Decimal)],
+    ) -> Orderbook {
+        let fixed = |levels: &[(Decimal, Decimal)]| -> Vec<(i64, i64)> {
+            levels
+                .iter()
+                .map(|&(price, size)| (SCALE.ticks(price).unwrap(), SCALE.lots(size).unwrap()))
+                .collect()
+        };
+        Orderbook::from_levels(SCALE, exchange, &fixed(bids), &fixed(asks))
+    }
+
+    fn levels(levels: &[OrderbookLevel]) -> Vec<(Decimal, Decimal)> {
+        levels
+            .iter()
+            .map(|level| (SCALE.price(level.price), SCALE.size(level.size)))
+            .collect()
+    }
+
+    #[test]
+    fn legs_are_parsed() {
+        let synthetic: Synthetic = "Binance:btcusdt, Binance:eurusdt:inverse".parse().unwrap();
+        assert_eq!(synthetic.legs.len(), 2);
+        assert!(!synthetic.legs[0].inverse);
+        assert!(synthetic.legs[1].inverse);
+        assert_eq!(synthetic.legs[1].to_string(), "Binance:eurusdt:inverse");
+        assert!("Binance".parse::<Synthetic>().is_err());
+        assert!("Synthetic:btceur".parse::<Synthetic>().is_err());
+        assert!("Binance:btcusdt:reverse".parse::<Synthetic>().is_err());
+        assert!("Binance:btcusdt:2/8:inverse".parse::<Synthetic>().is_err());
+        assert!("Binance:btcusdt:2/19".parse::<Synthetic>().is_err());
+        assert!("".parse::<Synthetic>().is_err());
+    }
+
+    #[test]
+    fn chained_sizes_propagate() {
+        let synthetic: Synthetic = "Binance:btcusdt,Bitstamp:usdteur".parse().unwrap();
+        let btcusdt = orderbook(
+            Exchange::Binance,
+            &[(dec!(100), dec!(1)), (dec!(99), dec!(2))],
+            &[(dec!(101), dec!(1))],
+        );
+        let usdteur = orderbook(
+            Exchange::Bitstamp,
+            &[(dec!(0.9), dec!(150)), (dec!(0.8), dec!(1000))],
+            &[(dec!(0.95), dec!(50))],
+        );
+        let implied = synthetic.implied(SCALE, &[btcusdt, usdteur]);
+
+        // 1 BTC sells for 100 USDT, then 0.9 EUR each. The remaining 50
+        // USDT at 0.9 carry 50 / 99 BTC of the next level, the rest of
+        // which goes at 0.8.
+        assert_eq!(
+            levels(&implied.bids),
+            vec![
+                (dec!(90), dec!(1)),
+                (dec!(89.1), dec!(0.505)),
+                (dec!(79.2), dec!(1.4949)),
+            ]
+        );
+        // Buying 1 BTC takes 101 USDT but only 50 are offered.
+        assert_eq!(levels(&implied.asks), vec![(dec!(95.95), dec!(0.495))]);
+        assert!(implied
+            .bids
+            .iter()
+            .all(|level| level.exchange == Exchange::Synthetic));
+    }
+
+    #[test]
+    fn inverse_leg_swaps_sides() {
+        let synthetic: Synthetic = "Binance:btcusdt,Binance:eurusdt:inverse".parse().unwrap();
+        let btcusdt = orderbook(
+            Exchange::Binance,
+            &[(dec!(100), dec!(1))],
+            &[(dec!(101), dec!(1))],
+        );
+        let eurusdt = orderbook(
+            Exchange::Binance,
+            &[(dec!(1.25), dec!(1000))],
+            &[(dec!(1.28), dec!(1000))],
+        );
+        let implied = synthetic.implied(SCALE, &[btcusdt, eurusdt]);
+        // Selling BTC for USDT, then buying EUR at the EUR/USDT ask.
+        assert_eq!(levels(&implied.bids), vec![(dec!(78.125), dec!(1))]);
+        // Buying BTC with USDT bought by selling EUR at the bid; rounded up.
+        assert_eq!(levels(&implied.asks), vec![(dec!(80.8), dec!(1))]);
+    }
+
+    #[tokio::test]
+    async fn run_sends_implied_books() {
+        let synthetic: Synthetic = "Binance:btcusdt,Bitstamp:usdteur".parse().unwrap();
+        let (btcusdt_tx, btcusdt_rx) = mpsc::channel(8);
+        let (usdteur_tx, usdteur_rx) = mpsc::channel(8);
+        let (tx, mut rx) = mpsc::channel(8);
+        tokio::spawn(run(synthetic, SCALE, vec![btcusdt_rx, usdteur_rx], tx));
+
+        let btcusdt = orderbook(Exchange::Binance, &[(dec!(100), dec!(1))], &[]);
+        btcusdt_tx
+            .send(OrderbookUpdateEvent::new(Exchange::Binance, btcusdt))
+            .await
+            .unwrap();
+        let usdteur = orderbook(Exchange::Bitstamp, &[(dec!(0.9), dec!(100))], &[]);
+        usdteur_tx
+            .send(OrderbookUpdateEvent::new(Exchange::Bitstamp, usdteur))
+            .await
+            .unwrap();
+        // Nothing is sent until both legs have reported.
+        let update = rx.recv().await.unwrap();
+        assert_eq!(update.exchange, Exchange::Synthetic);
+        assert_eq!(update.orderbook.top_bid(), Some(dec!(90)));
+        update.validate().unwrap();
+
+        // A leg dropped by its supervisor removes the implied book.
+        btcusdt_tx
+            .send(OrderbookUpdateEvent::new(
+                Exchange::Binance,
+                Orderbook::new(),
+            ))
+            .await
+            .unwrap();
+        assert!(rx.recv().await.unwrap().orderbook.is_empty());
+
+        drop((btcusdt_tx, usdteur_tx));
+        assert!(rx.recv().await.is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn silent_leg_expires_the_implied_book() {
+        let synthetic: Synthetic = "Binance:btcusdt,Bitstamp:usdteur".parse().unwrap();
+        let (btcusdt_tx, btcusdt_rx) = mpsc::channel(8);
+        let (usdteur_tx, usdteur_rx) = mpsc::channel(8);
+        let (tx, rx) = mpsc::channel(8);
+        let (snapshot_tx, mut snapshot_rx) = watch::channel(Snapshot::default());
+        let venue_tx = tx.clone();
+        tokio::spawn(run(synthetic, SCALE, vec![btcusdt_rx, usdteur_rx], tx));
+        tokio::spawn(aggregator::run(
+            "btceur".to_owned(),
+            rx,
+            snapshot_tx,
+            Duration::from_secs(5),
+            CrossPolicy::Flag,
+        ));
+        let btcusdt = || {
+            let btcusdt = orderbook(Exchange::Binance, &[(dec!(100), dec!(1))], &[]);
+            OrderbookUpdateEvent::new(Exchange::Binance, btcusdt)
+        };
+        let bitstamp = || {
+            let btceur = orderbook(Exchange::Bitstamp, &[(dec!(80), dec!(1))], &[]);
+            OrderbookUpdateEvent::new(Exchange::Bitstamp, btceur)
+        };
+        let synthetic_levels = |snapshot: &Snapshot| {
+            let mut bids = snapshot.orderbook.bids.iter();
+            bids.any(|level| level.exchange == Exchange::Synthetic)
+        };
+
+        btcusdt_tx.send(btcusdt()).await.unwrap();
+        let usdteur = orderbook(Exchange::Bitstamp, &[(dec!(0.9), dec!(100))], &[]);
+        usdteur_tx
+            .send(OrderbookUpdateEvent::new(Exchange::Bitstamp, usdteur))
+            .await
+            .unwrap();
+        time::sleep(Duration::from_millis(1)).await;
+        venue_tx.send(bitstamp()).await.unwrap();
+        snapshot_rx.changed().await.unwrap();
+        assert!(synthetic_levels(&snapshot_rx.borrow_and_update()));
+
+        // The usdteur leg goes silent, btcusdt and Bitstamp keep updating.
+        for _ in 0..6 {
+            time::sleep(Duration::from_secs(2)).await;
+            btcusdt_tx.send(btcusdt()).await.unwrap();
+            venue_tx.send(bitstamp()).await.unwrap();
+        }
+        time::sleep(Duration::from_millis(1)).await;
+        assert!(!synthetic_levels(&snapshot_rx.borrow_and_update()));
+    }
+
+    #[test]
+    fn legs_keep_their_own_scale() {
+        let synthetic: Synthetic = "Binance:btcusdt,Binance:usdteur:10/2".parse().unwrap();
+        assert_eq!(synthetic.legs[0].scale, Scale::default());
+        let leg_scale = synthetic.legs[1].scale;
+        assert_eq!(leg_scale, Scale::new(10, 2));
+        assert_eq!(synthetic.legs[1].to_string(), "Binance:usdteur:10/2");
+        let btcusdt = orderbook(Exchange::Binance, &[(dec!(100), dec!(1))], &[]);
+        let usdteur = Orderbook::from_levels(
+            leg_scale,
+            Exchange::Binance,
+            &[(leg_scale.ticks(dec!(0.9234567891)).unwrap(), 100_000)],
+            &[],
+        );
+        // 92.34567891 rounded down to the pair's 4 decimals.
+        let implied = synthetic.implied(SCALE, &[btcusdt, usdteur]);
+        assert_eq!(levels(&implied.bids), vec![(dec!(92.3456), dec!(1))]);
+    }
+
+    #[test]
+    fn empty_leg_empties_the_book() {
+        let synthetic: Synthetic = "Binance:btcusdt,Bitstamp:usdteur".parse().unwrap();
+        let btcusdt = orderbook(Exchange::Binance, &[(dec!(100), dec!(1))], &[]);
+        let implied = synthetic.implied(SCALE, &[btcusdt, Orderbook::new()]);
+        assert!(implied.is_empty());
+    }
+}
@@ -0,0 +1,63 @@
+//! # telemetry
+//!
+//! Structured logging setup based on `tracing`.
+use std::str::FromStr;
+
+use strum_macros::EnumString;
+use tracing_subscriber::EnvFilter;
+
+use crate::error::Error;
+
+/// Default filter used when neither `LOG_LEVEL` nor `RUST_LOG` is set.
+const DEFAULT_LEVEL: &str = "info";
+
+/// Output format of the log records.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, EnumString)]
+#[strum(serialize_all = "lowercase")]
+pub enum LogFormat {
+    /// Human readable, single line records.
+    Text,
+    /// One JSON object per record.
+    Json,
+}
+
+/// Install the global `tracing` subscriber.
+///
+/// The filter is taken from `LOG_LEVEL` (or `RUST_LOG`) and accepts
+/// the usual `EnvFilter` directives, e.g. `info,orderbook_aggregator=debug`.
+/// Setting `LOG_FORMAT=json` switches the output to JSON.
+pub fn init() -> crate::Result<()> {
+    let level = std::env::var("LOG_LEVEL")
+        .or_else(|_| std::env::var("RUST_LOG"))
+        .unwrap_or_else(|_| DEFAULT_LEVEL.to_owned());
+    let format = match std::env::var("LOG_FORMAT") {
+        Ok(format) => {
+            LogFormat::from_str(&format).map_err(|err| Error::config("LOG_FORMAT", err))?
+        }
+        Err(_) => LogFormat::Text,
+    };
+    init_with(&level, format)
+}
+
+/// Install the global `tracing` subscriber with explicit settings.
+pub fn init_with(level: &str, format: LogFormat) -> crate::Result<()> {
+    let filter = EnvFilter::try_new(level).map_err(|err| Error::config("LOG_LEVEL", err))?;
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+    let res = match format {
+        LogFormat::Text => builder.try_init(),
+        LogFormat::Json => builder.json().with_current_span(true).try_init(),
+    };
+    res.map_err(|err| Error::config("tracing subscriber", err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn log_format_from_str() {
+        assert_eq!(LogFormat::from_str("json").unwrap(), LogFormat::Json);
+        assert_eq!(LogFormat::from_str("text").unwrap(), LogFormat::Text);
+        assert!(LogFormat::from_str("xml").is_err());
+    }
+}
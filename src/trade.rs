@@ -0,0 +1,96 @@
+//! # trade
+//!
+//! Trades printed on the venues, normalized from their trade channels and
+//! published to every subscriber, see `binance::run_trades` and
+//! `bitstamp::run_trades`.
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rust_decimal::prelude::*;
+use tokio::sync::{broadcast, mpsc, watch};
+use tracing::debug;
+
+use crate::metrics;
+use crate::order_book::Exchange;
+
+/// Side of the order which took liquidity in a trade.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Aggressor {
+    /// A buy order lifted an ask.
+    Buy,
+    /// A sell order hit a bid.
+    Sell,
+}
+
+/// Trade printed on one venue.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Trade {
+    pub exchange: Exchange,
+    /// Trade id assigned by the venue.
+    pub id: u64,
+    pub price: Decimal,
+    /// Base quantity traded.
+    pub size: Decimal,
+    pub aggressor: Aggressor,
+    /// When the venue matched the trade, in microseconds since the epoch.
+    pub traded_at: u64,
+    /// When the trade was received from the venue, in microseconds since
+    /// the epoch.
+    pub received_at: u64,
+}
+
+/// Current time in microseconds since the epoch.
+pub(crate) fn now_micros() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_micros() as u64)
+}
+
+/// Publish every trade received on `rx` to the subscribers of `tx` and
+/// keep the latest one in `last_tx`, until every connector is gone.
+pub async fn run(
+    mut rx: mpsc::Receiver<Trade>,
+    tx: broadcast::Sender<Trade>,
+    last_tx: watch::Sender<Option<Trade>>,
+) {
+    while let Some(trade) = rx.recv().await {
+        let venue: &'static str = trade.exchange.into();
+        metrics::TRADES_TOTAL.with_label_values(&[venue]).inc();
+        debug!(venue, id = trade.id, price = %trade.price, size = %trade.size, "received trade");
+        // Nobody subscribed is fine, the latest trade is still kept.
+        let _ = tx.send(trade.clone());
+        let _ = last_tx.send(Some(trade));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn trades_are_published_and_the_last_one_kept() {
+        let (trade_tx, trade_rx) = mpsc::channel(2);
+        let (tx, mut subscriber) = broadcast::channel(2);
+        let (last_tx, last_rx) = watch::channel(None);
+        let publisher = tokio::spawn(run(trade_rx, tx, last_tx));
+
+        let trade = |id, aggressor| Trade {
+            exchange: Exchange::Bitstamp,
+            id,
+            price: dec!(0.061),
+            size: dec!(2),
+            aggressor,
+            traded_at: 1,
+            received_at: 2,
+        };
+        trade_tx.send(trade(1, Aggressor::Buy)).await.unwrap();
+        trade_tx.send(trade(2, Aggressor::Sell)).await.unwrap();
+        drop(trade_tx);
+        publisher.await.unwrap();
+
+        assert_eq!(subscriber.recv().await.unwrap(), trade(1, Aggressor::Buy));
+        assert_eq!(subscriber.recv().await.unwrap(), trade(2, Aggressor::Sell));
+        assert_eq!(*last_rx.borrow(), Some(trade(2, Aggressor::Sell)));
+    }
+}
@@ -0,0 +1,114 @@
+//! # websocket
+//!
+//! Client loop shared by the connectors. Each connection of a venue, for
+//! its books or for its trades, only differs in how it subscribes and how
+//! its messages are parsed.
+use futures::sink::SinkExt;
+use futures::stream::StreamExt;
+use tokio::sync::mpsc::Sender;
+use tracing::{info, warn};
+use websocket_lite::{Message, Opcode};
+
+use crate::error::Error;
+use crate::metrics;
+use crate::order_book::Exchange;
+use crate::shutdown::Shutdown;
+
+/// Read the websocket of `exchange` at `url`, sending `subscribe` first if
+/// given.
+///
+/// Every text message is handed to `parse` and what it returns, if
+/// anything, is sent on `tx`. Pings are answered. The websocket is closed
+/// and `Ok` returned once `shutdown` fires or the exchange closes it.
+/// Fails with the first error of `parse`, or with `Error::Connection` if
+/// the connection breaks.
+pub async fn read<T, P>(
+    exchange: Exchange,
+    url: &str,
+    subscribe: Option<String>,
+    tx: &Sender<T>,
+    mut shutdown: Shutdown,
+    mut parse: P,
+) -> crate::Result<()>
+where
+    P: FnMut(&str) -> crate::Result<Option<T>>,
+{
+    let venue: &'static str = exchange.into();
+    let builder =
+        websocket_lite::ClientBuilder::new(url).map_err(|err| Error::connection(exchange, err))?;
+    let mut ws_stream = builder
+        .async_connect()
+        .await
+        .map_err(|err| Error::connection(exchange, err))?;
+    info!("connected");
+
+    if let Some(subscribe) = subscribe {
+        ws_stream
+            .send(Message::text(subscribe))
+            .await
+            .map_err(|err| Error::connection(exchange, err))?;
+    }
+
+    loop {
+        let msg = tokio::select! {
+            msg = ws_stream.next() => msg,
+            _ = shutdown.recv() => {
+                info!("shutting down; closing connection");
+                let _ = ws_stream.send(Message::close(None)).await;
+                break Ok(());
+            }
+        };
+
+        let msg = match msg {
+            Some(Ok(msg)) => msg,
+            Some(Err(err)) => {
+                warn!(%err, "websocket error; closing");
+                let _ = ws_stream.send(Message::close(None)).await;
+                break Err(Error::connection(exchange, err));
+            }
+            None => {
+                break Err(Error::connection(exchange, "stream terminated"));
+            }
+        };
+
+        match msg.opcode() {
+            Opcode::Text => {
+                metrics::MESSAGES_TOTAL.with_label_values(&[venue]).inc();
+                // The codec validates text frames too, but a frame that
+                // isn't UTF-8 mustn't take the task down.
+                let response = match std::str::from_utf8(msg.data()) {
+                    Ok(response) => response,
+                    Err(err) => {
+                        warn!(%err, "text frame isn't UTF-8; closing");
+                        let _ = ws_stream.send(Message::close(None)).await;
+                        break Err(Error::connection(exchange, err));
+                    }
+                };
+                match parse(response) {
+                    Ok(Some(item)) => tx.send(item).await?,
+                    Ok(None) => {}
+                    Err(err) => {
+                        if let Error::Protocol { source, .. } = &err {
+                            warn!(err = %source, payload = response, "failed to parse message");
+                            metrics::PARSE_ERRORS_TOTAL
+                                .with_label_values(&[venue])
+                                .inc();
+                        }
+                        break Err(err);
+                    }
+                }
+            }
+            Opcode::Ping => ws_stream
+                .send(Message::pong(msg.into_data()))
+                .await
+                .map_err(|err| Error::connection(exchange, err))?,
+            Opcode::Close => {
+                info!("connection closed by exchange");
+                let _ = ws_stream.send(Message::close(None)).await;
+                break Ok(());
+            }
+            Opcode::Binary => {}
+            Opcode::Pong => {}
+        }
+    }
+}
@@ -0,0 +1,880 @@
+//! End-to-end tests of the gRPC server.
+//!
+//! Each test runs the whole pipeline in process: the real connectors
+//! against mock exchanges, the supervisor, the aggregator and the
+//! `AggregatorService` on an ephemeral port, queried with the generated
+//! client.
+use std::time::Duration;
+
+use ntest::timeout;
+use tokio::net::TcpListener;
+use tokio::sync::{broadcast, mpsc, watch};
+use tokio_stream::wrappers::TcpListenerStream;
+use tonic::transport::{Channel, Server};
+use tonic::{Code, Streaming};
+
+use orderbook_aggregator::{
+    aggregator::{self, CrossPolicy, Snapshot},
+    binance, bitstamp,
+    candle::{self, Candles, Interval},
+    fees::TakerFees,
+    history::{self, History},
+    mock_exchange::{
+        binance::depth,
+        bitstamp::{live_trade, order_book, subscription_succeeded},
+        MockExchange, Script,
+    },
+    order_book::Exchange,
+    proto::{
+        fill_request::Quantity, orderbook_aggregator_client::OrderbookAggregatorClient,
+        orderbook_aggregator_server::OrderbookAggregatorServer, Aggressor, ArbitrageRequest,
+        BookState, Candle, CandleSource, CandlesRequest, FillRequest, HistoryRequest,
+        Opportunities, Opportunity, PriceMode, RouteRequest, Side, Summary, SummaryRequest, Trade,
+        TradesRequest,
+    },
+    scale::Scale,
+    service::AggregatorService,
+    shutdown::Shutdown,
+    supervisor::{Restart, RestartPolicy, Supervisor},
+    trade,
+};
+
+/// Taker fees of the served venues, in basis points.
+const TAKER_FEES: &[(Exchange, u32)] = &[(Exchange::Binance, 10), (Exchange::Bitstamp, 50)];
+
+/// How long to wait for a single summary.
+const NEXT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Server connected to mock exchanges.
+struct Harness {
+    url: String,
+    notify_shutdown: watch::Sender<bool>,
+    _binance: MockExchange,
+    _bitstamp: MockExchange,
+    _trades: Option<MockExchange>,
+}
+
+impl Harness {
+    /// Start the pipeline with each mock exchange playing its scripts,
+    /// one per connection, dropping venues silent for `max_age`.
+    async fn start(binance: Vec<Script>, bitstamp: Vec<Script>, max_age: Duration) -> Self {
+        Self::start_with_trades(binance, bitstamp, Vec::new(), max_age).await
+    }
+
+    /// Like `start`, resolving crossed venues by `cross_policy`.
+    async fn start_with_policy(
+        binance: Vec<Script>,
+        bitstamp: Vec<Script>,
+        max_age: Duration,
+        cross_policy: CrossPolicy,
+    ) -> Self {
+        Self::launch(binance, bitstamp, Vec::new(), max_age, cross_policy).await
+    }
+
+    /// Like `start`, with a Bitstamp trade connector too if there are
+    /// `trades` scripts.
+    async fn start_with_trades(
+        binance: Vec<Script>,
+        bitstamp: Vec<Script>,
+        trades: Vec<Script>,
+        max_age: Duration,
+    ) -> Self {
+        Self::launch(binance, bitstamp, trades, max_age, CrossPolicy::Flag).await
+    }
+
+    /// Start the pipeline with every option set explicitly.
+    async fn launch(
+        binance: Vec<Script>,
+        bitstamp: Vec<Script>,
+        trades: Vec<Script>,
+        max_age: Duration,
+        cross_policy: CrossPolicy,
+    ) -> Self {
+        let binance = MockExchange::start(binance).await.unwrap();
+        let bitstamp = MockExchange::start(bitstamp).await.unwrap();
+        let (notify_shutdown, _) = watch::channel(false);
+        let shutdown = || Shutdown::new(notify_shutdown.subscribe());
+
+        let (tx, rx) = mpsc::channel(32);
+        let policy = RestartPolicy {
+            restart: Restart::Always,
+            min_backoff: Duration::from_millis(50),
+            ..RestartPolicy::default()
+        };
+        let mut supervisor = Supervisor::new(policy, shutdown());
+        let url = binance.url();
+        supervisor.spawn(Exchange::Binance, tx.clone(), move |tx, shutdown| {
+            let url = url.clone();
+            async move { binance::run(&url, "ethbtc", Scale::default(), tx, None, shutdown).await }
+        });
+        let url = bitstamp.url();
+        supervisor.spawn(Exchange::Bitstamp, tx, move |tx, shutdown| {
+            let url = url.clone();
+            async move { bitstamp::run(&url, "ethbtc", Scale::default(), tx, None, shutdown).await }
+        });
+
+        let (trades_tx, trades_rx) = mpsc::channel(32);
+        let trades = match trades.is_empty() {
+            true => None,
+            false => Some(MockExchange::start(trades).await.unwrap()),
+        };
+        if let Some(trades) = &trades {
+            let url = trades.url();
+            supervisor.spawn(Exchange::Bitstamp, trades_tx, move |tx, shutdown| {
+                let url = url.clone();
+                async move { bitstamp::run_trades(&url, "ethbtc", tx, shutdown).await }
+            });
+        }
+        let (trade_tx, _) = broadcast::channel(16);
+        let (last_trade_tx, last_trade_rx) = watch::channel(None);
+        tokio::spawn(trade::run(trades_rx, trade_tx.clone(), last_trade_tx));
+        let candles = Candles::new(candle::Config {
+            intervals: vec![Interval::OneSecond],
+            history: 10,
+        });
+
+        let (snapshot_tx, snapshot_rx) = watch::channel(Snapshot::default());
+        tokio::spawn(aggregator::run(
+            "ethbtc".to_owned(),
+            rx,
+            snapshot_tx,
+            max_age,
+            cross_policy,
+        ));
+        let building = candle::run(candles.clone(), trade_tx.subscribe(), snapshot_rx.clone());
+        tokio::spawn(building);
+        let history = History::open(":memory:").unwrap();
+        let config = history::Config {
+            path: ":memory:".to_owned(),
+            levels: 2,
+            book_interval: Duration::from_millis(0),
+            quote_retention: Duration::from_secs(60),
+            book_retention: Duration::from_secs(60),
+        };
+        tokio::spawn(history::run(history.clone(), config, snapshot_rx.clone()));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let taker_fees = TAKER_FEES
+            .iter()
+            .map(|&(exchange, fee)| (exchange, fee.into()))
+            .collect();
+        let service = AggregatorService::new(snapshot_rx, shutdown())
+            .with_taker_fees(TakerFees(taker_fees))
+            .with_trades(trade_tx, last_trade_rx)
+            .with_candles(candles)
+            .with_history(history);
+        let mut server_shutdown = shutdown();
+        tokio::spawn(
+            Server::builder()
+                .add_service(OrderbookAggregatorServer::new(service))
+                .serve_with_incoming_shutdown(TcpListenerStream::new(listener), async move {
+                    server_shutdown.recv().await
+                }),
+        );
+
+        Self {
+            url,
+            notify_shutdown,
+            _binance: binance,
+            _bitstamp: bitstamp,
+            _trades: trades,
+        }
+    }
+
+    async fn client(&self) -> OrderbookAggregatorClient<Channel> {
+        OrderbookAggregatorClient::connect(self.url.clone())
+            .await
+            .unwrap()
+    }
+
+    /// Open a `BookSummary` stream.
+    async fn subscribe(&self) -> Streaming<Summary> {
+        self.subscribe_with(PriceMode::Raw).await
+    }
+
+    /// Open a `BookSummary` stream priced by `price_mode`.
+    async fn subscribe_with(&self, price_mode: PriceMode) -> Streaming<Summary> {
+        let request = SummaryRequest {
+            price_mode: price_mode as i32,
+            ..SummaryRequest::default()
+        };
+        let mut client = self.client().await;
+        client.book_summary(request).await.unwrap().into_inner()
+    }
+
+    fn shutdown(&self) {
+        self.notify_shutdown.send(true).unwrap();
+    }
+}
+
+/// Next summary on `stream`, failing the test if none arrives in time.
+async fn next(stream: &mut Streaming<Summary>) -> Summary {
+    tokio::time::timeout(NEXT_TIMEOUT, stream.message())
+        .await
+        .expect("timed out waiting for a summary")
+        .unwrap()
+        .expect("stream ended")
+}
+
+/// Skip summaries until one satisfies `predicate` and return it.
+async fn next_matching<F>(stream: &mut Streaming<Summary>, predicate: F) -> Summary
+where
+    F: Fn(&Summary) -> bool,
+{
+    loop {
+        let summary = next(stream).await;
+        if predicate(&summary) {
+            return summary;
+        }
+    }
+}
+
+/// Opportunities of the next message on `stream`, failing the test if
+/// none arrives in time.
+async fn next_opportunities(stream: &mut Streaming<Opportunities>) -> Vec<Opportunity> {
+    tokio::time::timeout(NEXT_TIMEOUT, stream.message())
+        .await
+        .expect("timed out waiting for opportunities")
+        .unwrap()
+        .expect("stream ended")
+        .opportunities
+}
+
+/// Next trade on `stream`, failing the test if none arrives in time.
+async fn next_trade(stream: &mut Streaming<Trade>) -> Trade {
+    tokio::time::timeout(NEXT_TIMEOUT, stream.message())
+        .await
+        .expect("timed out waiting for a trade")
+        .unwrap()
+        .expect("stream ended")
+}
+
+/// Next candle on `stream`, failing the test if none arrives in time.
+async fn next_candle(stream: &mut Streaming<Candle>) -> Candle {
+    tokio::time::timeout(NEXT_TIMEOUT, stream.message())
+        .await
+        .expect("timed out waiting for a candle")
+        .unwrap()
+        .expect("stream ended")
+}
+
+/// Best bid and ask prices of `summary`.
+fn top(summary: &Summary) -> (f64, f64) {
+    (summary.bids[0].price, summary.asks[0].price)
+}
+
+/// Bitstamp script answering the subscription and sending `books`, each
+/// given as best bid and ask, the first after `start` and then `gap`
+/// apart.
+fn bitstamp_script(books: &[(&str, &str)], start: Duration, gap: Duration) -> Script {
+    let mut script = Script::new()
+        .expect_text()
+        .text(subscription_succeeded("ethbtc"))
+        .delay(start - gap);
+    for (microtimestamp, (bid, ask)) in books.iter().enumerate() {
+        script = script.delay(gap).text(order_book(
+            "ethbtc",
+            microtimestamp as u64,
+            &[(bid, "1")],
+            &[(ask, "1")],
+        ));
+    }
+    script
+}
+
+/// Binance script sending `count` identical books `gap` apart.
+fn binance_script(bid: &str, ask: &str, count: u64, gap: Duration) -> Script {
+    (1..=count).fold(Script::new(), |script, id| {
+        script
+            .delay(gap)
+            .text(depth(id, &[(bid, "2")], &[(ask, "2")]))
+    })
+}
+
+#[tokio::test]
+#[timeout(10000)]
+async fn summaries_follow_both_venues() {
+    let gap = Duration::from_millis(50);
+    let harness = Harness::start(
+        vec![binance_script("0.0610", "0.0630", 1, gap * 2)],
+        vec![bitstamp_script(
+            &[("0.0605", "0.0625"), ("0.0615", "0.0635")],
+            gap * 3,
+            gap * 3,
+        )],
+        Duration::from_secs(30),
+    )
+    .await;
+    let mut stream = harness.subscribe().await;
+
+    // Publishing starts once both venues have reported.
+    let summary = next(&mut stream).await;
+    assert_eq!(top(&summary), (0.061, 0.0625));
+    assert_eq!(summary.bids.len(), 2);
+    assert!((summary.spread - 0.0015).abs() < 1e-9);
+    assert_eq!(
+        (
+            summary.bids[0].exchange.as_str(),
+            summary.asks[0].exchange.as_str()
+        ),
+        ("Binance", "Bitstamp")
+    );
+    assert!(summary.bids.iter().all(|level| level.side() == Side::Bid));
+    assert!(summary.asks.iter().all(|level| level.side() == Side::Ask));
+    assert!((summary.mid - 0.06175).abs() < 1e-9);
+    // Bitstamp's single lot at the top ask pulls the microprice up.
+    assert!((summary.microprice - 0.062).abs() < 1e-9);
+    assert!((summary.spread_bps - 0.0015 / 0.06175 * 10_000.0).abs() < 1e-6);
+    assert_eq!(summary.imbalance, 0.0);
+    assert_eq!((summary.bid_depth, summary.ask_depth), (0.0, 0.0));
+
+    let summary = next(&mut stream).await;
+    assert_eq!(top(&summary), (0.0615, 0.063));
+    let bids: Vec<f64> = summary.bids.iter().map(|level| level.price).collect();
+    assert_eq!(bids, vec![0.0615, 0.061]);
+}
+
+#[tokio::test]
+#[timeout(10000)]
+async fn summary_depth_must_be_within_range() {
+    let gap = Duration::from_millis(50);
+    let harness = Harness::start(
+        vec![binance_script("0.0610", "0.0630", 1, gap)],
+        vec![bitstamp_script(&[("0.0605", "0.0625")], gap * 2, gap)],
+        Duration::from_secs(30),
+    )
+    .await;
+    let mut client = harness.client().await;
+    for depth_bps in &[-1.0, 10_000.5, 1e30, f64::NAN] {
+        let request = SummaryRequest {
+            depth_bps: *depth_bps,
+            ..SummaryRequest::default()
+        };
+        let status = client.book_summary(request).await.unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+    }
+
+    let request = SummaryRequest {
+        depth_bps: 10_000.0,
+        ..SummaryRequest::default()
+    };
+    let mut stream = client.book_summary(request).await.unwrap().into_inner();
+    let summary = next(&mut stream).await;
+    assert_eq!((summary.bid_depth, summary.ask_depth), (3.0, 3.0));
+}
+
+#[tokio::test]
+#[timeout(10000)]
+async fn disconnected_venue_is_dropped_and_restored() {
+    let gap = Duration::from_millis(50);
+    let harness = Harness::start(
+        vec![binance_script("0.0610", "0.0630", 40, gap)],
+        vec![
+            bitstamp_script(&[("0.0620", "0.0625")], gap * 2, gap)
+                .delay(gap * 2)
+                .disconnect(),
+            bitstamp_script(&[("0.0621", "0.0624")], gap, gap),
+        ],
+        Duration::from_secs(30),
+    )
+    .await;
+    let mut stream = harness.subscribe().await;
+
+    next_matching(&mut stream, |summary| top(summary).0 == 0.062).await;
+    // Binance alone while Bitstamp reconnects, then both again.
+    let summary = next_matching(&mut stream, |summary| summary.bids.len() == 1).await;
+    assert_eq!(top(&summary), (0.061, 0.063));
+    let summary = next_matching(&mut stream, |summary| top(summary).0 == 0.0621).await;
+    assert_eq!(top(&summary), (0.0621, 0.0624));
+}
+
+#[tokio::test]
+#[timeout(10000)]
+async fn silent_venue_goes_stale() {
+    let gap = Duration::from_millis(50);
+    let harness = Harness::start(
+        vec![binance_script("0.0610", "0.0630", 40, gap)],
+        vec![bitstamp_script(&[("0.0620", "0.0625")], gap, gap)],
+        Duration::from_millis(300),
+    )
+    .await;
+    let mut stream = harness.subscribe().await;
+
+    let summary = next_matching(&mut stream, |summary| summary.bids.len() == 2).await;
+    assert_eq!(top(&summary), (0.062, 0.0625));
+    // Bitstamp keeps the connection open but stops sending updates.
+    let summary = next_matching(&mut stream, |summary| summary.bids.len() == 1).await;
+    assert_eq!(top(&summary), (0.061, 0.063));
+}
+
+#[tokio::test]
+#[timeout(10000)]
+async fn concurrent_subscribers_see_the_same_summaries() {
+    let gap = Duration::from_millis(50);
+    let books = [
+        ("0.0601", "0.0640"),
+        ("0.0602", "0.0639"),
+        ("0.0603", "0.0638"),
+        ("0.0604", "0.0637"),
+    ];
+    // Delay the data until every client has subscribed.
+    let harness = Harness::start(
+        vec![binance_script("0.0600", "0.0650", 1, gap * 6)],
+        vec![bitstamp_script(&books, gap * 6, gap)],
+        Duration::from_secs(30),
+    )
+    .await;
+    let mut streams = vec![
+        harness.subscribe().await,
+        harness.subscribe().await,
+        harness.subscribe().await,
+    ];
+
+    let mut seen = Vec::new();
+    for stream in &mut streams {
+        let mut summaries = vec![next(stream).await];
+        while top(summaries.last().unwrap()).0 != 0.0604 {
+            summaries.push(next(stream).await);
+        }
+        seen.push(summaries);
+    }
+    assert_eq!(seen[0].last().map(top), Some((0.0604, 0.0637)));
+    assert!(seen.iter().all(|summaries| *summaries == seen[0]));
+}
+
+#[tokio::test]
+#[timeout(10000)]
+async fn shutdown_ends_streams_with_unavailable() {
+    let gap = Duration::from_millis(50);
+    let harness = Harness::start(
+        vec![binance_script("0.0610", "0.0630", 1, gap)],
+        vec![bitstamp_script(&[("0.0620", "0.0625")], gap, gap)],
+        Duration::from_secs(30),
+    )
+    .await;
+    let mut stream = harness.subscribe().await;
+    next(&mut stream).await;
+
+    harness.shutdown();
+    let status = loop {
+        match tokio::time::timeout(NEXT_TIMEOUT, stream.message()).await {
+            Ok(Ok(Some(_))) => {}
+            Ok(Err(status)) => break status,
+            res => panic!("unexpected stream end {:?}", res),
+        }
+    };
+    assert_eq!(status.code(), Code::Unavailable);
+}
+
+#[tokio::test]
+#[timeout(10000)]
+async fn estimate_fill_sweeps_full_depth() {
+    // Bitstamp asks 0.0625 to 0.0636, one each, Binance 2 at 0.0630:
+    // 13 levels, more than a summary shows.
+    let prices: Vec<String> = (625..637).map(|tick| format!("0.0{}", tick)).collect();
+    let asks: Vec<(&str, &str)> = prices.iter().map(|price| (price.as_str(), "1")).collect();
+    let bitstamp = Script::new()
+        .expect_text()
+        .text(subscription_succeeded("ethbtc"))
+        .delay(Duration::from_millis(100))
+        .text(order_book("ethbtc", 1, &[("0.0605", "1")], &asks));
+    let harness = Harness::start(
+        vec![binance_script(
+            "0.0610",
+            "0.0630",
+            1,
+            Duration::from_millis(50),
+        )],
+        vec![bitstamp],
+        Duration::from_secs(30),
+    )
+    .await;
+    let mut stream = harness.subscribe().await;
+    let summary = next_matching(&mut stream, |summary| summary.asks.len() == 10).await;
+    assert_eq!(top(&summary), (0.061, 0.0625));
+
+    let mut client = harness.client().await;
+    let estimate = |side: Side, quantity| {
+        let request = FillRequest {
+            side: side as i32,
+            quantity: Some(quantity),
+        };
+        let mut client = client.clone();
+        async move { client.estimate_fill(request).await }
+    };
+
+    let fill = estimate(Side::Ask, Quantity::Base(13.0))
+        .await
+        .unwrap()
+        .into_inner();
+    assert!(fill.complete);
+    assert!((fill.base - 13.0).abs() < 1e-9);
+    assert!((fill.worst_price - 0.0635).abs() < 1e-9);
+    assert!((fill.vwap - fill.quote / fill.base).abs() < 1e-9);
+    assert!(fill.slippage_bps > 0.0);
+
+    let fill = estimate(Side::Ask, Quantity::Base(20.0))
+        .await
+        .unwrap()
+        .into_inner();
+    assert!(!fill.complete);
+    assert!((fill.base - 14.0).abs() < 1e-9);
+    assert!((fill.worst_price - 0.0636).abs() < 1e-9);
+
+    let fill = estimate(Side::Bid, Quantity::Quote(0.061))
+        .await
+        .unwrap()
+        .into_inner();
+    assert!(fill.complete);
+    assert!((fill.base - 1.0).abs() < 1e-9);
+    assert!((fill.vwap - 0.061).abs() < 1e-9);
+
+    let status = estimate(Side::Bid, Quantity::Base(-1.0)).await.unwrap_err();
+    assert_eq!(status.code(), Code::InvalidArgument);
+    client
+        .estimate_fill(FillRequest::default())
+        .await
+        .unwrap_err();
+}
+
+#[tokio::test]
+#[timeout(10000)]
+async fn route_order_splits_across_venues() {
+    let gap = Duration::from_millis(50);
+    let harness = Harness::start(
+        vec![binance_script("0.0610", "0.0630", 1, gap)],
+        vec![bitstamp_script(&[("0.0605", "0.0625")], gap * 2, gap)],
+        Duration::from_secs(30),
+    )
+    .await;
+    let mut stream = harness.subscribe().await;
+    next(&mut stream).await;
+
+    let request = RouteRequest {
+        side: Side::Ask as i32,
+        quantity: 2.5,
+    };
+    let plan = harness
+        .client()
+        .await
+        .route_order(request)
+        .await
+        .unwrap()
+        .into_inner();
+    let orders: Vec<(&str, f64, f64)> = plan
+        .orders
+        .iter()
+        .map(|order| (order.exchange.as_str(), order.quantity, order.limit_price))
+        .collect();
+    assert_eq!(
+        orders,
+        vec![("Bitstamp", 1.0, 0.0625), ("Binance", 1.5, 0.063)]
+    );
+    assert!(plan.complete);
+}
+
+#[tokio::test]
+#[timeout(10000)]
+async fn fee_adjusted_summaries_rank_by_executable_price() {
+    let gap = Duration::from_millis(50);
+    let harness = Harness::start(
+        vec![binance_script("0.0610", "0.0630", 1, gap)],
+        vec![bitstamp_script(&[("0.0605", "0.0629")], gap * 2, gap)],
+        Duration::from_secs(30),
+    )
+    .await;
+    let mut raw = harness.subscribe().await;
+    let mut adjusted = harness.subscribe_with(PriceMode::FeeAdjusted).await;
+
+    let summary = next(&mut raw).await;
+    assert_eq!(summary.asks[0].exchange, "Bitstamp");
+    assert_eq!(summary.asks[0].price, summary.asks[0].raw_price);
+
+    // Bitstamp's 50 bps fee makes Binance's ask the cheaper one.
+    let summary = next(&mut adjusted).await;
+    let ask = &summary.asks[0];
+    assert_eq!(ask.exchange, "Binance");
+    assert_eq!(ask.raw_price, 0.063);
+    assert!((ask.price - 0.063063).abs() < 1e-9);
+    let bid = &summary.bids[0];
+    assert_eq!((bid.exchange.as_str(), bid.raw_price), ("Binance", 0.061));
+    assert!((bid.price - 0.060939).abs() < 1e-9);
+    assert!((summary.spread - (0.063063 - 0.060939)).abs() < 1e-9);
+}
+
+#[tokio::test]
+#[timeout(10000)]
+async fn arbitrage_opportunities_follow_crossed_venues() {
+    let gap = Duration::from_millis(50);
+    let harness = Harness::start(
+        vec![binance_script("0.0610", "0.0630", 1, gap)],
+        vec![bitstamp_script(
+            &[("0.0640", "0.0650"), ("0.0605", "0.0625")],
+            gap * 4,
+            gap * 2,
+        )],
+        Duration::from_secs(30),
+    )
+    .await;
+    let mut summaries = harness.subscribe().await;
+    let mut stream = harness
+        .client()
+        .await
+        .arbitrage_opportunities(ArbitrageRequest::default())
+        .await
+        .unwrap()
+        .into_inner();
+
+    // Bitstamp's bid beats Binance's ask by more than both fees.
+    let opportunities = next_opportunities(&mut stream).await;
+    assert_eq!(opportunities.len(), 1);
+    let opportunity = &opportunities[0];
+    assert_eq!(
+        (
+            opportunity.buy_exchange.as_str(),
+            opportunity.sell_exchange.as_str()
+        ),
+        ("Binance", "Bitstamp")
+    );
+    assert_eq!(opportunity.quantity, 1.0);
+    assert_eq!(
+        (opportunity.buy_price, opportunity.sell_price),
+        (0.063, 0.064)
+    );
+    assert!((opportunity.profit - (0.064 * 0.995 - 0.063 * 1.001)).abs() < 1e-9);
+
+    // Uncrossing is announced with an empty list.
+    assert!(next_opportunities(&mut stream).await.is_empty());
+
+    let summary = next_matching(&mut summaries, |summary| summary.bids[0].price == 0.064).await;
+    assert_eq!(summary.state(), BookState::Crossed);
+    assert!(summary.spread < 0.0);
+    let summary = next(&mut summaries).await;
+    assert_eq!(summary.state(), BookState::Normal);
+}
+
+#[tokio::test]
+#[timeout(10000)]
+async fn arbitrage_sees_venues_dropped_by_cross_policy() {
+    let gap = Duration::from_millis(50);
+    let harness = Harness::start_with_policy(
+        vec![binance_script("0.0610", "0.0630", 1, gap)],
+        vec![bitstamp_script(&[("0.0640", "0.0650")], gap * 4, gap)],
+        Duration::from_secs(30),
+        CrossPolicy::DropBoth,
+    )
+    .await;
+    let mut summaries = harness.subscribe().await;
+    let mut stream = harness
+        .client()
+        .await
+        .arbitrage_opportunities(ArbitrageRequest::default())
+        .await
+        .unwrap()
+        .into_inner();
+
+    // The summaries are uncrossed, the opportunity is still reported.
+    let summary = next(&mut summaries).await;
+    assert_eq!(summary.state(), BookState::Normal);
+    assert_eq!(top(&summary), (0.061, 0.065));
+    let opportunities = next_opportunities(&mut stream).await;
+    assert_eq!(opportunities.len(), 1);
+    assert_eq!(
+        (
+            opportunities[0].buy_exchange.as_str(),
+            opportunities[0].sell_exchange.as_str()
+        ),
+        ("Binance", "Bitstamp")
+    );
+}
+
+#[tokio::test]
+#[timeout(10000)]
+async fn trades_are_streamed_and_summarized() {
+    let gap = Duration::from_millis(50);
+    let trades = Script::new()
+        .expect_text()
+        .text(subscription_succeeded("ethbtc"))
+        .delay(gap * 4)
+        .text(live_trade("ethbtc", 1, "0.0615", "0.5", 1_000_000, 0))
+        .text(live_trade("ethbtc", 2, "0.0614", "1.5", 2_000_000, 1));
+    let harness = Harness::start_with_trades(
+        vec![binance_script("0.0610", "0.0630", 20, gap)],
+        vec![bitstamp_script(&[("0.0612", "0.0625")], gap, gap)],
+        vec![trades],
+        Duration::from_secs(30),
+    )
+    .await;
+    let mut client = harness.client().await;
+
+    let request = TradesRequest {
+        exchanges: vec!["Nasdaq".to_owned()],
+    };
+    let status = client.trades(request).await.unwrap_err();
+    assert_eq!(status.code(), Code::InvalidArgument);
+
+    let request = TradesRequest {
+        exchanges: vec!["Bitstamp".to_owned()],
+    };
+    let mut stream = client.trades(request).await.unwrap().into_inner();
+    let mut summaries = harness.subscribe().await;
+    let trade = next_trade(&mut stream).await;
+    assert_eq!(trade.exchange, "Bitstamp");
+    assert_eq!((trade.id, trade.price, trade.amount), (1, 0.0615, 0.5));
+    assert_eq!(trade.aggressor(), Aggressor::Buy);
+    assert_eq!(trade.traded_at, 1_000_000);
+    let trade = next_trade(&mut stream).await;
+    assert_eq!((trade.id, trade.aggressor()), (2, Aggressor::Sell));
+
+    // Binance keeps publishing books, which carry the latest trade.
+    let summary = next_matching(&mut summaries, |summary| {
+        summary.last_trade.as_ref().map(|trade| trade.id) == Some(2)
+    })
+    .await;
+    assert_eq!(summary.last_trade.unwrap().price, 0.0614);
+}
+
+#[tokio::test]
+#[timeout(10000)]
+async fn candles_are_backfilled_and_streamed() {
+    let gap = Duration::from_millis(50);
+    let trades = Script::new()
+        .expect_text()
+        .text(subscription_succeeded("ethbtc"))
+        .text(live_trade("ethbtc", 1, "0.0615", "0.5", 1_000_000, 0))
+        .text(live_trade("ethbtc", 2, "0.0617", "1.5", 1_500_000, 0))
+        .text(live_trade("ethbtc", 3, "0.0614", "1", 2_000_000, 1));
+    let harness = Harness::start_with_trades(
+        vec![binance_script("0.0610", "0.0630", 20, gap)],
+        vec![bitstamp_script(&[("0.0612", "0.0625")], gap * 2, gap)],
+        vec![trades],
+        Duration::from_secs(30),
+    )
+    .await;
+    let mut client = harness.client().await;
+
+    let request = CandlesRequest {
+        interval: "5m".to_owned(),
+        ..CandlesRequest::default()
+    };
+    let status = client.candles(request).await.unwrap_err();
+    assert_eq!(status.code(), Code::InvalidArgument);
+
+    // Both venues' books are in, so the trades were received long ago.
+    let mut summaries = harness.subscribe().await;
+    next_matching(&mut summaries, |summary| summary.bids[0].price == 0.0612).await;
+
+    let request = CandlesRequest {
+        source: CandleSource::Venue as i32,
+        exchange: "Bitstamp".to_owned(),
+        interval: "1s".to_owned(),
+        history: 0,
+    };
+    let mut stream = client.candles(request).await.unwrap().into_inner();
+    let candle = next_candle(&mut stream).await;
+    assert!(candle.closed);
+    assert_eq!((candle.open_time, candle.count), (1_000_000, 2));
+    assert_eq!(
+        (candle.open, candle.high, candle.low, candle.close),
+        (0.0615, 0.0617, 0.0615, 0.0617)
+    );
+    assert_eq!(candle.volume, 2.0);
+    let candle = next_candle(&mut stream).await;
+    assert!(!candle.closed);
+    assert_eq!((candle.open_time, candle.close), (2_000_000, 0.0614));
+
+    // Mid candles keep following the aggregated book.
+    let request = CandlesRequest {
+        source: CandleSource::Mid as i32,
+        interval: "1s".to_owned(),
+        ..CandlesRequest::default()
+    };
+    let mut stream = client.candles(request).await.unwrap().into_inner();
+    loop {
+        let candle = next_candle(&mut stream).await;
+        assert_eq!(candle.source(), CandleSource::Mid);
+        assert_eq!(candle.volume, 0.0);
+        if (candle.close - 0.06185).abs() < 1e-9 {
+            break;
+        }
+    }
+}
+
+#[tokio::test]
+#[timeout(10000)]
+async fn history_answers_with_the_stored_top_of_book() {
+    let gap = Duration::from_millis(50);
+    let harness = Harness::start(
+        vec![binance_script("0.0610", "0.0630", 1, gap)],
+        vec![bitstamp_script(
+            &[("0.0612", "0.0625"), ("0.0613", "0.0624")],
+            gap * 2,
+            gap,
+        )],
+        Duration::from_secs(30),
+    )
+    .await;
+    let mut summaries = harness.subscribe().await;
+    next_matching(&mut summaries, |summary| summary.bids[0].price == 0.0613).await;
+    let mut client = harness.client().await;
+
+    let request = HistoryRequest {
+        from: 2,
+        to: 1,
+        ..HistoryRequest::default()
+    };
+    let status = client.get_history(request).await.unwrap_err();
+    assert_eq!(status.code(), Code::InvalidArgument);
+
+    let request = HistoryRequest {
+        books: true,
+        ..HistoryRequest::default()
+    };
+    let history = client.get_history(request).await.unwrap().into_inner();
+    let tops: Vec<(f64, f64)> = history
+        .quotes
+        .iter()
+        .map(|quote| {
+            (
+                quote.bid.as_ref().unwrap().price,
+                quote.ask.as_ref().unwrap().price,
+            )
+        })
+        .collect();
+    assert_eq!(tops, vec![(0.0612, 0.0625), (0.0613, 0.0624)]);
+    let last = history.quotes.last().unwrap();
+    assert_eq!(last.bid.as_ref().unwrap().exchange, "Bitstamp");
+    assert!(history.quotes[0].time <= last.time);
+
+    // The book snapshots keep both venues' levels.
+    let book = history.books.last().unwrap();
+    assert_eq!(book.bids.len(), 2);
+    assert_eq!(book.bids[1].exchange, "Binance");
+
+    // The quote in effect at a time after the last change is the last one.
+    let request = HistoryRequest {
+        from: last.time + 1,
+        ..HistoryRequest::default()
+    };
+    let history = client.get_history(request).await.unwrap().into_inner();
+    assert_eq!(history.quotes, vec![last.clone()]);
+
+    // Bounds past the largest stored time don't wrap around.
+    let request = HistoryRequest {
+        from: u64::MAX - 1,
+        to: u64::MAX,
+        books: true,
+        ..HistoryRequest::default()
+    };
+    let history = client.get_history(request).await.unwrap().into_inner();
+    assert_eq!(history.quotes, vec![last.clone()]);
+    let request = HistoryRequest {
+        to: u64::MAX,
+        books: true,
+        ..HistoryRequest::default()
+    };
+    let history = client.get_history(request).await.unwrap().into_inner();
+    assert_eq!(history.quotes.len(), 2);
+    assert!(!history.books.is_empty());
+}